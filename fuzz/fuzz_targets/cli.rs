@@ -0,0 +1,14 @@
+#![no_main]
+use core::convert::TryFrom as _;
+
+use libfuzzer_sys::fuzz_target;
+
+use tutorial::{authenticator::Command, cli};
+
+// parsing the command line into a `Command`, arguments separated by NUL bytes
+fuzz_target!(|args: &str| {
+    let args = core::iter::once("trussed-totp-pc-tutorial").chain(args.split('\0'));
+    if let Ok(matches) = cli::clap_app().get_matches_from_safe(args) {
+        Command::try_from(&matches).ok();
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use tutorial::{authenticator::Credential, policy::Policy};
+
+// deserializing records read back from the state file, which may be corrupted
+fuzz_target!(|data: &[u8]| {
+    Credential::from_bytes(data).ok();
+    Policy::from_bytes(data).ok();
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use tutorial::secret::{SecretBytes, SecretString};
+
+// decoding the secret passed to `register`
+fuzz_target!(|secret: &str| {
+    SecretBytes::from_base32(&SecretString::from(secret)).ok();
+});
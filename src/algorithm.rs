@@ -0,0 +1,184 @@
+//! OTP algorithms, turning the HMAC-SHA1 that Trussed calculates into a code.
+//!
+//! Trussed only ever calculates the HMAC-SHA1 of a credential's secret and a moving factor.
+//! The credential's algorithm decides whether that factor is the time or a counter, and
+//! how the HMAC becomes the code shown. New variants implement `OtpAlgorithm` and are added
+//! to `ALGORITHMS`, the authenticator looks them up by the name stored with the credential.
+//!
+//! mOTP is not among them: its codes are an MD5 hash over the secret itself (and a PIN),
+//! which would have to leave Trussed to calculate it.
+
+use core::convert::TryInto;
+
+use littlefs2::path::Path;
+use serde::{Deserialize, Serialize};
+use trussed::{syscall, try_syscall, Bytes, types::Location};
+
+use crate::Result;
+use crate::paths;
+
+/// The algorithm of credentials registered before there was a choice
+pub const DEFAULT_ALGORITHM: &str = "totp";
+
+/// A way to derive one-time passwords from the HMAC-SHA1 of a moving factor
+pub trait OtpAlgorithm: Sync {
+    /// The name the algorithm is selected and stored by, e.g. `totp`
+    fn name(&self) -> &'static str;
+
+    /// Whether the moving factor is a counter, advanced with each code, instead of the time
+    fn is_counter_based(&self) -> bool {
+        false
+    }
+
+    /// The code, from the HMAC-SHA1 of the moving factor
+    fn code(&self, hmac: &[u8]) -> Result<String>;
+}
+
+/// Time-based codes of six digits, cf. RFC 6238
+pub struct Totp;
+
+/// Counter-based codes of six digits, cf. RFC 4226
+pub struct Hotp;
+
+/// Time-based codes of five characters, as the Steam mobile app shows them
+pub struct Steam;
+
+/// All algorithms credentials can be registered with
+pub static ALGORITHMS: [&dyn OtpAlgorithm; 3] = [&Totp, &Hotp, &Steam];
+
+/// Looks up an algorithm by its name
+pub fn find(name: &str) -> Result<&'static dyn OtpAlgorithm> {
+    ALGORITHMS.iter().copied()
+        .find(|algorithm| algorithm.name() == name)
+        .ok_or_else(|| anyhow::anyhow!("Unknown OTP algorithm {}, known are {}", name, names().join(", ")))
+}
+
+/// The names of all algorithms
+pub fn names() -> Vec<&'static str> {
+    ALGORITHMS.iter().map(|algorithm| algorithm.name()).collect()
+}
+
+impl OtpAlgorithm for Totp {
+    fn name(&self) -> &'static str {
+        "totp"
+    }
+
+    fn code(&self, hmac: &[u8]) -> Result<String> {
+        Ok(format!("{:06}", dynamic_truncation(hmac)? % 1_000_000))
+    }
+}
+
+impl OtpAlgorithm for Hotp {
+    fn name(&self) -> &'static str {
+        "hotp"
+    }
+
+    fn is_counter_based(&self) -> bool {
+        true
+    }
+
+    fn code(&self, hmac: &[u8]) -> Result<String> {
+        Ok(format!("{:06}", dynamic_truncation(hmac)? % 1_000_000))
+    }
+}
+
+impl OtpAlgorithm for Steam {
+    fn name(&self) -> &'static str {
+        "steam"
+    }
+
+    fn code(&self, hmac: &[u8]) -> Result<String> {
+        const ALPHABET: &[u8] = b"23456789BCDFGHJKMNPQRTVWXY";
+        let mut value = dynamic_truncation(hmac)? as usize;
+        let mut code = String::new();
+        for _ in 0..5 {
+            code.push(ALPHABET[value % ALPHABET.len()] as char);
+            value /= ALPHABET.len();
+        }
+        Ok(code)
+    }
+}
+
+/// Dynamic truncation of an HMAC-SHA1 to 31 bits, cf. RFC 4226, section 5.3
+pub fn dynamic_truncation(hmac: &[u8]) -> Result<u32> {
+    if hmac.len() != 20 {
+        return Err(anyhow::anyhow!("Unexpected HMAC length {}", hmac.len()));
+    }
+    let offset = (hmac[19] & 0xf) as usize;
+    Ok(u32::from_be_bytes(hmac[offset..][..4].try_into().unwrap()) & 0x7fff_ffff)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// The algorithm of a credential, and its counter if the algorithm is counter-based
+///
+/// These are kept apart from the credential, so credentials stored before algorithms could
+/// be chosen need no migration; those have no parameters, and use TOTP.
+pub struct Parameters {
+    algorithm: String,
+    counter: u64,
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Self { algorithm: DEFAULT_ALGORITHM.into(), counter: 0 }
+    }
+}
+
+impl Parameters {
+    /// Constructor, failing for unknown algorithms
+    pub fn new(algorithm: &str, counter: u64) -> Result<Self> {
+        find(algorithm)?;
+        Ok(Self { algorithm: algorithm.into(), counter })
+    }
+
+    /// The algorithm's name
+    pub fn algorithm(&self) -> &str {
+        &self.algorithm
+    }
+
+    /// The counter of the next code, for counter-based algorithms
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    /// Moves on to the next counter, once a code was used
+    pub fn advance(&mut self) {
+        self.counter = self.counter.saturating_add(1);
+    }
+
+    /// Loads the parameters of the credential stored in the file, defaulting to TOTP
+    ///
+    /// Parameters are kept in the same location as the credential.
+    pub fn load<T: trussed::Client>(trussed: &mut T, location: Location, filename: &Path) -> Result<Self> {
+        let serialized_parameters = match try_syscall!(trussed.read_file(location, paths::parameters(filename))) {
+            Ok(reply) => reply.data,
+            Err(_) => return Ok(Self::default()),
+        };
+        Self::from_bytes(serialized_parameters.as_ref())
+    }
+
+    /// Deserializes parameters as stored, failing (instead of panicking) on malformed input
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        // falling back to TOTP would silently change a HOTP credential's codes
+        postcard::from_bytes(bytes).map_err(|_| anyhow::anyhow!("The stored OTP parameters are corrupted"))
+    }
+
+    /// Persists the parameters of the credential stored in the file
+    pub fn store<T: trussed::Client>(&self, trussed: &mut T, location: Location, filename: &Path) -> Result<()> {
+        let mut buf = [0u8; 64];
+        let serialized_parameters = postcard::to_slice(self, &mut buf)
+            .map_err(|_| anyhow::anyhow!("postcard serialization error"))?;
+        syscall!(trussed.write_file(
+            location,
+            paths::parameters(filename),
+            Bytes::from_slice(&*serialized_parameters).unwrap(),
+            None
+        ));
+        Ok(())
+    }
+
+    /// Removes the parameters of the credential stored in the file, if any
+    pub fn remove<T: trussed::Client>(trussed: &mut T, location: Location, filename: &Path) {
+        try_syscall!(trussed.remove_file(location, paths::parameters(filename))).ok();
+    }
+}
@@ -7,65 +7,489 @@
 //! encodings outside of the "app", which can then focus even more on
 //! implementing the exact logic required.
 
-use core::convert::TryInto;
-
-use delog::hex_str;
-use log::{debug, info};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
 use trussed::{syscall, try_syscall, types::Message};
-use trussed::{Bytes, types::{Mechanism, /*SignatureSerialization, StorageAttributes,*/ Location}};
+use trussed::{Bytes, types::{Mechanism, SignatureSerialization, /*StorageAttributes,*/ Location, PathBuf}};
 
 use crate::Result;
+use crate::algorithm::{self, Parameters};
+use crate::crypto_util::constant_time_eq;
+use crate::integrity::Integrity;
+use crate::issuers::{self, MAX_ISSUER_FIELD_LENGTH};
+use crate::paths;
+pub use crate::policy::PresenceError;
+use crate::policy::{self, Action, Policy, Requirement};
+use crate::recovery::{RecoveryCode, RecoveryCodes};
+use crate::secret::{SecretBytes, SecretString};
+use crate::usage::Usage;
 
 const MAX_CRED_LABEL_LENGTH: usize = 256;
+/// Services issue secrets of 10 (80 bits, the RFC 4226 minimum) up to 64 bytes
+const SECRET_LENGTHS: core::ops::RangeInclusive<usize> = 10..=64;
+/// The length of all secrets before other lengths were supported
+const LEGACY_SECRET_LENGTH: usize = 20;
+/// Where credentials may be stored, in the order they are looked up
+const LOCATIONS: [Location; 3] = [Location::Internal, Location::External, Location::Volatile];
+/// Challenges of YubiKey-style challenge-response take up to 64 bytes
+const MAX_CHALLENGE_LENGTH: usize = 64;
+/// Keys of YubiKey-style challenge-response have (up to) 20 bytes, HMAC-SHA1's output length
+const CHALLENGE_RESPONSE_KEY_LENGTH: usize = 20;
+const MAX_CHALLENGE_RESPONSE_KEY_LENGTH: usize = 64;
+/// Periods of time-based credentials, in seconds; 30 is what most services use
+pub const PERIODS: core::ops::RangeInclusive<u64> = 15..=300;
+/// The period of credentials registered without one
+pub const DEFAULT_PERIOD: u64 = 30;
+/// Codes precomputed at once cover at most this many periods
+pub const MAX_PRECOMPUTED: u32 = 100;
+/// Deleted credentials can be restored for 30 days
+pub const TRASH_RETENTION_SECONDS: u64 = 30 * 86_400;
+/// Number of failures with a credential at which `Event::FailureThreshold` is emitted, by default
+pub const FAILURE_THRESHOLD: u32 = 5;
 
 /// The core "app", implementing TOTP authentication, using Trussed®
 pub struct Authenticator<T>
 {
     trussed: T,
+    on_event: Option<Box<dyn FnMut(Event)>>,
+    layout_migrated: bool,
+    presence_timeout: core::time::Duration,
+    read_only: bool,
+    failure_threshold: u32,
 }
 
 #[derive(Clone, Debug, PartialEq)]
-/// One of the two commands this authenticator can process: credential registration
+/// Progress notifications, emitted while the authenticator processes a command.
+///
+/// Runners embedding the app can use these to drive their own user interface,
+/// e.g. a spinner during storage access, or a "touch your device" hint, and to
+/// act on what happened to the credentials, e.g. run hooks.
+pub enum Event {
+    /// The authenticator is waiting for confirmation of user presence
+    WaitingForTouch,
+    /// The authenticator is reading from or writing to persistent storage
+    StorageBusy,
+    /// A credential or challenge-response key was deleted, or replaced by a new one
+    Deleted {
+        /// Its label
+        label: String,
+    },
+    /// The failures with a credential reached the threshold, cf. `set_failure_threshold`
+    FailureThreshold {
+        /// Its label
+        label: String,
+    },
+    /// The command has been processed successfully
+    Done,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: credential registration
 pub struct Register {
     /// Label for the credential, e.g. `alice@trussed.dev`
     pub label: String,
     /// Choices could be made here on who is responsible for decoding the raw secret bytes
-    pub base32_secret: String,
+    pub base32_secret: SecretString,
     /// Period in seconds after which the counter for the TOTP token is incremented
     pub period_seconds: u64,
+    /// Distinguishes several secrets registered under the same label
+    pub slot: u8,
+    /// Timestamp of registration (seconds since UNIX epoch)
+    pub timestamp: u64,
+    /// Where the secret and the credential are stored; volatile ones are gone after the session
+    #[serde(default = "internal")]
+    pub location: Location,
+    /// How codes are derived from the secret, one of `algorithm::ALGORITHMS`
+    #[serde(default = "default_algorithm")]
+    pub algorithm: String,
+    /// The counter of the first code, for counter-based algorithms such as HOTP
+    #[serde(default)]
+    pub counter: u64,
+    /// Id of the issuer in the catalog, cf. `issuers`
+    #[serde(default)]
+    pub issuer: Option<String>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-/// One of the two commands this authenticator can process: authentication with a registered
+/// The algorithm of everything registered before algorithms could be chosen
+fn default_algorithm() -> String {
+    algorithm::DEFAULT_ALGORITHM.into()
+}
+
+/// The location of everything stored before locations could be chosen
+fn internal() -> Location {
+    Location::Internal
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: authentication with a registered
 /// credential
 pub struct Authenticate {
     /// Label for the credential, e.g. `alice@trussed.dev`
     pub label: String,
+    /// Slot of the credential, defaults to the highest slot registered under the label
+    pub slot: Option<u8>,
     /// Timestamp (seconds since UNIX epoch)
     // pub timestamp: std::time::Instant,
     pub timestamp: u64,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: authentication with all time-based credentials
+/// at once, e.g. for an overview of the current codes
+pub struct AuthenticateAll {
+    /// Timestamp (seconds since UNIX epoch)
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: computing the codes of the next periods
+/// in advance, for use while offline
+pub struct Precompute {
+    /// Label for the credential, e.g. `alice@trussed.dev`
+    pub label: String,
+    /// Slot of the credential, defaults to the highest slot registered under the label
+    pub slot: Option<u8>,
+    /// Timestamp (seconds since UNIX epoch) of the first period
+    pub timestamp: u64,
+    /// Number of periods, up to `MAX_PRECOMPUTED`
+    pub count: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: checking a one-time password against
+/// a registered credential
+pub struct Verify {
+    /// Label for the credential, e.g. `alice@trussed.dev`
+    pub label: String,
+    /// Slot of the credential, defaults to the highest slot registered under the label
+    pub slot: Option<u8>,
+    /// Timestamp (seconds since UNIX epoch)
+    pub timestamp: u64,
+    /// The one-time password to check
+    pub otp: Otp,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: finding (and deleting) credentials
+/// which have not been used for a long time
+pub struct Prune {
+    /// Timestamp to compare usage against (seconds since UNIX epoch)
+    pub timestamp: u64,
+    /// Credentials not used for this many seconds are stale
+    pub unused_for_seconds: u64,
+    /// Delete the stale credentials, instead of only reporting them
+    pub delete: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: dealing with credential files that
+/// can no longer be deserialized
+pub struct Repair {
+    /// Delete the corrupted files of credentials, instead of moving them to quarantine,
+    /// and those quarantined before
+    pub delete: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: listing the deleted credentials,
+/// after purging those deleted longer ago than `TRASH_RETENTION_SECONDS`
+pub struct Trash {
+    /// Timestamp to compare deletions against (seconds since UNIX epoch)
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: restoring a deleted credential
+pub struct Undelete {
+    /// Label for the credential, e.g. `alice@trussed.dev`
+    pub label: String,
+    /// Slot of the credential, defaults to the most recently deleted one with the label
+    pub slot: Option<u8>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: summarizing the usage of the credentials
+pub struct Stats {
+    /// Timestamp (seconds since UNIX epoch) determining the current week
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: changing the requirement for a command
+pub struct SetPolicy {
+    /// The command whose requirement changes
+    pub action: Action,
+    /// The new requirement
+    pub requirement: Requirement,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: overriding how an issuer is shown, or adding one
+pub struct SetIssuer {
+    /// The issuer's id, e.g. `github`
+    pub id: String,
+    /// The name to show
+    pub name: String,
+    /// The slug of the icon in Simple Icons
+    pub icon: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: removing the override of an issuer
+pub struct ResetIssuer {
+    /// The issuer's id, e.g. `github`
+    pub id: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: keeping recovery codes, which the service
+/// issued along with a credential's secret
+pub struct AddRecoveryCodes {
+    /// Label for the credential, e.g. `alice@trussed.dev`
+    pub label: String,
+    /// Slot of the credential, defaults to the highest slot registered under the label
+    pub slot: Option<u8>,
+    /// The codes, added to those already kept
+    pub codes: Vec<SecretString>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: showing the recovery codes of a credential
+pub struct ShowRecoveryCodes {
+    /// Label for the credential, e.g. `alice@trussed.dev`
+    pub label: String,
+    /// Slot of the credential, defaults to the highest slot registered under the label
+    pub slot: Option<u8>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: marking a recovery code of a credential as used
+pub struct UseRecoveryCode {
+    /// Label for the credential, e.g. `alice@trussed.dev`
+    pub label: String,
+    /// Slot of the credential, defaults to the highest slot registered under the label
+    pub slot: Option<u8>,
+    /// The code used, defaults to the first unused one
+    pub code: Option<SecretString>,
+    /// Timestamp of the use (seconds since UNIX epoch)
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: registering a key for HMAC-SHA1
+/// challenge-response, as YubiKeys offer it (e.g. to unlock KeePassXC databases)
+pub struct RegisterChallengeResponse {
+    /// Label for the key, e.g. `passwords.kdbx`
+    pub label: String,
+    /// The key, hex-encoded; a random one is generated in Trussed if missing
+    pub hex_secret: Option<SecretString>,
+    /// Timestamp of registration (seconds since UNIX epoch)
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: responding to a challenge with the HMAC-SHA1
+/// of a challenge-response key
+pub struct RespondToChallenge {
+    /// Label for the key, e.g. `passwords.kdbx`
+    pub label: String,
+    /// The challenge, of up to 64 bytes
+    pub challenge: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this authenticator can process: deleting a challenge-response key
+pub struct DeleteChallengeResponse {
+    /// Label for the key, e.g. `passwords.kdbx`
+    pub label: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 /// The public API of this TOTP authenticator
 #[allow(missing_docs)]
 pub enum Command {
     Register(Register),
     Authenticate(Authenticate),
+    AuthenticateAll(AuthenticateAll),
+    Precompute(Precompute),
+    Verify(Verify),
+    List,
+    Labels,
+    Prune(Prune),
+    Repair(Repair),
+    ShowPolicy,
+    SetPolicy(SetPolicy),
+    ShowIssuers,
+    SetIssuer(SetIssuer),
+    ResetIssuer(ResetIssuer),
+    Stats(Stats),
+    Trash(Trash),
+    Undelete(Undelete),
+    AddRecoveryCodes(AddRecoveryCodes),
+    ShowRecoveryCodes(ShowRecoveryCodes),
+    UseRecoveryCode(UseRecoveryCode),
+    RegisterChallengeResponse(RegisterChallengeResponse),
+    RespondToChallenge(RespondToChallenge),
+    DeleteChallengeResponse(DeleteChallengeResponse),
 }
 
-#[derive(Clone, Debug, PartialEq)]
-/// Contains a one-time password
-pub struct Otp(pub u64);
+impl Command {
+    /// Whether the command changes stored state, i.e. is refused in read-only mode
+    ///
+    /// Authentications only update usage counters, so they are allowed, except for
+    /// counter-based credentials, which the authenticator refuses itself (cf. `set_read_only`).
+    pub fn is_mutating(&self) -> bool {
+        // no wildcard, so each new command must be classified
+        match self {
+            Command::Register(_) | Command::Repair(_) | Command::SetPolicy(_) | Command::Undelete(_)
+            | Command::SetIssuer(_) | Command::ResetIssuer(_)
+            | Command::AddRecoveryCodes(_) | Command::UseRecoveryCode(_)
+            | Command::RegisterChallengeResponse(_) | Command::DeleteChallengeResponse(_) => true,
+            Command::Prune(prune) => prune.delete,
+            Command::Authenticate(_) | Command::AuthenticateAll(_) | Command::Precompute(_) | Command::Verify(_)
+            | Command::List | Command::Labels
+            | Command::ShowPolicy | Command::ShowIssuers | Command::Stats(_) | Command::Trash(_) | Command::ShowRecoveryCodes(_)
+            | Command::RespondToChallenge(_) => false,
+        }
+    }
+
+    /// The name of the corresponding CLI subcommand, e.g. `chalresp respond`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Register(_) => "register",
+            Command::Authenticate(_) => "authenticate",
+            Command::AuthenticateAll(_) => "authenticate --all",
+            Command::Precompute(_) => "authenticate --precompute",
+            Command::Verify(_) => "verify",
+            Command::List => "list",
+            Command::Labels => "labels",
+            Command::Prune(_) => "prune",
+            Command::Repair(_) => "repair",
+            Command::ShowPolicy => "policy show",
+            Command::SetPolicy(_) => "policy set",
+            Command::ShowIssuers => "issuer show",
+            Command::SetIssuer(_) => "issuer set",
+            Command::ResetIssuer(_) => "issuer reset",
+            Command::Stats(_) => "stats",
+            Command::Trash(_) => "trash",
+            Command::Undelete(_) => "undelete",
+            Command::AddRecoveryCodes(_) => "recovery add",
+            Command::ShowRecoveryCodes(_) => "recovery show",
+            Command::UseRecoveryCode(_) => "recovery use",
+            Command::RegisterChallengeResponse(_) => "chalresp register",
+            Command::RespondToChallenge(_) => "chalresp respond",
+            Command::DeleteChallengeResponse(_) => "chalresp delete",
+        }
+    }
+
+    /// The label of the credential or key the command is about, if it is about one
+    pub fn label(&self) -> Option<&str> {
+        match self {
+            Command::Register(Register { label, .. }) | Command::Authenticate(Authenticate { label, .. })
+            | Command::Precompute(Precompute { label, .. })
+            | Command::Verify(Verify { label, .. }) | Command::Undelete(Undelete { label, .. })
+            | Command::AddRecoveryCodes(AddRecoveryCodes { label, .. })
+            | Command::ShowRecoveryCodes(ShowRecoveryCodes { label, .. })
+            | Command::UseRecoveryCode(UseRecoveryCode { label, .. })
+            | Command::RegisterChallengeResponse(RegisterChallengeResponse { label, .. })
+            | Command::RespondToChallenge(RespondToChallenge { label, .. })
+            | Command::DeleteChallengeResponse(DeleteChallengeResponse { label }) => Some(label),
+            Command::AuthenticateAll(_) | Command::List | Command::Labels | Command::Prune(_) | Command::Repair(_)
+            | Command::ShowPolicy | Command::SetPolicy(_) | Command::ShowIssuers | Command::SetIssuer(_)
+            | Command::ResetIssuer(_) | Command::Stats(_) | Command::Trash(_) => None,
+        }
+    }
+
+    /// The command with its secrets replaced by dummies, which are still valid for it,
+    /// e.g. for transcripts
+    pub fn redacted(&self) -> Command {
+        match self {
+            Command::Register(register) => Command::Register(Register {
+                base32_secret: register.base32_secret.base32_dummy(),
+                ..register.clone()
+            }),
+            // distinct, so none of them is skipped as a duplicate
+            Command::AddRecoveryCodes(add) => Command::AddRecoveryCodes(AddRecoveryCodes {
+                codes: (1..=add.codes.len()).map(|index| format!("redacted-{}", index).into()).collect(),
+                ..add.clone()
+            }),
+            // the codes are redacted, so the first unused one stands in for the one used
+            Command::UseRecoveryCode(use_code) => Command::UseRecoveryCode(UseRecoveryCode {
+                code: None,
+                ..use_code.clone()
+            }),
+            Command::RegisterChallengeResponse(register) => Command::RegisterChallengeResponse(RegisterChallengeResponse {
+                hex_secret: register.hex_secret.as_ref().map(SecretString::hex_dummy),
+                ..register.clone()
+            }),
+            command => command.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// Contains a one-time password, as presented (e.g. left-zero-padded digits)
+pub struct Otp(pub String);
 
-/// OTP codes are typically presented as left-zero-padded strings
 impl core::fmt::Display for Otp {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{:06}", self.0)
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// Response to the `Authenticate` command: the one-time password, and how long it is valid,
+/// e.g. for frontends to render a countdown
+///
+/// OTPs of counter-based algorithms are valid until used, so they have no expiry.
+pub struct Authenticated {
+    /// The one-time password
+    pub otp: Otp,
+    /// Period in seconds after which the next OTP is due
+    pub period: Option<u64>,
+    /// Timestamp (seconds since UNIX epoch) from which on the OTP is outdated
+    pub valid_until: Option<u64>,
+    /// Seconds from the supplied timestamp until the OTP is outdated
+    pub remaining_seconds: Option<u64>,
+}
+
+impl Authenticated {
+    /// The OTP of a time-based credential, valid for the period of the timestamp
+    fn timed(otp: Otp, period: u64, timestamp: u64) -> Self {
+        let valid_until = (timestamp / period).saturating_add(1).saturating_mul(period);
+        Self {
+            otp,
+            period: Some(period),
+            valid_until: Some(valid_until),
+            remaining_seconds: Some(valid_until.saturating_sub(timestamp)),
+        }
     }
 }
 
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// Response to the `AuthenticateAll` command, for each credential
+pub struct LabelledOtp {
+    /// The credential's label
+    pub label: String,
+    /// The credential's slot
+    pub slot: u8,
+    /// The OTP, and how long it is valid
+    #[serde(flatten)]
+    pub authenticated: Authenticated,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A precomputed one-time password, and the period it is valid in
+pub struct Window {
+    /// The one-time password
+    pub otp: Otp,
+    /// Timestamp (seconds since UNIX epoch) from which on the OTP is valid
+    pub start: u64,
+    /// Timestamp (seconds since UNIX epoch) from which on the OTP is outdated
+    pub end: u64,
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 /// The metadata associated with a TOTP secret, enabling later use
 /// in the `Authenticate` command.
@@ -74,119 +498,1261 @@ impl core::fmt::Display for Otp {
 /// credentials to easily be stored in binary format.
 pub struct Credential {
     label: trussed::Bytes<MAX_CRED_LABEL_LENGTH>,
+    slot: u8,
+    period_seconds: u64,
+    key_handle: trussed::types::KeyId,
+    created_at: u64,
+    /// Only set by versions before usage records, cf. `usage`
+    last_used: Option<u64>,
+    /// Length of the secret, as registered
+    secret_length: u8,
+    /// Id of the issuer in the catalog, cf. `issuers`
+    issuer: Option<trussed::Bytes<MAX_ISSUER_FIELD_LENGTH>>,
+    #[serde(skip)]
+    usage: Usage,
+    #[serde(skip)]
+    parameters: Parameters,
+    /// Where the credential is stored, which is implied by where it is found
+    #[serde(skip, default = "internal")]
+    location: Location,
+}
+
+#[derive(Deserialize)]
+/// A credential as stored before secrets of other lengths than 20 bytes were supported
+struct LegacyCredential {
+    label: trussed::Bytes<MAX_CRED_LABEL_LENGTH>,
+    slot: u8,
+    period_seconds: u64,
+    key_handle: trussed::types::KeyId,
+    created_at: u64,
+    last_used: Option<u64>,
+}
+
+impl From<LegacyCredential> for Credential {
+    fn from(legacy: LegacyCredential) -> Self {
+        let LegacyCredential { label, slot, period_seconds, key_handle, created_at, last_used } = legacy;
+        Self {
+            label, slot, period_seconds, key_handle, created_at, last_used,
+            secret_length: LEGACY_SECRET_LENGTH as u8,
+            issuer: None,
+            usage: Usage::default(),
+            parameters: Parameters::default(),
+            location: Location::Internal,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+/// A credential as stored before credentials could refer to their issuer
+struct CredentialWithoutIssuer {
+    label: trussed::Bytes<MAX_CRED_LABEL_LENGTH>,
+    slot: u8,
     period_seconds: u64,
     key_handle: trussed::types::KeyId,
+    created_at: u64,
+    last_used: Option<u64>,
+    secret_length: u8,
+}
+
+impl From<CredentialWithoutIssuer> for Credential {
+    fn from(credential: CredentialWithoutIssuer) -> Self {
+        let CredentialWithoutIssuer { label, slot, period_seconds, key_handle, created_at, last_used, secret_length } = credential;
+        Self {
+            label, slot, period_seconds, key_handle, created_at, last_used, secret_length,
+            issuer: None,
+            usage: Usage::default(),
+            parameters: Parameters::default(),
+            location: Location::Internal,
+        }
+    }
+}
+
+impl Credential {
+    /// The label the credential was registered with
+    pub fn label(&self) -> &str {
+        core::str::from_utf8(&self.label).unwrap_or("<invalid UTF-8>")
+    }
+
+    /// The slot the credential was registered in
+    pub fn slot(&self) -> u8 {
+        self.slot
+    }
+
+    /// Period in seconds after which the counter for the TOTP token is incremented
+    pub fn period_seconds(&self) -> u64 {
+        self.period_seconds
+    }
+
+    /// Timestamp of registration (seconds since UNIX epoch)
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    /// Timestamp of the last authentication (seconds since UNIX epoch), if any
+    pub fn last_used(&self) -> Option<u64> {
+        self.usage.last_used().or(self.last_used)
+    }
+
+    /// How often the credential was used
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    /// The OTP algorithm, and its counter if it is counter-based
+    pub fn parameters(&self) -> &Parameters {
+        &self.parameters
+    }
+
+    /// Length of the secret in bytes
+    pub fn secret_length(&self) -> usize {
+        self.secret_length as usize
+    }
+
+    /// Id of the issuer in the catalog, if the credential refers to one
+    pub fn issuer(&self) -> Option<&str> {
+        self.issuer.as_ref().and_then(|issuer| core::str::from_utf8(issuer).ok())
+    }
+
+    /// Where the credential, and its secret, are stored
+    pub fn location(&self) -> Location {
+        self.location
+    }
+
+    /// Deserializes a credential as stored, failing (instead of panicking) on malformed input
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        // older formats lack the trailing `issuer` (and `secret_length`), so they never parse as newer ones
+        postcard::from_bytes(bytes)
+            .or_else(|_| postcard::from_bytes::<CredentialWithoutIssuer>(bytes).map(Self::from))
+            .or_else(|_| postcard::from_bytes::<LegacyCredential>(bytes).map(Self::from))
+            .map_err(|_| anyhow::anyhow!("malformed credential"))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A deleted credential; its secret is kept in Trussed until it is purged
+pub struct Trashed {
+    credential: Credential,
+    deleted_at: u64,
+}
+
+impl Trashed {
+    /// The deleted credential
+    pub fn credential(&self) -> &Credential {
+        &self.credential
+    }
+
+    /// Timestamp of deletion (seconds since UNIX epoch)
+    pub fn deleted_at(&self) -> u64 {
+        self.deleted_at
+    }
+
+    /// Timestamp after which the credential is purged (seconds since UNIX epoch)
+    pub fn purged_at(&self) -> u64 {
+        self.deleted_at.saturating_add(TRASH_RETENTION_SECONDS)
+    }
+
+    /// Deserializes a deleted credential as stored, failing on malformed input
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        // in the legacy format, `secret_length` would be read from the first byte of
+        // `deleted_at`, which (being a varint of any recent timestamp) is out of range
+        postcard::from_bytes(bytes).ok()
+            .filter(|trashed: &Self| SECRET_LENGTHS.contains(&trashed.credential.secret_length()))
+            .or_else(|| postcard::from_bytes::<LegacyTrashed>(bytes).ok().map(Self::from))
+            .ok_or_else(|| anyhow::anyhow!("malformed deleted credential"))
+    }
+}
+
+#[derive(Deserialize)]
+/// A deleted credential as stored before secrets of other lengths than 20 bytes were supported
+struct LegacyTrashed {
+    credential: LegacyCredential,
+    deleted_at: u64,
+}
+
+impl From<LegacyTrashed> for Trashed {
+    fn from(legacy: LegacyTrashed) -> Self {
+        Self { credential: legacy.credential.into(), deleted_at: legacy.deleted_at }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// The metadata of a challenge-response key, whose secret only Trussed knows
+pub struct ChallengeResponse {
+    label: trussed::Bytes<MAX_CRED_LABEL_LENGTH>,
+    key_handle: trussed::types::KeyId,
+    created_at: u64,
+}
+
+impl ChallengeResponse {
+    /// The label the key was registered with
+    pub fn label(&self) -> &str {
+        core::str::from_utf8(&self.label).unwrap_or("<invalid UTF-8>")
+    }
+
+    /// Timestamp of registration (seconds since UNIX epoch)
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    /// Deserializes a challenge-response key's metadata as stored, failing (instead of panicking)
+    /// on malformed input
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        postcard::from_bytes(bytes).map_err(|_| anyhow::anyhow!("The challenge-response key is corrupted"))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+/// Response to the `List` command
+pub struct Listing {
+    /// All credentials that could be read back
+    pub credentials: Vec<Credential>,
+    /// Paths of the files of credentials that could not be deserialized,
+    /// e.g. `credentials/…`, or `parameters/…` if only the OTP parameters are corrupted
+    pub corrupted: Vec<String>,
 }
 
 impl<T> Authenticator<T>
 where
-    T: trussed::Client + trussed::client::mechanisms::Totp,
+    T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
 {
     /// Constructor, consumes a Trussed client
     pub fn new(trussed: T) -> Self {
-        Self { trussed }
+        Self {
+            trussed,
+            on_event: None,
+            layout_migrated: false,
+            presence_timeout: core::time::Duration::from_secs(5),
+            read_only: false,
+            failure_threshold: FAILURE_THRESHOLD,
+        }
+    }
+
+    /// Changes the number of failures with a credential at which `Event::FailureThreshold`
+    /// is emitted, defaults to `FAILURE_THRESHOLD`; zero never emits it
+    pub fn set_failure_threshold(&mut self, threshold: u32) {
+        self.failure_threshold = threshold;
+    }
+
+    /// Declares that changes to the state are discarded, e.g. with `--read-only`,
+    /// so commands only allowed when they are kept, like handing out HOTP codes, are refused
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Changes how long to wait for confirmation of user presence, defaults to five seconds
+    pub fn set_presence_timeout(&mut self, timeout: core::time::Duration) {
+        self.presence_timeout = timeout;
+    }
+
+    /// Registers a callback, which is called with each `Event` the authenticator emits
+    pub fn set_event_handler(&mut self, handler: impl FnMut(Event) + 'static) {
+        self.on_event = Some(Box::new(handler));
+    }
+
+    fn emit(&mut self, event: Event) {
+        if let Some(handler) = self.on_event.as_mut() {
+            handler(event);
+        }
+    }
+
+    /// Helper method, counting a failure with the credential, once the failures reach the threshold
+    fn record_failure(&mut self, label: &str, usage: &mut Usage) {
+        usage.record_failure();
+        if usage.failures() == self.failure_threshold {
+            self.emit(Event::FailureThreshold { label: label.into() });
+        }
     }
 
     /// Injects the TOTP secret in Trussed's key storage, stores a `Credential`
     /// with the metadata for the secret.
+    ///
+    /// A credential registered before under the same label and slot is replaced, and its
+    /// secret deleted; one in the trash must be restored first.
     pub fn register(&mut self, parameters: &Register) -> Result<()> {
 
-        let  Register { label, base32_secret, period_seconds } = parameters;
+        let  Register { label, base32_secret, period_seconds, slot, timestamp, location, algorithm, counter, issuer } = parameters;
         debug!("register {:?}", parameters);
+        let otp_parameters = Parameters::new(algorithm, *counter)?;
+        if !PERIODS.contains(period_seconds) {
+            return Err(anyhow::anyhow!("The period is {} seconds, supported are {} to {}",
+                period_seconds, PERIODS.start(), PERIODS.end()));
+        }
+        if let Some(issuer) = issuer {
+            issuers::check_id(issuer)?;
+        }
+        self.authorize(Action::Register)?;
+
+        // a deleted credential's usage, parameters and recovery codes are kept under its file name
+        let filename = self.filename_for_label(label, *slot)?;
+        if try_syscall!(self.trussed.read_file(Location::Internal, paths::trashed(&filename))).is_ok() {
+            return Err(anyhow::anyhow!(
+                "A deleted credential labelled {} is in the trash for slot {}, undelete it first", label, slot));
+        }
 
         // 1. Decode TOTP secret
-        let raw_key_bytes = data_encoding::BASE32.decode(&base32_secret.as_bytes())?;
-        let raw_key: [u8; 20] = (&raw_key_bytes[..]).try_into()?;
-        debug!("raw key: {}", hex_str!(&raw_key, 4));
+        let raw_key_bytes = SecretBytes::from_base32(base32_secret)?;
+        let secret_length = raw_key_bytes.expose().len();
+        if !SECRET_LENGTHS.contains(&secret_length) {
+            return Err(anyhow::anyhow!("The secret has {} bytes, supported are {} to {}",
+                secret_length, SECRET_LENGTHS.start(), SECRET_LENGTHS.end()));
+        }
+        debug!("raw key: {:?}", &raw_key_bytes);
 
         // 2. Store secret in Trussed
         let key_handle = syscall!(
             self.trussed
-                .unsafe_inject_shared_key(&raw_key, Location::Internal)
+                .unsafe_inject_shared_key(raw_key_bytes.expose(), *location)
         ).key;
         info!("new key handle: {:?}", key_handle);
 
         // 3. Generate credential
         let credential = Credential {
             label: Bytes::from_slice(label.as_bytes()).map_err(EmptyError::from)?,
+            slot: *slot,
             period_seconds: *period_seconds,
             key_handle,
+            created_at: *timestamp,
+            last_used: None,
+            secret_length: secret_length as u8,
+            // no panic - the id was checked to fit
+            issuer: issuer.as_ref().map(|issuer| Bytes::from_slice(issuer.as_bytes()).unwrap()),
+            usage: Usage::default(),
+            parameters: otp_parameters,
+            location: *location,
+        };
+
+        // 4. Store credential, replacing any previous one with its usage and secret, wherever it is
+        let previous: Vec<Credential> = LOCATIONS.iter()
+            .filter_map(|other| self.load_credential(*other, &filename).ok())
+            .collect();
+        for other in LOCATIONS.iter().filter(|other| **other != *location) {
+            try_syscall!(self.trussed.remove_file(*other, paths::credential(&filename))).ok();
+            Usage::remove(&mut self.trussed, *other, &filename);
+            Parameters::remove(&mut self.trussed, *other, &filename);
+        }
+        self.store_credential(&credential)?;
+        credential.parameters.store(&mut self.trussed, *location, &filename)?;
+        Usage::remove(&mut self.trussed, *location, &filename);
+        for previous in previous {
+            info!("deleting replaced key handle: {:?}", previous.key_handle);
+            try_syscall!(self.trussed.delete(previous.key_handle)).ok();
+            self.emit(Event::Deleted { label: previous.label().into() });
+        }
+
+        // done \o/
+        self.emit(Event::Done);
+        Ok(())
+    }
+
+    /// Looks up a previously registered credential (else fails),
+    /// create a TOTP using the supplied timestamp, and determines its expiry.
+    pub fn authenticate(&mut self, parameters: &Authenticate) -> Result<Authenticated> {
+        let Authenticate { label, slot, timestamp } = parameters;
+        debug!("authenticate {:?}", parameters);
+
+        let credential = self.find_credential(label, *slot)?;
+        let otp = self.otp(&credential, *timestamp)?;
+        let counter_based = algorithm::find(credential.parameters.algorithm())?.is_counter_based();
+        // the advanced counter would be discarded, and the same code handed out again
+        if counter_based && self.read_only {
+            return Err(crate::middleware::ReadOnlyRefusal(
+                format!("Codes of {} credentials advance a counter", credential.parameters.algorithm())).into());
+        }
+        let filename = self.filename_for_label(credential.label(), credential.slot)?;
+        let mut usage = credential.usage;
+        if let Err(error) = self.authorize(Action::Authenticate) {
+            self.record_failure(credential.label(), &mut usage);
+            usage.store(&mut self.trussed, credential.location, &filename)?;
+            return Err(error);
+        }
+
+        usage.record_use(*timestamp);
+        self.emit(Event::StorageBusy);
+        usage.store(&mut self.trussed, credential.location, &filename)?;
+        // each code of a counter-based algorithm is only handed out once
+        if counter_based {
+            let mut parameters = credential.parameters.clone();
+            parameters.advance();
+            parameters.store(&mut self.trussed, credential.location, &filename)?;
+        }
+
+        // done \o_
+        self.emit(Event::Done);
+        if counter_based {
+            return Ok(Authenticated { otp, period: None, valid_until: None, remaining_seconds: None });
+        }
+        Ok(Authenticated::timed(otp, credential.period_seconds, *timestamp))
+    }
+
+    /// Authenticates with all time-based credentials, sorted by label and slot.
+    ///
+    /// The credentials are read in one go, and user presence is confirmed once, instead of
+    /// for each credential. Counter-based credentials are left out, as each of their codes
+    /// is only handed out when asked for.
+    pub fn authenticate_all(&mut self, parameters: &AuthenticateAll) -> Result<Vec<LabelledOtp>> {
+        let AuthenticateAll { timestamp } = *parameters;
+        debug!("authenticate all {:?}", parameters);
+
+        let mut credentials = Vec::new();
+        for credential in self.listing().credentials {
+            if !algorithm::find(credential.parameters.algorithm())?.is_counter_based() {
+                let filename = self.filename_for_label(credential.label(), credential.slot)?;
+                credentials.push((credential, filename));
+            }
+        }
+        credentials.sort_by(|(a, _), (b, _)| (a.label(), a.slot).cmp(&(b.label(), b.slot)));
+
+        if let Err(error) = self.authorize(Action::Authenticate) {
+            for (credential, filename) in credentials.iter() {
+                let mut usage = credential.usage;
+                self.record_failure(credential.label(), &mut usage);
+                usage.store(&mut self.trussed, credential.location, filename)?;
+            }
+            return Err(error);
+        }
+
+        let mut otps = Vec::new();
+        for (mut credential, filename) in credentials {
+            let otp = self.otp(&credential, timestamp)?;
+            credential.usage.record_use(timestamp);
+            self.emit(Event::StorageBusy);
+            credential.usage.store(&mut self.trussed, credential.location, &filename)?;
+            otps.push(LabelledOtp {
+                label: credential.label().into(),
+                slot: credential.slot,
+                authenticated: Authenticated::timed(otp, credential.period_seconds, timestamp),
+            });
+        }
+        self.emit(Event::Done);
+        Ok(otps)
+    }
+
+    /// Computes the OTPs of the periods starting with the timestamp's, along with the
+    /// periods they are valid in.
+    ///
+    /// Precomputed codes don't need the authenticator anymore, like a copy of the secret
+    /// for their periods, so the policy has a requirement of its own for them. Codes of
+    /// counter-based algorithms are handed out one by one, with `authenticate`.
+    pub fn precompute(&mut self, parameters: &Precompute) -> Result<Vec<Window>> {
+        let Precompute { label, slot, timestamp, count } = parameters;
+        debug!("precompute {:?}", parameters);
+        if !(1..=MAX_PRECOMPUTED).contains(count) {
+            return Err(anyhow::anyhow!("Can precompute 1 to {} codes, not {}", MAX_PRECOMPUTED, count));
+        }
+
+        let credential = self.find_credential(label, *slot)?;
+        if algorithm::find(credential.parameters.algorithm())?.is_counter_based() {
+            return Err(anyhow::anyhow!("Codes of {} can't be precomputed", credential.parameters.algorithm()));
+        }
+        let filename = self.filename_for_label(credential.label(), credential.slot)?;
+        let mut usage = credential.usage;
+        if let Err(error) = self.authorize(Action::Precompute) {
+            self.record_failure(credential.label(), &mut usage);
+            usage.store(&mut self.trussed, credential.location, &filename)?;
+            return Err(error);
+        }
+
+        let period = credential.period_seconds;
+        let first = timestamp / period;
+        let mut windows = Vec::new();
+        for counter in first..first.saturating_add(*count as u64) {
+            let start = counter.saturating_mul(period);
+            windows.push(Window {
+                otp: self.otp(&credential, start)?,
+                start,
+                end: start.saturating_add(period),
+            });
+        }
+
+        usage.record_use(*timestamp);
+        self.emit(Event::StorageBusy);
+        usage.store(&mut self.trussed, credential.location, &filename)?;
+        self.emit(Event::Done);
+        Ok(windows)
+    }
+
+    /// Shows which commands require what.
+    pub fn policy(&mut self) -> Result<Policy> {
+        Policy::load(&mut self.trussed)
+    }
+
+    /// Changes the requirement for a command.
+    ///
+    /// This always requires user presence, otherwise the policy could be weakened unnoticed.
+    pub fn set_policy(&mut self, parameters: &SetPolicy) -> Result<()> {
+        debug!("set policy {:?}", parameters);
+        let mut policy = Policy::load(&mut self.trussed)?;
+        self.confirm_presence()?;
+        policy.set(parameters.action, parameters.requirement);
+        self.emit(Event::StorageBusy);
+        policy.store(&mut self.trussed)?;
+        self.emit(Event::Done);
+        Ok(())
+    }
+
+    /// The catalog of issuers, with the user's overrides applied
+    pub fn issuers(&mut self) -> Result<issuers::Catalog> {
+        Ok(issuers::Catalog::new(&issuers::Overrides::load(&mut self.trussed)?))
+    }
+
+    /// Overrides how an issuer is shown, or adds one.
+    ///
+    /// This changes what listings show about credentials, so it requires what registering does.
+    pub fn set_issuer(&mut self, parameters: &SetIssuer) -> Result<()> {
+        let SetIssuer { id, name, icon } = parameters;
+        debug!("set issuer {:?}", parameters);
+        let mut overrides = issuers::Overrides::load(&mut self.trussed)?;
+        overrides.set(issuers::Issuer { id: id.clone(), name: name.clone(), icon: icon.clone() })?;
+        self.authorize(Action::Register)?;
+        self.emit(Event::StorageBusy);
+        overrides.store(&mut self.trussed)?;
+        self.emit(Event::Done);
+        Ok(())
+    }
+
+    /// Removes the override of an issuer, failing if there is none
+    pub fn reset_issuer(&mut self, parameters: &ResetIssuer) -> Result<()> {
+        debug!("reset issuer {:?}", parameters);
+        let mut overrides = issuers::Overrides::load(&mut self.trussed)?;
+        if !overrides.reset(&parameters.id) {
+            return Err(anyhow::anyhow!("The issuer {} is not overridden", parameters.id));
+        }
+        self.authorize(Action::Register)?;
+        self.emit(Event::StorageBusy);
+        overrides.store(&mut self.trussed)?;
+        self.emit(Event::Done);
+        Ok(())
+    }
+
+    /// Helper method, enforcing the policy for an action
+    fn authorize(&mut self, action: Action) -> Result<()> {
+        let requirement = Policy::load(&mut self.trussed)?.requirement(action);
+        debug!("{} requires {}", action.name(), requirement);
+        match requirement {
+            Requirement::Nothing => Ok(()),
+            Requirement::Presence => self.confirm_presence(),
+        }
+    }
+
+    /// Helper method, asking the user to confirm their presence
+    fn confirm_presence(&mut self) -> Result<()> {
+        self.emit(Event::WaitingForTouch);
+        policy::confirm_presence(&mut self.trussed, self.presence_timeout)
+    }
+
+    /// Helper method, calculating the OTP of a credential, with its algorithm
+    fn otp(&mut self, credential: &Credential, timestamp: u64) -> Result<Otp> {
+        debug!("found credential: {:?}", credential);
+
+        let algorithm = algorithm::find(credential.parameters.algorithm())?;
+        let counter = match algorithm.is_counter_based() {
+            true => credential.parameters.counter(),
+            false => timestamp / credential.period_seconds,
+        };
+
+        // Trussed only calculates the HMAC, the algorithm makes a code of it
+        let counter_bytes: [u8; 8] = counter.to_be_bytes();
+        let hmac = syscall!(self.trussed.sign(
+            Mechanism::HmacSha1,
+            credential.key_handle,
+            &counter_bytes,
+            SignatureSerialization::Raw,
+        )).signature;
+        let otp = algorithm.code(&hmac)?;
+        debug!("calculated OTP: {}", otp);
+
+        Ok(Otp(otp))
+    }
+
+    /// Checks whether the supplied one-time password is the one `authenticate`
+    /// generates for the supplied timestamp.
+    pub fn verify(&mut self, parameters: &Verify) -> Result<bool> {
+        let Verify { label, slot, timestamp, otp } = parameters;
+        debug!("verify {:?}", parameters);
+
+        let credential = self.find_credential(label, *slot)?;
+        // checking a code would have to advance the counter, as handing it out does
+        if algorithm::find(credential.parameters.algorithm())?.is_counter_based() {
+            return Err(anyhow::anyhow!("Codes of {} credentials can't be verified", credential.parameters.algorithm()));
+        }
+        let expected = self.otp(&credential, *timestamp)?;
+        let filename = self.filename_for_label(credential.label(), credential.slot)?;
+        let mut usage = credential.usage;
+        if let Err(error) = self.authorize(Action::Verify) {
+            self.record_failure(credential.label(), &mut usage);
+            usage.store(&mut self.trussed, credential.location, &filename)?;
+            return Err(error);
+        }
+
+        // the expected OTP is derived from the secret, comparing it must not leak timing information
+        let valid = constant_time_eq(expected.0.as_bytes(), otp.0.as_bytes());
+        if !valid {
+            self.record_failure(credential.label(), &mut usage);
+            usage.store(&mut self.trussed, credential.location, &filename)?;
+        }
+        self.emit(Event::Done);
+        Ok(valid)
+    }
+
+    /// Reads back all stored credentials, reporting those which fail to deserialize.
+    pub fn list(&mut self) -> Result<Listing> {
+        self.authorize(Action::List)?;
+        Ok(self.listing())
+    }
+
+    /// Finds the credentials that have not been used (or registered) for the given time,
+    /// and moves them to the trash if asked to.
+    pub fn prune(&mut self, parameters: &Prune) -> Result<Vec<Credential>> {
+        let Prune { timestamp, unused_for_seconds, delete } = parameters;
+        debug!("prune {:?}", parameters);
+        if *delete {
+            self.authorize(Action::Prune)?;
+        }
+
+        let cutoff = timestamp.saturating_sub(*unused_for_seconds);
+        let stale: Vec<Credential> = self.listing().credentials.into_iter()
+            .filter(|credential| credential.last_used().unwrap_or(credential.created_at) < cutoff)
+            .collect();
+
+        if *delete {
+            for credential in stale.iter() {
+                info!("deleting stale credential {}", credential.label());
+                // only the internal store keeps deleted credentials, as the others may not persist
+                match credential.location {
+                    Location::Internal => self.move_to_trash(credential, *timestamp)?,
+                    _ => self.delete_credential(credential)?,
+                }
+            }
+            self.purge(*timestamp);
+        }
+
+        self.emit(Event::Done);
+        Ok(stale)
+    }
+
+    /// Lists the credentials in the trash, after purging those deleted too long ago.
+    pub fn trash(&mut self, parameters: &Trash) -> Result<Vec<Trashed>> {
+        debug!("trash {:?}", parameters);
+        self.authorize(Action::List)?;
+        self.purge(parameters.timestamp);
+        let trashed = self.trashed().into_iter().map(|(_, trashed)| trashed).collect();
+        self.emit(Event::Done);
+        Ok(trashed)
+    }
+
+    /// Restores a credential from the trash, unless another one was registered in its place.
+    pub fn undelete(&mut self, parameters: &Undelete) -> Result<Credential> {
+        let Undelete { label, slot } = parameters;
+        debug!("undelete {:?}", parameters);
+        self.authorize(Action::Register)?;
+
+        let (filename, trashed) = self.trashed().into_iter()
+            .filter(|(_, trashed)| trashed.credential.label() == label)
+            .filter(|(_, trashed)| slot.map_or(true, |slot| trashed.credential.slot == slot))
+            .max_by_key(|(_, trashed)| trashed.deleted_at)
+            .ok_or_else(|| anyhow::anyhow!("Could not find a deleted credential labelled {}", label))?;
+
+        let registered = LOCATIONS.iter().any(|location| {
+            try_syscall!(self.trussed.read_file(*location, paths::credential(&filename))).is_ok()
+        });
+        if registered {
+            return Err(anyhow::anyhow!(
+                "Another credential labelled {} is registered in slot {}", label, trashed.credential.slot));
+        }
+        self.store_credential(&trashed.credential)?;
+        syscall!(self.trussed.remove_file(Location::Internal, paths::trashed(&filename)));
+
+        self.emit(Event::Done);
+        Ok(trashed.credential)
+    }
+
+    /// Moves the files of credentials that fail to deserialize into the quarantine directory,
+    /// or deletes them. Returns the paths of the affected files.
+    ///
+    /// Only the file that fails is affected: a credential whose OTP parameters are corrupted
+    /// is kept, and falls back to TOTP, like credentials stored before algorithms could be chosen.
+    /// Deleting also empties the quarantine, which keeps `referenced_keys` from telling unused keys.
+    pub fn repair(&mut self, parameters: &Repair) -> Result<Vec<String>> {
+        debug!("repair {:?}", parameters);
+        self.authorize(Action::Repair)?;
+        let mut repaired = Vec::new();
+
+        if parameters.delete {
+            for (location, path) in self.quarantined() {
+                info!("deleting quarantined file {}", path.as_ref());
+                syscall!(self.trussed.remove_file(location, path.clone()));
+                repaired.push(String::from(path.as_ref()));
+            }
+        }
+
+        for (location, filename) in self.credential_files() {
+            let path = match self.load_credential(location, &filename) {
+                Ok(_) => continue,
+                Err(path) => path,
+            };
+            info!("corrupted file {}", path.as_ref());
+
+            if !parameters.delete {
+                let data = syscall!(self.trussed.read_file(location, path.clone())).data;
+                syscall!(self.trussed.write_file(location, paths::quarantined(&path), data, None));
+            }
+            syscall!(self.trussed.remove_file(location, path.clone()));
+
+            repaired.push(String::from(path.as_ref()));
+        }
+
+        Ok(repaired)
+    }
+
+    /// Keeps recovery codes for a credential, sealed by Trussed.
+    ///
+    /// Only credentials in the internal store can have recovery codes, which must outlast the session.
+    pub fn add_recovery_codes(&mut self, parameters: &AddRecoveryCodes) -> Result<()> {
+        let AddRecoveryCodes { label, slot, codes } = parameters;
+        debug!("add recovery codes {:?}", parameters);
+        self.authorize(Action::Register)?;
+
+        let credential = self.find_credential(label, *slot)?;
+        if credential.location != Location::Internal {
+            return Err(anyhow::anyhow!("Only credentials in the internal store can have recovery codes"));
+        }
+        let filename = self.filename_for_label(credential.label(), credential.slot)?;
+        let mut recovery_codes = RecoveryCodes::load(&mut self.trussed, &filename)?;
+        recovery_codes.add(codes)?;
+        self.emit(Event::StorageBusy);
+        recovery_codes.store(&mut self.trussed, &filename)?;
+
+        self.emit(Event::Done);
+        Ok(())
+    }
+
+    /// Unseals the recovery codes of a credential, which is as sensitive as authenticating.
+    pub fn show_recovery_codes(&mut self, parameters: &ShowRecoveryCodes) -> Result<Vec<RecoveryCode>> {
+        let ShowRecoveryCodes { label, slot } = parameters;
+        debug!("show recovery codes {:?}", parameters);
+
+        let credential = self.find_credential(label, *slot)?;
+        self.authorize(Action::Authenticate)?;
+        let filename = self.filename_for_label(credential.label(), credential.slot)?;
+        let recovery_codes = RecoveryCodes::load(&mut self.trussed, &filename)?;
+
+        self.emit(Event::Done);
+        Ok(recovery_codes.codes().to_vec())
+    }
+
+    /// Marks a recovery code of a credential as used, returning it.
+    pub fn use_recovery_code(&mut self, parameters: &UseRecoveryCode) -> Result<RecoveryCode> {
+        let UseRecoveryCode { label, slot, code, timestamp } = parameters;
+        debug!("use recovery code {:?}", parameters);
+
+        let credential = self.find_credential(label, *slot)?;
+        self.authorize(Action::Authenticate)?;
+        let filename = self.filename_for_label(credential.label(), credential.slot)?;
+        let mut recovery_codes = RecoveryCodes::load(&mut self.trussed, &filename)?;
+        let used = recovery_codes.consume(code.as_ref(), *timestamp)?;
+        self.emit(Event::StorageBusy);
+        recovery_codes.store(&mut self.trussed, &filename)?;
+
+        self.emit(Event::Done);
+        Ok(used)
+    }
+
+    /// Injects (or generates) an HMAC-SHA1 key in Trussed's key storage, replacing any key
+    /// registered with the label before.
+    pub fn register_challenge_response(&mut self, parameters: &RegisterChallengeResponse) -> Result<()> {
+        let RegisterChallengeResponse { label, hex_secret, timestamp } = parameters;
+        debug!("register challenge-response {:?}", parameters);
+        self.authorize(Action::Register)?;
+
+        let secret = match hex_secret {
+            Some(hex_secret) => SecretBytes::from_hex(hex_secret)?,
+            None => {
+                let random = syscall!(self.trussed.random_bytes(CHALLENGE_RESPONSE_KEY_LENGTH)).bytes;
+                SecretBytes::from(random.to_vec())
+            }
+        };
+        // HMAC hashes longer keys down, YubiKeys don't take them
+        if secret.expose().is_empty() || secret.expose().len() > MAX_CHALLENGE_RESPONSE_KEY_LENGTH {
+            return Err(anyhow::anyhow!("The secret has {} bytes, supported are 1 to {}",
+                secret.expose().len(), MAX_CHALLENGE_RESPONSE_KEY_LENGTH));
+        }
+        let key_handle = syscall!(
+            self.trussed.unsafe_inject_shared_key(secret.expose(), Location::Internal)
+        ).key;
+
+        let challenge_response = ChallengeResponse {
+            label: Bytes::from_slice(label.as_bytes()).map_err(EmptyError::from)?,
+            key_handle,
+            created_at: *timestamp,
         };
+        let filename = self.filename_for_label(label, 0)?;
+        if let Some(previous) = self.load_challenge_response(&filename) {
+            syscall!(self.trussed.delete(previous.key_handle));
+        }
         let mut buf = [0u8; 512];
-        let serialized_credential = postcard::to_slice(&credential, &mut buf)
+        let serialized = postcard::to_slice(&challenge_response, &mut buf)
             .map_err(|_| anyhow::anyhow!("postcard serialization error"))?;
+        self.emit(Event::StorageBusy);
+        syscall!(self.trussed.write_file(
+            Location::Internal,
+            paths::challenge_response(&filename),
+            Bytes::from_slice(&*serialized).unwrap(),
+            None
+        ));
 
-        // 4. Store credential
-        let filename = self.filename_for_label(&label);
-        debug!("saving to filename {}", filename.as_ref());
+        self.emit(Event::Done);
+        Ok(())
+    }
+
+    /// Calculates the HMAC-SHA1 of the challenge with a challenge-response key, which
+    /// requires what authenticating requires.
+    pub fn respond_to_challenge(&mut self, parameters: &RespondToChallenge) -> Result<Vec<u8>> {
+        let RespondToChallenge { label, challenge } = parameters;
+        debug!("respond to challenge {:?}", parameters);
+        if challenge.len() > MAX_CHALLENGE_LENGTH {
+            return Err(anyhow::anyhow!("Challenges have at most {} bytes", MAX_CHALLENGE_LENGTH));
+        }
+
+        let challenge_response = self.find_challenge_response(label)?;
+        self.authorize(Action::Authenticate)?;
+        let response = syscall!(self.trussed.sign(
+            Mechanism::HmacSha1,
+            challenge_response.key_handle,
+            challenge,
+            SignatureSerialization::Raw,
+        )).signature;
+
+        self.emit(Event::Done);
+        Ok(response.to_vec())
+    }
+
+    /// Deletes a challenge-response key, which requires what pruning requires.
+    pub fn delete_challenge_response(&mut self, parameters: &DeleteChallengeResponse) -> Result<()> {
+        debug!("delete challenge-response {:?}", parameters);
+        let challenge_response = self.find_challenge_response(&parameters.label)?;
+        self.authorize(Action::Prune)?;
 
+        let filename = self.filename_for_label(&parameters.label, 0)?;
+        self.emit(Event::StorageBusy);
+        syscall!(self.trussed.remove_file(Location::Internal, paths::challenge_response(&filename)));
+        syscall!(self.trussed.delete(challenge_response.key_handle));
+        self.emit(Event::Deleted { label: parameters.label.clone() });
+
+        self.emit(Event::Done);
+        Ok(())
+    }
+
+    /// Reads back the credentials with counter-based algorithms, for the runner to check their
+    /// counters against a record kept outside the state.
+    ///
+    /// Counters are no secret, and the check runs before every command, so this is not subject
+    /// to the policy.
+    pub fn counter_based(&mut self) -> Vec<Credential> {
+        self.listing().credentials.into_iter()
+            .filter(|credential| algorithm::find(credential.parameters().algorithm())
+                .map_or(false, |algorithm| algorithm.is_counter_based()))
+            .collect()
+    }
+
+    /// Checks the MAC over the policy and the key index, cf. `integrity`; without one,
+    /// e.g. in a new state, there is nothing to check.
+    ///
+    /// Like `counter_based`, this runs before every command, so it is not subject to the policy.
+    pub fn verify_integrity(&mut self) -> bool {
+        match Integrity::load(&mut self.trussed) {
+            Ok(Some(integrity)) => {
+                let digest = self.integrity_digest();
+                integrity.verify(&mut self.trussed, &digest)
+            }
+            Ok(None) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// Updates the MAC over the policy and the key index, after commands changed them.
+    ///
+    /// Read-only, nothing is stored, as the changes would be discarded anyway.
+    pub fn update_integrity(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let previous = Integrity::load(&mut self.trussed)?;
+        let digest = self.integrity_digest();
+        let integrity = Integrity::compute(&mut self.trussed, previous.as_ref().map(Integrity::key), &digest)?;
+        if previous.as_ref() != Some(&integrity) {
+            self.emit(Event::StorageBusy);
+            integrity.store(&mut self.trussed)?;
+            self.emit(Event::Done);
+        }
+        Ok(())
+    }
+
+    /// Accepts the policy and the key index as they are, after they were changed outside
+    /// the app, under a new MAC key.
+    ///
+    /// This always requires user presence, otherwise forged records could be accepted unnoticed.
+    pub fn accept_integrity(&mut self) -> Result<()> {
+        self.confirm_presence()?;
+        if self.read_only {
+            return Ok(());
+        }
+        warn!("accepting the policy and credentials as changed outside the app");
+        // the record may be corrupted, or its key gone
+        if let Ok(Some(previous)) = Integrity::load(&mut self.trussed) {
+            try_syscall!(self.trussed.delete(previous.key())).ok();
+        }
+        let digest = self.integrity_digest();
+        let integrity = Integrity::compute(&mut self.trussed, None, &digest)?;
+        self.emit(Event::StorageBusy);
+        integrity.store(&mut self.trussed)?;
+        self.emit(Event::Done);
+        Ok(())
+    }
+
+    /// Lists the keys that credentials, including deleted ones, their recovery codes,
+    /// challenge-response keys and the integrity MAC refer to.
+    ///
+    /// Fails if some credential files can't be read, as their keys would seem unused; this
+    /// includes quarantined ones, until `repair --delete` deletes them.
+    pub fn referenced_keys(&mut self) -> Result<Vec<trussed::types::KeyId>> {
+        self.authorize(Action::List)?;
+        let listing = self.listing();
+        if !listing.corrupted.is_empty() {
+            return Err(anyhow::anyhow!(
+                "{} credential files are corrupted (use `repair` to quarantine them)", listing.corrupted.len()));
+        }
+        // the OTP parameters refer to no keys
+        let parameters = paths::quarantined(&PathBuf::from(paths::PARAMETERS_DIR));
+        let quarantined = self.quarantined().into_iter()
+            .filter(|(_, path)| !path.as_ref().starts_with(parameters.as_ref()))
+            .count();
+        if quarantined > 0 {
+            return Err(anyhow::anyhow!(
+                "{} quarantined credential files may refer to keys, which would seem unused \
+                (use `repair --delete` to delete them)", quarantined));
+        }
+        let mut keys: Vec<_> = listing.credentials.iter().map(|credential| credential.key_handle).collect();
+        keys.extend(self.trashed().into_iter().map(|(_, trashed)| trashed.credential.key_handle));
+        for filename in self.files_in(Location::Internal, PathBuf::from(paths::RECOVERY_DIR)) {
+            keys.extend(RecoveryCodes::key(&mut self.trussed, &filename)?);
+        }
+        for filename in self.files_in(Location::Internal, PathBuf::from(paths::CHALLENGE_RESPONSE_DIR)) {
+            let challenge_response = self.load_challenge_response(&filename)
+                .ok_or_else(|| anyhow::anyhow!("The challenge-response key {} is corrupted", filename.as_ref()))?;
+            keys.push(challenge_response.key_handle);
+        }
+        keys.extend(Integrity::load(&mut self.trussed)?.map(|integrity| integrity.key()));
+        Ok(keys)
+    }
+
+    /// Helper method, loading the credential in the given slot,
+    /// or in the highest slot registered under the label
+    fn find_credential(&mut self, label: &str, slot: Option<u8>) -> Result<Credential> {
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                let filenames = self.credential_files();
+                return filenames.iter()
+                    .filter_map(|(location, filename)| self.load_credential(*location, filename).ok())
+                    .filter(|credential| credential.label() == label)
+                    .max_by_key(|credential| credential.slot)
+                    .ok_or_else(|| anyhow::anyhow!("Could not find a credential labelled {}", label));
+            }
+        };
+
+        let filename = self.filename_for_label(&label, slot)?;
+        self.emit(Event::StorageBusy);
+        let (location, serialized_credential) = LOCATIONS.iter()
+            .find_map(|location| {
+                try_syscall!(self.trussed.read_file(*location, paths::credential(&filename)))
+                    .ok().map(|reply| (*location, reply.data))
+            })
+            .ok_or_else(|| anyhow::anyhow!("Could not find a credential labelled {} in slot {}", label, slot))?;
+
+        let mut credential = Credential::from_bytes(serialized_credential.as_ref())
+            .map_err(|_| anyhow::anyhow!(
+                "The credential labelled {} is corrupted (use `repair` to quarantine it)", label))?;
+        // file names are truncated hashes, so they may collide for different labels
+        if credential.label() != label {
+            return Err(anyhow::anyhow!("Could not find a credential labelled {} in slot {}", label, slot));
+        }
+        credential.usage = Usage::load(&mut self.trussed, location, &filename);
+        credential.parameters = Parameters::load(&mut self.trussed, location, &filename)?;
+        credential.location = location;
+        Ok(credential)
+    }
+
+    /// Helper method, moving a credential to the trash; its secret and usage are kept
+    fn move_to_trash(&mut self, credential: &Credential, timestamp: u64) -> Result<()> {
+        let filename = self.filename_for_label(credential.label(), credential.slot)?;
+        // a credential deleted earlier under the same label and slot can't be kept
+        if let Some(previous) = self.load_trashed(&filename) {
+            syscall!(self.trussed.delete(previous.credential.key_handle));
+        }
+
+        let trashed = Trashed { credential: credential.clone(), deleted_at: timestamp };
+        let mut buf = [0u8; 512];
+        let serialized_trashed = postcard::to_slice(&trashed, &mut buf)
+            .map_err(|_| anyhow::anyhow!("postcard serialization error"))?;
+        self.emit(Event::StorageBusy);
         syscall!(self.trussed.write_file(
             Location::Internal,
-            filename,
-            Bytes::from_slice(&*serialized_credential).unwrap(),
+            paths::trashed(&filename),
+            Bytes::from_slice(&*serialized_trashed).unwrap(),
             None
         ));
+        syscall!(self.trussed.remove_file(Location::Internal, paths::credential(&filename)));
+        self.emit(Event::Deleted { label: credential.label().into() });
+        Ok(())
+    }
 
-        // done \o/
+    /// Helper method, deleting a credential right away, with its secret, usage and recovery codes
+    fn delete_credential(&mut self, credential: &Credential) -> Result<()> {
+        let filename = self.filename_for_label(credential.label(), credential.slot)?;
+        self.emit(Event::StorageBusy);
+        syscall!(self.trussed.remove_file(credential.location, paths::credential(&filename)));
+        Usage::remove(&mut self.trussed, credential.location, &filename);
+        Parameters::remove(&mut self.trussed, credential.location, &filename);
+        RecoveryCodes::remove(&mut self.trussed, &filename);
+        syscall!(self.trussed.delete(credential.key_handle));
+        self.emit(Event::Deleted { label: credential.label().into() });
         Ok(())
     }
 
-    /// Looks up a previously registered credential (else fails),
-    /// create a TOTP using the supplied timestamp.
-    pub fn authenticate(&mut self, parameters: &Authenticate) -> Result<Otp> {
-        let Authenticate { label, timestamp } = parameters;
-        debug!("authenticate {:?}", parameters);
+    /// Helper method, finally deleting the credentials in the trash whose time has come
+    fn purge(&mut self, timestamp: u64) {
+        for (filename, trashed) in self.trashed() {
+            if trashed.purged_at() <= timestamp {
+                info!("purging deleted credential {}", trashed.credential.label());
+                self.emit(Event::StorageBusy);
+                syscall!(self.trussed.remove_file(Location::Internal, paths::trashed(&filename)));
+                Usage::remove(&mut self.trussed, Location::Internal, &filename);
+                Parameters::remove(&mut self.trussed, Location::Internal, &filename);
+                RecoveryCodes::remove(&mut self.trussed, &filename);
+                syscall!(self.trussed.delete(trashed.credential.key_handle));
+                self.emit(Event::Deleted { label: trashed.credential.label().into() });
+            }
+        }
+    }
+
+    /// Helper method, reading back all credentials in the trash, with their file names
+    fn trashed(&mut self) -> Vec<(PathBuf, Trashed)> {
+        self.files_in(Location::Internal, PathBuf::from(paths::TRASH_DIR)).into_iter()
+            .filter_map(|filename| self.load_trashed(&filename).map(|trashed| (filename, trashed)))
+            .collect()
+    }
 
-        // 1. Load credential
-        let filename = self.filename_for_label(&label);
-        let serialized_credential = try_syscall!(self.trussed.read_file(
+    /// Helper method, listing the paths of the quarantined files, with their location
+    ///
+    /// Files are quarantined with their own path (cf. `paths::quarantined`), or were moved
+    /// directly into the quarantine directory before.
+    fn quarantined(&mut self) -> Vec<(Location, PathBuf)> {
+        let mut files = Vec::new();
+        for location in LOCATIONS.iter() {
+            for dir in [None, Some(paths::CREDENTIALS_DIR), Some(paths::PARAMETERS_DIR)].iter() {
+                let dir = match dir {
+                    Some(dir) => paths::quarantined(&PathBuf::from(*dir)),
+                    None => PathBuf::from(paths::QUARANTINE_DIR),
+                };
+                for filename in self.files_in(*location, dir.clone()) {
+                    let mut path = dir.clone();
+                    path.push(&filename);
+                    files.push((*location, path));
+                }
+            }
+        }
+        files
+    }
+
+    /// Helper method, loading a credential from the trash
+    fn load_trashed(&mut self, filename: &PathBuf) -> Option<Trashed> {
+        let serialized_trashed = try_syscall!(self.trussed.read_file(
             Location::Internal,
-            filename,
-        ))
-            .map_err(|_| anyhow::anyhow!("Could not find a credential labelled {}", label))?
-            .data;
-
-        let credential: Credential = postcard::from_bytes(serialized_credential.as_ref())
-            .map_err(|_| anyhow::anyhow!("postcard deserialization error"))?;
-        debug!("found credential: {:?}", &credential);
-
-        // 2. Calculate OTP
-        let counter = *timestamp / credential.period_seconds;
-
-        // // TODO: take this out of Trussed again, and implement "by hand" for posterity
-        // let counter_bytes: [u8; 8] = counter.to_be_bytes();
-        // let hmac = syscall!(self.trussed.sign(
-        //     Mechanism::Totp,
-        //     credential.handle,
-        //     &counter_bytes,
-        //     SignatureSerialization::Raw,
-        // )).signature;
-        // debug!("calculated HMAC: {}", hex_str!(&hmac, 4));
-
-        let otp = syscall!(self.trussed.sign_totp(
-            credential.key_handle,
-            counter,
-        )).signature;
+            paths::trashed(filename),
+        )).ok()?.data;
+        Trashed::from_bytes(serialized_trashed.as_ref()).ok()
+    }
 
-        try_syscall!(self.trussed.confirm_user_present(5_000))
-            .map_err(|_| anyhow::anyhow!("Could not obtain confirmation of user presence!"))?;
+    /// Helper method, loading the challenge-response key with the label, else failing
+    fn find_challenge_response(&mut self, label: &str) -> Result<ChallengeResponse> {
+        let filename = self.filename_for_label(label, 0)?;
+        self.emit(Event::StorageBusy);
+        self.load_challenge_response(&filename)
+            .filter(|challenge_response| challenge_response.label() == label)
+            .ok_or_else(|| anyhow::anyhow!("Could not find a challenge-response key labelled {}", label))
+    }
 
-        let otp = u64::from_le_bytes(otp[..8].try_into().unwrap());
-        debug!("calculated OTP: {}", otp);
+    /// Helper method, loading a challenge-response key's metadata
+    fn load_challenge_response(&mut self, filename: &PathBuf) -> Option<ChallengeResponse> {
+        let serialized = try_syscall!(self.trussed.read_file(
+            Location::Internal,
+            paths::challenge_response(filename),
+        )).ok()?.data;
+        ChallengeResponse::from_bytes(serialized.as_ref()).ok()
+    }
 
-        // done \o_
-        Ok(Otp(otp))
+    /// Helper method, reading back all credentials
+    fn listing(&mut self) -> Listing {
+        let mut listing = Listing::default();
+
+        for (location, filename) in self.credential_files() {
+            match self.load_credential(location, &filename) {
+                Ok(credential) => listing.credentials.push(credential),
+                Err(path) => listing.corrupted.push(String::from(path.as_ref())),
+            }
+        }
+
+        listing
+    }
+
+    /// Helper method, serializing and storing a credential
+    fn store_credential(&mut self, credential: &Credential) -> Result<()> {
+        let mut buf = [0u8; 512];
+        let serialized_credential = postcard::to_slice(credential, &mut buf)
+            .map_err(|_| anyhow::anyhow!("postcard serialization error"))?;
+
+        let filename = self.filename_for_label(credential.label(), credential.slot)?;
+        debug!("saving to filename {}", filename.as_ref());
+
+        self.emit(Event::StorageBusy);
+        syscall!(self.trussed.write_file(
+            credential.location,
+            paths::credential(&filename),
+            Bytes::from_slice(&*serialized_credential).unwrap(),
+            None
+        ));
+        Ok(())
+    }
+
+    /// Helper method, listing the names of the files in a directory
+    fn files_in(&mut self, location: Location, dir: PathBuf) -> Vec<PathBuf> {
+        self.emit(Event::StorageBusy);
+        let mut files = Vec::new();
+        // the directory doesn't exist until the first file is stored in it
+        let mut entry = match try_syscall!(self.trussed.read_dir_first(location, dir, None)) {
+            Ok(reply) => reply.entry,
+            Err(_) => None,
+        };
+        while let Some(dir_entry) = entry {
+            if dir_entry.metadata().is_file() {
+                files.push(dir_entry.file_name().clone());
+            }
+            entry = syscall!(self.trussed.read_dir_next()).entry;
+        }
+        files
+    }
+
+    /// Helper method, hashing the records the integrity MAC covers, with their paths
+    fn integrity_digest(&mut self) -> [u8; 32] {
+        let directories: [(&str, fn(&littlefs2::path::Path) -> PathBuf); 4] = [
+            (paths::CREDENTIALS_DIR, paths::credential),
+            (paths::PARAMETERS_DIR, paths::parameters),
+            (paths::TRASH_DIR, paths::trashed),
+            (paths::CHALLENGE_RESPONSE_DIR, paths::challenge_response),
+        ];
+        let mut records = vec![paths::config(policy::POLICY_NAME)];
+        for (dir, path) in directories.iter() {
+            let filenames = self.files_in(Location::Internal, PathBuf::from(*dir));
+            records.extend(filenames.iter().map(|filename| path(filename)));
+        }
+
+        let mut hasher = Sha256::new();
+        for record in records {
+            let data = try_syscall!(self.trussed.read_file(Location::Internal, record.clone())).map(|reply| reply.data);
+            let record: &str = record.as_ref();
+            hasher.update(&(record.len() as u32).to_be_bytes());
+            hasher.update(record.as_bytes());
+            // a missing policy is the default one
+            match data {
+                Ok(data) => {
+                    hasher.update(&(data.len() as u32).to_be_bytes());
+                    hasher.update(&*data);
+                }
+                Err(_) => hasher.update(&[0xff; 4]),
+            }
+        }
+        hasher.finalize().into()
+    }
+
+    /// Helper method, listing the names of the files in which credentials are stored, with their location
+    fn credential_files(&mut self) -> Vec<(Location, PathBuf)> {
+        self.migrate_layout();
+        let mut files = Vec::new();
+        for location in LOCATIONS.iter() {
+            let filenames = self.files_in(*location, PathBuf::from(paths::CREDENTIALS_DIR));
+            files.extend(filenames.into_iter().map(|filename| (*location, filename)));
+        }
+        files
+    }
+
+    /// Helper method, moving credentials stored before the introduction of `paths`,
+    /// directly in the app's namespace, into the credentials directory
+    ///
+    /// Only files that are credentials, named after their label and slot, are moved; others
+    /// are left alone. Read-only, nothing is moved, as the changes would be discarded anyway.
+    fn migrate_layout(&mut self) {
+        if self.layout_migrated {
+            return;
+        }
+        self.layout_migrated = true;
+
+        for filename in self.files_in(Location::Internal, PathBuf::new()) {
+            let data = syscall!(self.trussed.read_file(Location::Internal, filename.clone())).data;
+            let credential = match Credential::from_bytes(data.as_ref()) {
+                Ok(credential) => credential,
+                Err(_) => continue,
+            };
+            let expected = match self.filename_for_label(credential.label(), credential.slot) {
+                Ok(expected) => expected,
+                Err(_) => continue,
+            };
+            let (expected, actual): (&str, &str) = (expected.as_ref(), filename.as_ref());
+            if expected != actual {
+                continue;
+            }
+            if self.read_only {
+                warn!("not migrating credential file {} in read-only mode", filename.as_ref());
+                continue;
+            }
+            info!("migrating credential file {}", filename.as_ref());
+            syscall!(self.trussed.write_file(Location::Internal, paths::credential(&filename), data, None));
+            syscall!(self.trussed.remove_file(Location::Internal, filename));
+        }
+    }
+
+    /// Helper method, loading a credential file and the records kept beside it,
+    /// or returning the path of the file that can't be deserialized
+    fn load_credential(&mut self, location: Location, filename: &PathBuf) -> core::result::Result<Credential, PathBuf> {
+        let path = paths::credential(filename);
+        let serialized_credential = try_syscall!(self.trussed.read_file(location, path.clone()))
+            .map_err(|_| path.clone())?.data;
+        let mut credential = Credential::from_bytes(serialized_credential.as_ref()).map_err(|_| path)?;
+        credential.usage = Usage::load(&mut self.trussed, location, filename);
+        credential.parameters = Parameters::load(&mut self.trussed, location, filename)
+            .map_err(|_| paths::parameters(filename))?;
+        credential.location = location;
+        Ok(credential)
     }
 
-    /// Helper method, using Trussed, to determine a filename for the Credential
-    fn filename_for_label(&mut self, label: &str) -> trussed::types::PathBuf {
-        let filename = syscall!(self.trussed.hash(Mechanism::Sha256, Message::from_slice(label.as_bytes()).unwrap())).hash;
+    /// Helper method, using Trussed, to determine the name of the file for the Credential
+    /// (within `paths::CREDENTIALS_DIR`), which also names its usage record
+    fn filename_for_label(&mut self, label: &str, slot: u8) -> Result<trussed::types::PathBuf> {
+        self.migrate_layout();
+        let mut message = Message::from_slice(label.as_bytes())
+            .map_err(|_| anyhow::anyhow!("The label is too long"))?;
+        // slot 0 keeps the filename derivation from before slots existed;
+        // the NUL separator can't be part of a label passed in over the CLI
+        if slot != 0 {
+            message.extend_from_slice(&[0, slot])
+                .map_err(|_| anyhow::anyhow!("The label is too long"))?;
+        }
+        let filename = syscall!(self.trussed.hash(Mechanism::Sha256, message)).hash;
         let mut hex_filename = [0u8; 16];
         use std::io::Write as _;
-        // first 8 bytes of SHA256 hash of label, as hexadecimal digits
+        // first 8 bytes of SHA256 hash of label (and slot), as hexadecimal digits
         hex_filename.as_mut().write_fmt(format_args!("{}", delog::hexstr!(&filename[..8]))).unwrap();
 
-        trussed::types::PathBuf::from(hex_filename.as_ref())
+        Ok(trussed::types::PathBuf::from(hex_filename.as_ref()))
     }
 }
 
@@ -0,0 +1,26 @@
+//! Typing OTPs into the focused window, as synthetic keystrokes (X11, macOS, Windows).
+//!
+//! This is what `pass-otp` users know from autotype setups: the OTP goes straight into the
+//! login form, without passing through the clipboard. Keystrokes go to whichever window has
+//! the focus, so the user confirms first, and then has a moment to switch windows.
+
+use std::io::BufRead as _;
+
+use enigo::KeyboardControllable as _;
+
+use crate::output::Style;
+use crate::Result;
+
+/// Asks on stderr whether to type, reading the answer from stdin
+pub fn confirm(style: &Style) -> Result<bool> {
+    crate::output::prompt(&style.question("Type the OTP into the focused window?", false))?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Waits for the delay, then types the text into the focused window
+pub fn type_text(text: &str, delay: core::time::Duration) {
+    std::thread::sleep(delay);
+    enigo::Enigo::new().key_sequence(text);
+}
@@ -0,0 +1,45 @@
+//! Batch files, running many commands against a single mounted state.
+//!
+//! A batch file either holds one command per line, in the CLI's syntax without the binary's
+//! name (e.g. `register alice@trussed.dev JBSWY3DPEHPK3PXP`), or a JSON array of commands as
+//! serialized in transcripts. Empty lines and lines starting with `#` are skipped.
+
+use core::convert::TryFrom as _;
+
+use crate::authenticator::Command;
+use crate::cli;
+use crate::Result;
+
+/// Reads all commands of a batch file, failing on the first one that can't be parsed
+pub fn read(path: impl AsRef<std::path::Path>) -> Result<Vec<Command>> {
+    let contents = std::fs::read_to_string(path)?;
+    if contents.trim_start().starts_with('[') {
+        return Ok(serde_json::from_str(&contents)?);
+    }
+
+    let mut commands = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let command = parse(line)
+            .map_err(|e| anyhow::anyhow!("line {}: {}", i + 1, e))?;
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+/// Parses a line in the CLI's syntax
+fn parse(line: &str) -> Result<Command> {
+    let args = core::iter::once(clap::crate_name!()).chain(line.split_whitespace());
+    let matches = cli::clap_app().get_matches_from_safe(args)
+        .map_err(|e| anyhow::anyhow!("{}", e.message))?;
+    match matches.subcommand_name() {
+        // these are handled by the runner, not the app, or by another app
+        Some(name) if cli::RUNNER_SUBCOMMANDS.contains(&name) || name == "secret" => {
+            Err(anyhow::anyhow!("`{}` can't be run from a batch", name))
+        }
+        _ => Command::try_from(&matches),
+    }
+}
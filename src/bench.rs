@@ -0,0 +1,190 @@
+//! Rough throughput measurements, giving a baseline for storage and crypto performance.
+//!
+//! The storage numbers are taken on a scratch file, and all keys are injected into
+//! volatile storage, so running the benchmark leaves the actual state untouched.
+
+use std::time::{Duration, Instant};
+
+use littlefs2::driver::Storage as _;
+use trussed::{syscall, types::{Location, Message, PathBuf}};
+
+use crate::platform::store::{self, FileFlash};
+use crate::Result;
+
+const BENCH_FILENAME: &str = "bench";
+const BENCH_DIR: &str = "bench-credentials";
+/// Number of credentials `authenticate --all` is measured with
+const CREDENTIALS: u32 = 20;
+
+#[derive(Clone, Debug, PartialEq)]
+/// The outcome of running one operation repeatedly
+pub struct Measurement {
+    /// Short description of the operation
+    pub name: String,
+    /// How often the operation was run
+    pub iterations: u32,
+    /// Total time taken for all iterations
+    pub total: Duration,
+}
+
+impl Measurement {
+    fn run(name: impl Into<String>, iterations: u32, mut operation: impl FnMut(u32)) -> Self {
+        let start = Instant::now();
+        for i in 0..iterations {
+            operation(i);
+        }
+        Self { name: name.into(), iterations, total: start.elapsed() }
+    }
+
+    /// Average time taken per operation
+    pub fn latency(&self) -> Duration {
+        self.total / self.iterations.max(1)
+    }
+
+    /// Average number of operations per second
+    pub fn per_second(&self) -> f64 {
+        self.iterations as f64 / self.total.as_secs_f64()
+    }
+
+    /// How many times faster an operation is than the baseline
+    pub fn speedup_over(&self, baseline: &Measurement) -> f64 {
+        baseline.latency().as_secs_f64() / self.latency().as_secs_f64()
+    }
+}
+
+impl core::fmt::Display for Measurement {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:<40} {:>10.1} ops/s {:>12.3?}/op", self.name, self.per_second(), self.latency())
+    }
+}
+
+/// Measures raw block access of the file-backed flash, on a scratch file.
+///
+/// "cold" reads visit each block once, "warm" reads hit the same block repeatedly.
+/// Comparing a `cache_blocks` of zero (writing through) with a larger cache shows
+/// the gain of the block cache.
+pub fn flash(scratch_path: &std::path::Path, iterations: u32, cache_blocks: usize) -> Result<Vec<Measurement>> {
+    let mut flash = FileFlash::new(scratch_path, cache_blocks, None, false)?;
+    let sync_handle = flash.sync_handle();
+    let prefix = format!("flash (cache: {} blocks)", cache_blocks);
+    let block_size = FileFlash::BLOCK_SIZE;
+    let block_count = FileFlash::BLOCK_COUNT as u32;
+    let mut block = [0u8; FileFlash::BLOCK_SIZE];
+
+    let measurements = vec![
+        Measurement::run(format!("{}: erase block", prefix), iterations, |i| {
+            flash.erase(((i % block_count) as usize) * block_size, block_size).unwrap();
+        }),
+        Measurement::run(format!("{}: write block", prefix), iterations, |i| {
+            flash.write(((i % block_count) as usize) * block_size, &block).unwrap();
+        }),
+        Measurement::run(format!("{}: sync", prefix), 1, |_| {
+            sync_handle.sync().unwrap();
+        }),
+        Measurement::run(format!("{}: read block (cold)", prefix), iterations, |i| {
+            flash.read(((i % block_count) as usize) * block_size, &mut block).unwrap();
+        }),
+        Measurement::run(format!("{}: read block (warm)", prefix), iterations, |_| {
+            flash.read(0, &mut block).unwrap();
+        }),
+    ];
+
+    std::fs::remove_file(scratch_path)?;
+    std::fs::remove_file(store::wear_path(scratch_path)).ok();
+    Ok(measurements)
+}
+
+/// Measures the Trussed operations the authenticator relies on.
+pub fn trussed<T>(trussed: &mut T, iterations: u32) -> Result<Vec<Measurement>>
+where
+    T: trussed::Client + trussed::client::mechanisms::Totp,
+{
+    let raw_key = [0x42u8; 20];
+    let data = Message::from_slice(&[0x42u8; 256]).unwrap();
+    let mut measurements = Vec::new();
+
+    measurements.push(Measurement::run("trussed: write file (internal)", iterations, |_| {
+        syscall!(trussed.write_file(Location::Internal, PathBuf::from(BENCH_FILENAME), data.clone(), None));
+    }));
+    measurements.push(Measurement::run("trussed: read file (internal)", iterations, |_| {
+        syscall!(trussed.read_file(Location::Internal, PathBuf::from(BENCH_FILENAME)));
+    }));
+    syscall!(trussed.remove_file(Location::Internal, PathBuf::from(BENCH_FILENAME)));
+
+    let mut keys = Vec::new();
+    measurements.push(Measurement::run("trussed: inject key (volatile)", iterations, |_| {
+        keys.push(syscall!(trussed.unsafe_inject_shared_key(&raw_key, Location::Volatile)).key);
+    }));
+
+    let key = keys[0];
+    measurements.push(Measurement::run("trussed: generate OTP (cold)", 1, |i| {
+        syscall!(trussed.sign_totp(key, i as u64));
+    }));
+    measurements.push(Measurement::run("trussed: generate OTP (warm)", iterations, |i| {
+        syscall!(trussed.sign_totp(key, i as u64));
+    }));
+
+    for key in keys {
+        syscall!(trussed.delete(key));
+    }
+
+    Ok(measurements)
+}
+
+/// Measures what `authenticate --all` gains over authenticating with each credential on
+/// its own, with the same access pattern on volatile scratch files.
+///
+/// The Trussed service processes one request at a time, so requests for several OTPs can't
+/// overlap; the gain is in reading the credentials once, instead of once per credential
+/// while looking it up by its label.
+pub fn authenticate_all<T>(trussed: &mut T, iterations: u32) -> Result<Vec<Measurement>>
+where
+    T: trussed::Client + trussed::client::mechanisms::Totp,
+{
+    let raw_key = [0x42u8; 20];
+    let data = Message::from_slice(&[0x42u8; 256]).unwrap();
+    for i in 0..CREDENTIALS {
+        syscall!(trussed.write_file(Location::Volatile, bench_path(&i.to_string()), data.clone(), None));
+    }
+    let key = syscall!(trussed.unsafe_inject_shared_key(&raw_key, Location::Volatile)).key;
+
+    let one_by_one = Measurement::run(
+        format!("trussed: {} OTPs, one lookup each", CREDENTIALS), iterations, |_| {
+            for i in 0..CREDENTIALS {
+                read_all(trussed);
+                syscall!(trussed.sign_totp(key, i as u64));
+            }
+        });
+    let all_at_once = Measurement::run(
+        format!("trussed: {} OTPs, one lookup for all", CREDENTIALS), iterations, |_| {
+            read_all(trussed);
+            for i in 0..CREDENTIALS {
+                syscall!(trussed.sign_totp(key, i as u64));
+            }
+        });
+
+    syscall!(trussed.delete(key));
+    for i in 0..CREDENTIALS {
+        syscall!(trussed.remove_file(Location::Volatile, bench_path(&i.to_string())));
+    }
+    Ok(vec![one_by_one, all_at_once])
+}
+
+fn bench_path(filename: &str) -> PathBuf {
+    let mut path = PathBuf::from(BENCH_DIR);
+    path.push(&PathBuf::from(filename));
+    path
+}
+
+/// Reads all files of the scratch directory, like listing the credentials does
+fn read_all<T: trussed::Client>(trussed: &mut T) {
+    let mut filenames = Vec::new();
+    let mut entry = syscall!(trussed.read_dir_first(Location::Volatile, PathBuf::from(BENCH_DIR), None)).entry;
+    while let Some(dir_entry) = entry {
+        filenames.push(dir_entry.file_name().clone());
+        entry = syscall!(trussed.read_dir_next()).entry;
+    }
+    for filename in filenames {
+        syscall!(trussed.read_file(Location::Volatile, bench_path(filename.as_ref())));
+    }
+}
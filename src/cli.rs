@@ -9,17 +9,29 @@ use clap::{
     SubCommand,
 };
 
-use crate::authenticator::{Authenticate, Command, Register};
+use crate::authenticator::{
+    AddRecoveryCodes, Authenticate, AuthenticateAll, Command, DeleteChallengeResponse, Otp, Precompute, Prune, Register,
+    RegisterChallengeResponse, Repair, ResetIssuer, RespondToChallenge, SetIssuer, SetPolicy, ShowRecoveryCodes, Stats, Trash,
+    Undelete, UseRecoveryCode, Verify,
+    DEFAULT_PERIOD, MAX_PRECOMPUTED, PERIODS,
+};
+use crate::secret::SecretBytes;
 
 /// entry point to the CLI
-pub fn init_cli() -> (clap::ArgMatches<'static>, String) {
+pub fn init_cli() -> (clap::ArgMatches<'static>, String, usize) {
     let clap_app = clap_app();
     let matches = clap_app.get_matches();
     // no panic - clap enforces the value's existence
     let state_file: String = matches.value_of("STATE-FILE").unwrap().into();
-    (matches, state_file.into())
+    // no panic - clap enforces the value's existence and validity
+    let cache_blocks = matches.value_of("CACHE-BLOCKS").unwrap().parse().unwrap();
+    (matches, state_file.into(), cache_blocks)
 }
 
+/// Subcommands handled by the runner, rather than dispatched as a `Command` into the app
+pub const RUNNER_SUBCOMMANDS: [&str; 10] =
+    ["batch", "bench", "debug", "healthcheck", "pick", "provision", "replay", "setup", "state", "storage"];
+
 const ABOUT: &str = "
 An example app, using Trussed®, running on PC, implementing TOTP.
 
@@ -45,6 +57,193 @@ pub fn clap_app() -> clap::App<'static, 'static> {
              .global(true)
         )
 
+        .arg(Arg::with_name("CACHE-BLOCKS")
+             .long("cache-blocks")
+             .default_value("128")
+             .help("number of state file blocks cached in memory, 0 writes through")
+             .required(false)
+             .global(true)
+             .validator(|s| s.parse::<usize>().map(drop).map_err(|e| e.to_string()))
+        )
+
+        .arg(Arg::with_name("ENCRYPT-STATE")
+             .long("encrypt-state")
+             .help("encrypt the state file with a passphrase, taken from TOTP_STATE_PASSPHRASE or prompted for")
+             .required(false)
+             .global(true)
+        )
+
+        .arg(Arg::with_name("CACHE-PASSPHRASE")
+             .long("cache-passphrase")
+             .help("cache the state file passphrase in the OS keychain for a period, e.g. 15m")
+             .value_name("PERIOD")
+             .required(false)
+             .global(true)
+             .requires("ENCRYPT-STATE")
+             .hidden(cfg!(not(feature = "keychain")))
+             .validator(|s| parse_duration(&s).map(drop).map_err(|e| e.to_string()))
+        )
+
+        .arg(Arg::with_name("READ-ONLY")
+             .long("read-only")
+             .help("refuse commands that change the state, and never write to the state file")
+             .required(false)
+             .global(true)
+        )
+
+        .arg(Arg::with_name("REFORMAT")
+             .long("reformat")
+             .help("replace a state file that can't be mounted with an empty one, after backing it up")
+             .required(false)
+             .global(true)
+             .conflicts_with("READ-ONLY")
+        )
+
+        .arg(Arg::with_name("ALLOW-ROLLBACK")
+             .long("allow-rollback")
+             .help("accept HOTP counters behind the ones last seen, e.g. after restoring a backup")
+             .required(false)
+             .global(true)
+        )
+
+        .arg(Arg::with_name("ACCEPT-MODIFIED-STATE")
+             .long("accept-modified-state")
+             .help("accept a policy or credentials changed outside the app, e.g. by editing the state file (requires presence)")
+             .required(false)
+             .global(true)
+        )
+
+        .arg(Arg::with_name("QUOTA")
+             .long("quota")
+             .help("limit what a client may store in the state file, e.g. totp=64K (repeatable)")
+             .value_name("CLIENT=SIZE")
+             .required(false)
+             .multiple(true)
+             .number_of_values(1)
+             .global(true)
+             .validator(|s| parse_quota(&s).map(drop).map_err(|e| e.to_string()))
+        )
+
+        .arg(Arg::with_name("CONFIG")
+             .long("config")
+             .help("configuration file (TOML), declaring e.g. [hooks]")
+             .value_name("FILE")
+             .env("TOTP_CONFIG")
+             .required(false)
+             .global(true)
+        )
+
+        .arg(Arg::with_name("HOOK")
+             .long("hook")
+             .help("run a shell command on an event (failure, failure-threshold, deleted, policy-changed), e.g. failure='notify-send \"$TOTP_ERROR\"' (repeatable, after the configuration file's)")
+             .value_name("EVENT=COMMAND")
+             .required(false)
+             .multiple(true)
+             .number_of_values(1)
+             .global(true)
+             .validator(|s| parse_hook(&s).map(drop).map_err(|e| e.to_string()))
+        )
+
+        .arg(Arg::with_name("FAILURE-THRESHOLD")
+             .long("failure-threshold")
+             .help("number of failures with a credential at which the failure-threshold event is raised, 0 for never [default: 5]")
+             .value_name("COUNT")
+             .required(false)
+             .global(true)
+             .validator(|s| s.parse::<u32>().map(drop).map_err(|_| "must be a number".into()))
+        )
+
+        .arg(Arg::with_name("RNG")
+             .long("rng")
+             .help("source of randomness: chacha (seeded by the OS), os, or for reproducible demos seed:FILE")
+             .value_name("SOURCE")
+             .default_value("chacha")
+             .global(true)
+             .validator(|s| s.parse::<crate::platform::rng::RngSource>().map(drop).map_err(|e| e.to_string()))
+        )
+
+        .arg(Arg::with_name("UI")
+             .long("ui")
+             .default_value("stdin")
+             .help("how user presence is checked: stdin, or for testing test:deny, test:delay[:MILLISECONDS], test:flaky")
+             .required(false)
+             .global(true)
+             .validator(|s| s.parse::<crate::platform::Presence>().map(drop).map_err(|e| e.to_string()))
+        )
+
+        .arg(Arg::with_name("PRESENCE-TIMEOUT")
+             .long("presence-timeout")
+             .help("how long to wait for confirmation of user presence, e.g. 30s or 2m")
+             .value_name("DURATION")
+             .default_value("5s")
+             .global(true)
+             .validator(|s| parse_duration(&s).map(drop).map_err(|e| e.to_string()))
+        )
+
+        .arg(Arg::with_name("RECORD")
+             .long("record")
+             .help("append the command and its response to a transcript file, with secrets redacted")
+             .value_name("TRANSCRIPT")
+             .required(false)
+             .global(true)
+        )
+
+        .arg(Arg::with_name("LOG-LEVEL")
+             .long("log-level")
+             .help("log levels, overall and per module, e.g. info,tutorial::platform=debug [default: $RUST_LOG]")
+             .value_name("FILTERS")
+             .required(false)
+             .global(true)
+        )
+
+        .arg(Arg::with_name("LOG-FILE")
+             .long("log-file")
+             .help("append logs to a file instead of writing them to stderr")
+             .value_name("FILE")
+             .required(false)
+             .global(true)
+        )
+
+        .arg(Arg::with_name("COLOR")
+             .long("color")
+             .help("whether to color the output; auto respects $NO_COLOR")
+             .value_name("WHEN")
+             .possible_values(&["auto", "always", "never"])
+             .default_value("auto")
+             .global(true)
+        )
+
+        .arg(Arg::with_name("LANG")
+             .long("lang")
+             .help("language of the messages, defaults to the locale's on a terminal and to English otherwise")
+             .value_name("LANG")
+             .possible_values(&["en", "de"])
+             .required(false)
+             .global(true)
+        )
+
+        .arg(Arg::with_name("ACCESSIBLE")
+             .long("accessible")
+             .help("format for screen readers: no colors, OTPs in groups of digits, plain prompts")
+             .required(false)
+             .global(true)
+        )
+
+        .arg(Arg::with_name("SPELL-CODES")
+             .long("spell-codes")
+             .help("with --accessible, print OTPs one character per line")
+             .required(false)
+             .global(true)
+             .requires("ACCESSIBLE")
+        )
+
+        .arg(Arg::with_name("TRACE-TRUSSED")
+             .long("trace-trussed")
+             .help("log each Trussed syscall to stderr, and summarize them on exit")
+             .required(false)
+             .global(true)
+        )
+
         // cf. https://github.com/google/google-authenticator/wiki/Key-Uri-Format
         // eg. otpauth://totp/Example:alice@google.com?secret=JBSWY3DPEHPK3PXP&issuer=Example
 
@@ -60,6 +259,61 @@ pub fn clap_app() -> clap::App<'static, 'static> {
                  .value_name("SECRET")
                  .required(true)
              )
+            .arg(Arg::with_name("SLOT")
+                 .long("slot")
+                 .help("slot to register the secret in, to keep several secrets under one label [default: 0]")
+                 .value_name("SLOT")
+                 .required(false)
+                 .validator(|s| s.parse::<u8>().map(drop).map_err(|_| "must be a number from 0 to 255".into()))
+             )
+            .arg(Arg::with_name("LOCATION")
+                 .long("location")
+                 .help("where to store the secret; volatile secrets are gone after the run (or batch)")
+                 .value_name("LOCATION")
+                 .possible_values(&["internal", "volatile"])
+                 .default_value("internal")
+             )
+            .arg(Arg::with_name("BACKUP-WORDS")
+                 .long("backup-words")
+                 .help("print the secret once, in groups with check characters to write down; it is not stored")
+                 .required(false)
+             )
+            .arg(Arg::with_name("ALGORITHM")
+                 .long("algorithm")
+                 .help("how codes are derived from the secret: totp, hotp (counter-based) or steam")
+                 .value_name("ALGORITHM")
+                 .default_value(crate::algorithm::DEFAULT_ALGORITHM)
+                 .validator(|s| crate::algorithm::find(&s).map(drop).map_err(|e| e.to_string()))
+             )
+            .arg(Arg::with_name("PERIOD")
+                 .long("period")
+                 .help("seconds each code of a time-based algorithm is valid for [default: 30]")
+                 .value_name("SECONDS")
+                 .required(false)
+                 .validator(|s| match s.parse::<u64>() {
+                     Ok(period) if PERIODS.contains(&period) => Ok(()),
+                     _ => Err(format!("must be a number from {} to {}", PERIODS.start(), PERIODS.end())),
+                 })
+             )
+            .arg(Arg::with_name("COUNTER")
+                 .long("counter")
+                 .help("counter of the first code, for counter-based algorithms [default: 0]")
+                 .value_name("COUNTER")
+                 .required(false)
+                 .validator(|s| s.parse::<u64>().map(drop).map_err(|_| "must be a non-negative integer".into()))
+             )
+            .arg(Arg::with_name("ISSUER")
+                 .long("issuer")
+                 .help("id of the issuer in the catalog (cf. `issuer show`), e.g. github, shown when listing")
+                 .value_name("ISSUER")
+                 .required(false)
+                 .validator(|s| crate::issuers::check_id(&s).map_err(|e| e.to_string()))
+             )
+            .arg(Arg::with_name("FROM-WORDS")
+                 .long("from-words")
+                 .help("the secret is a backup printed by --backup-words, e.g. \"JBSWW Y3DPO EHPKH 3PXPM\"")
+                 .required(false)
+             )
         )
 
         .subcommand(SubCommand::with_name("authenticate")
@@ -71,11 +325,470 @@ pub fn clap_app() -> clap::App<'static, 'static> {
                  .value_name("TIMESTAMP")
                  .required(false)
              )
+            .arg(Arg::with_name("label")
+                 .help("Label of the TOTP secret to use, e.g. alice@trussed.dev")
+                 .value_name("LABEL")
+                 .required_unless("ALL")
+             )
+            .arg(Arg::with_name("ALL")
+                 .long("all")
+                 .help("generate the OTPs of all time-based credentials, confirming user presence once")
+                 .required(false)
+                 .conflicts_with_all(&["label", "SLOT", "PRECOMPUTE", "TYPE"])
+             )
+            .arg(Arg::with_name("SLOT")
+                 .long("slot")
+                 .help("slot of the TOTP secret to use [default: highest registered slot]")
+                 .value_name("SLOT")
+                 .required(false)
+                 .validator(|s| s.parse::<u8>().map(drop).map_err(|_| "must be a number from 0 to 255".into()))
+             )
+            .arg(Arg::with_name("JSON")
+                 .long("json")
+                 .help("print the OTP as JSON, with its period, expiry (valid_until) and remaining_seconds")
+                 .required(false)
+             )
+            .arg(Arg::with_name("QUIET")
+                 .short("q")
+                 .long("quiet")
+                 .help("print just the OTP, without a newline or any logging, e.g. for $(...) in shell scripts")
+                 .required(false)
+                 .conflicts_with_all(&["ALL", "JSON", "PRECOMPUTE", "TYPE"])
+             )
+            .arg(Arg::with_name("PRECOMPUTE")
+                 .long("precompute")
+                 .help("print the OTPs of this many periods, for use while offline (policy `precompute`)")
+                 .value_name("COUNT")
+                 .required(false)
+                 .validator(|s| match s.parse::<u32>() {
+                     Ok(count) if (1..=MAX_PRECOMPUTED).contains(&count) => Ok(()),
+                     _ => Err(format!("must be a number from 1 to {}", MAX_PRECOMPUTED)),
+                 })
+             )
+            .arg(Arg::with_name("TYPE")
+                 .long("type")
+                 .help("type the OTP into the focused window instead of printing it, after confirmation")
+                 .required(false)
+                 .conflicts_with("JSON")
+                 .conflicts_with("PRECOMPUTE")
+                 .hidden(cfg!(not(feature = "autotype")))
+             )
+            .arg(Arg::with_name("TYPE-DELAY")
+                 .long("type-delay")
+                 .help("time to switch to the window the OTP is typed into, e.g. 3s")
+                 .value_name("DURATION")
+                 .default_value("2s")
+                 .requires("TYPE")
+                 .hidden(cfg!(not(feature = "autotype")))
+                 .validator(|s| parse_duration(&s).map(drop).map_err(|e| e.to_string()))
+             )
+            .arg(Arg::with_name("YES")
+                 .long("yes")
+                 .help("type without asking for confirmation")
+                 .requires("TYPE")
+                 .hidden(cfg!(not(feature = "autotype")))
+             )
+        )
+
+        .subcommand(SubCommand::with_name("verify")
+            .about("check a TOTP against a previously registered secret")
+            .arg(Arg::with_name("TIMESTAMP")
+                 .short("t")
+                 .long("timestamp")
+                 .help("timestamp the OTP was generated at, as seconds since the UNIX epoch")
+                 .value_name("TIMESTAMP")
+                 .required(false)
+             )
             .arg(Arg::with_name("label")
                  .help("Label of the TOTP secret to use, e.g. alice@trussed.dev")
                  .value_name("LABEL")
                  .required(true)
              )
+            .arg(Arg::with_name("SLOT")
+                 .long("slot")
+                 .help("slot of the TOTP secret to use [default: highest registered slot]")
+                 .value_name("SLOT")
+                 .required(false)
+                 .validator(|s| s.parse::<u8>().map(drop).map_err(|_| "must be a number from 0 to 255".into()))
+             )
+            .arg(Arg::with_name("otp")
+                 .help("the OTP to check, e.g. 123456")
+                 .value_name("OTP")
+                 .required(true)
+                 .validator(|s| match !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric()) {
+                     true => Ok(()),
+                     false => Err("must consist of letters and digits only".into()),
+                 })
+             )
+        )
+
+        .subcommand(SubCommand::with_name("list")
+            .about("list the labels of all registered TOTP secrets")
+        )
+
+        .subcommand(SubCommand::with_name("labels")
+            .about("print only the labels of all registered TOTP secrets, e.g. for shell completion")
+            .setting(clap::AppSettings::Hidden)
+        )
+
+        .subcommand(SubCommand::with_name("pick")
+            .about("pick a label with an external picker (dmenu, rofi, fzf, ...), and generate its TOTP")
+            .arg(Arg::with_name("picker")
+                 .long("picker")
+                 .help("shell command reading labels from stdin and writing the picked one to stdout")
+                 .value_name("COMMAND")
+                 .env("TOTP_PICKER")
+                 .default_value("fzf")
+             )
+        )
+
+        .subcommand(SubCommand::with_name("stats")
+            .about("summarize how often each TOTP secret was used, most used first")
+        )
+
+        .subcommand(SubCommand::with_name("prune")
+            .about("find TOTP secrets that have not been used for a long time")
+            .arg(Arg::with_name("unused-for")
+                 .long("unused-for")
+                 .help("how long a secret needs to be unused to be stale, e.g. 365d, 12h, 30m or 90s")
+                 .value_name("DURATION")
+                 .default_value("365d")
+                 .validator(|s| parse_duration(&s).map(drop).map_err(|e| e.to_string()))
+             )
+            .arg(Arg::with_name("delete")
+                 .long("delete")
+                 .help("delete the stale secrets (restorable for 30 days) instead of only listing them")
+                 .required(false)
+             )
+        )
+
+        .subcommand(SubCommand::with_name("trash")
+            .about("list deleted TOTP secrets, which can be restored for 30 days")
+        )
+
+        .subcommand(SubCommand::with_name("undelete")
+            .about("restore a deleted TOTP secret")
+            .arg(Arg::with_name("label")
+                 .help("Label of the TOTP secret to restore, e.g. alice@trussed.dev")
+                 .value_name("LABEL")
+                 .required(true)
+             )
+            .arg(Arg::with_name("SLOT")
+                 .long("slot")
+                 .help("slot of the TOTP secret to restore [default: most recently deleted]")
+                 .value_name("SLOT")
+                 .required(false)
+                 .validator(|s| s.parse::<u8>().map(drop).map_err(|_| "must be a number from 0 to 255".into()))
+             )
+        )
+
+        .subcommand(SubCommand::with_name("repair")
+            .about("move credentials that can no longer be read into quarantine")
+            .arg(Arg::with_name("delete")
+                 .long("delete")
+                 .help("delete corrupted credentials instead of quarantining them, and those quarantined before")
+                 .required(false)
+             )
+        )
+
+        .subcommand(SubCommand::with_name("recovery")
+            .about("keep the recovery codes a service issued along with a TOTP secret, sealed by Trussed")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("add")
+                .about("keep recovery codes, in addition to those already kept")
+                .arg(Arg::with_name("label")
+                     .help("Label of the TOTP secret, e.g. alice@trussed.dev")
+                     .value_name("LABEL")
+                     .required(true)
+                 )
+                .arg(Arg::with_name("SLOT")
+                     .long("slot")
+                     .help("slot of the TOTP secret [default: highest registered slot]")
+                     .value_name("SLOT")
+                     .required(false)
+                     .validator(|s| s.parse::<u8>().map(drop).map_err(|_| "must be a number from 0 to 255".into()))
+                 )
+                .arg(Arg::with_name("codes")
+                     .help("the recovery codes, e.g. 1234-5678")
+                     .value_name("CODE")
+                     .multiple(true)
+                     .required(true)
+                 )
+            )
+            .subcommand(SubCommand::with_name("show")
+                .about("show the recovery codes, and which of them are used")
+                .arg(Arg::with_name("label")
+                     .help("Label of the TOTP secret, e.g. alice@trussed.dev")
+                     .value_name("LABEL")
+                     .required(true)
+                 )
+                .arg(Arg::with_name("SLOT")
+                     .long("slot")
+                     .help("slot of the TOTP secret [default: highest registered slot]")
+                     .value_name("SLOT")
+                     .required(false)
+                     .validator(|s| s.parse::<u8>().map(drop).map_err(|_| "must be a number from 0 to 255".into()))
+                 )
+            )
+            .subcommand(SubCommand::with_name("use")
+                .about("mark a recovery code as used, and print it")
+                .arg(Arg::with_name("label")
+                     .help("Label of the TOTP secret, e.g. alice@trussed.dev")
+                     .value_name("LABEL")
+                     .required(true)
+                 )
+                .arg(Arg::with_name("SLOT")
+                     .long("slot")
+                     .help("slot of the TOTP secret [default: highest registered slot]")
+                     .value_name("SLOT")
+                     .required(false)
+                     .validator(|s| s.parse::<u8>().map(drop).map_err(|_| "must be a number from 0 to 255".into()))
+                 )
+                .arg(Arg::with_name("code")
+                     .help("the recovery code used [default: the first unused one]")
+                     .value_name("CODE")
+                     .required(false)
+                 )
+            )
+        )
+
+        .subcommand(SubCommand::with_name("chalresp")
+            .about("emulate a YubiKey's HMAC-SHA1 challenge-response, e.g. to unlock KeePassXC databases")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("register")
+                .about("register a key, replacing any with the same label")
+                .arg(Arg::with_name("label")
+                     .help("label of the key, e.g. passwords.kdbx")
+                     .value_name("LABEL")
+                     .required(true)
+                 )
+                .arg(Arg::with_name("SECRET")
+                     .long("secret")
+                     .help("the key, hex-encoded as ykman shows it [default: generated, never leaving Trussed]")
+                     .value_name("HEX")
+                     .required(false)
+                 )
+            )
+            .subcommand(SubCommand::with_name("respond")
+                .about("print the HMAC-SHA1 of a challenge, hex-encoded")
+                .arg(Arg::with_name("label")
+                     .help("label of the key, e.g. passwords.kdbx")
+                     .value_name("LABEL")
+                     .required(true)
+                 )
+                .arg(Arg::with_name("challenge")
+                     .help("the challenge, hex-encoded, up to 64 bytes")
+                     .value_name("HEX-CHALLENGE")
+                     .required(true)
+                     .validator(|s| data_encoding::HEXLOWER_PERMISSIVE.decode(s.as_bytes()).map(drop).map_err(|e| e.to_string()))
+                 )
+            )
+            .subcommand(SubCommand::with_name("delete")
+                .about("delete a key")
+                .arg(Arg::with_name("label")
+                     .help("label of the key, e.g. passwords.kdbx")
+                     .value_name("LABEL")
+                     .required(true)
+                 )
+            )
+        )
+
+        .subcommand(SubCommand::with_name("secret")
+            .about("keep static secrets, e.g. API tokens, sealed by Trussed in an app of their own")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("add")
+                .about("store a secret under a name")
+                .arg(Arg::with_name("name")
+                     .help("name of the secret, e.g. github-token")
+                     .value_name("NAME")
+                     .required(true)
+                 )
+                .arg(Arg::with_name("value")
+                     .help("the secret; prompted for if missing, so it stays out of the shell history")
+                     .value_name("VALUE")
+                     .required(false)
+                 )
+                .arg(Arg::with_name("REQUIRE")
+                     .long("require")
+                     .help("what needs to happen before the secret is revealed or deleted")
+                     .value_name("REQUIREMENT")
+                     .possible_values(&["nothing", "presence"])
+                     .default_value("presence")
+                 )
+            )
+            .subcommand(SubCommand::with_name("get")
+                .about("reveal a secret")
+                .arg(Arg::with_name("name")
+                     .help("name of the secret, e.g. github-token")
+                     .value_name("NAME")
+                     .required(true)
+                 )
+            )
+            .subcommand(SubCommand::with_name("list")
+                .about("list the names of the secrets, and what revealing them requires")
+            )
+            .subcommand(SubCommand::with_name("delete")
+                .about("delete a secret")
+                .arg(Arg::with_name("name")
+                     .help("name of the secret, e.g. github-token")
+                     .value_name("NAME")
+                     .required(true)
+                 )
+            )
+        )
+
+        .subcommand(SubCommand::with_name("policy")
+            .about("show or change which commands require user presence")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("show")
+                .about("show the requirement of each command")
+            )
+            .subcommand(SubCommand::with_name("set")
+                .about("change the requirement of a command (requires user presence)")
+                .arg(Arg::with_name("command")
+                     .help("the command to change, e.g. authenticate")
+                     .value_name("COMMAND")
+                     .possible_values(&crate::policy::Action::ALL.iter().map(|action| action.name()).collect::<Vec<_>>())
+                     .required(true)
+                 )
+                .arg(Arg::with_name("requirement")
+                     .help("what the command requires")
+                     .value_name("REQUIREMENT")
+                     .possible_values(&["nothing", "presence"])
+                     .required(true)
+                 )
+            )
+        )
+
+        .subcommand(SubCommand::with_name("issuer")
+            .about("show or change the catalog of issuers, their display names and icons")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("show")
+                .about("show the issuers, built in and overridden")
+            )
+            .subcommand(SubCommand::with_name("set")
+                .about("override an issuer's display name and icon, or add an issuer")
+                .arg(Arg::with_name("id")
+                     .help("the issuer's id, e.g. github")
+                     .value_name("ISSUER")
+                     .required(true)
+                     .validator(|s| crate::issuers::check_id(&s).map_err(|e| e.to_string()))
+                 )
+                .arg(Arg::with_name("name")
+                     .help("the name to show, e.g. \"GitHub Enterprise\"")
+                     .value_name("NAME")
+                     .required(true)
+                 )
+                .arg(Arg::with_name("icon")
+                     .help("the slug of the icon in Simple Icons, e.g. github")
+                     .value_name("ICON")
+                     .required(true)
+                 )
+            )
+            .subcommand(SubCommand::with_name("reset")
+                .about("remove the override of an issuer")
+                .arg(Arg::with_name("id")
+                     .help("the issuer's id, e.g. github")
+                     .value_name("ISSUER")
+                     .required(true)
+                 )
+            )
+        )
+
+        .subcommand(SubCommand::with_name("setup")
+            .about("walk through setting up a new state file, asking which commands require user presence")
+        )
+
+        .subcommand(SubCommand::with_name("healthcheck")
+            .about("check the clock, RNG, state file and Trussed service, as JSON, exiting non-zero if unhealthy")
+        )
+
+        .subcommand(SubCommand::with_name("storage")
+            .about("inspect the state file")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("health")
+                .about("show free space, file counts and erase cycles of the state file")
+            )
+            .subcommand(SubCommand::with_name("gc")
+                .about("remove secrets no credential refers to anymore, e.g. left behind by re-registering")
+            )
+        )
+
+        .subcommand(SubCommand::with_name("state")
+            .about("export or import the state file as a whole, e.g. for backups")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("export")
+                .about("write the state file's image, with a SHA-256 to verify it on import")
+                .arg(Arg::with_name("file")
+                     .help("file to export to")
+                     .value_name("FILE")
+                     .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("import")
+                .about("replace the state file with a verified export")
+                .arg(Arg::with_name("file")
+                     .help("file to import from")
+                     .value_name("FILE")
+                     .required(true)
+                )
+                .arg(Arg::with_name("FORCE")
+                     .long("force")
+                     .help("replace an existing state file")
+                     .required(false)
+                )
+            )
+        )
+
+        .subcommand(SubCommand::with_name("debug")
+            .about("help debugging issues")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("dump-state")
+                .about("print the files in the state file as JSON, decoding known records, without secrets")
+            )
+        )
+
+        .subcommand(SubCommand::with_name("replay")
+            .about("re-run the commands of a transcript against a fresh state, comparing responses")
+            .arg(Arg::with_name("transcript")
+                 .help("transcript file, as written with --record")
+                 .value_name("TRANSCRIPT")
+                 .required(true)
+             )
+        )
+
+        .subcommand(SubCommand::with_name("batch")
+            .about("run the commands of a batch file against the state, reporting each result")
+            .arg(Arg::with_name("batch")
+                 .help("file with one command per line (e.g. `register LABEL SECRET`), or a JSON array of commands")
+                 .value_name("FILE")
+                 .required(true)
+             )
+        )
+
+        .subcommand(SubCommand::with_name("provision")
+            .about("register the credentials and set the policy a file declares, skipping what is already there")
+            .arg(Arg::with_name("FILE")
+                 .long("file")
+                 .help("provisioning file (TOML) with [policy] and [[credential]] entries")
+                 .value_name("FILE")
+                 .required(true)
+             )
+        )
+
+        .subcommand(SubCommand::with_name("bench")
+            .about("measure storage and crypto throughput, leaving the state untouched")
+            .arg(Arg::with_name("ITERATIONS")
+                 .short("n")
+                 .long("iterations")
+                 .help("number of times each operation is run")
+                 .value_name("ITERATIONS")
+                 .default_value("100")
+                 .validator(|s| match s.parse::<u32>() {
+                     Ok(n) if n > 0 => Ok(()),
+                     _ => Err("must be a positive integer".into()),
+                 })
+             )
         )
     ;
 
@@ -88,25 +801,409 @@ impl TryFrom<&'_ clap::ArgMatches<'static>> for Command {
         if let Some(command) = args.subcommand_matches("register") {
             return Ok(Command::Register(Register {
                 label: command.value_of("label").unwrap().into(),
-                base32_secret: command.value_of("secret").unwrap().into(),
-                period_seconds: 30,
+                // clap keeps its own copy of the arguments, which we can't wipe
+                base32_secret: match command.is_present("FROM-WORDS") {
+                    true => SecretBytes::from_backup(&command.value_of("secret").unwrap().into())?.to_base32(),
+                    false => command.value_of("secret").unwrap().into(),
+                },
+                // no panic - clap validates the value
+                period_seconds: command.value_of("PERIOD").map_or(DEFAULT_PERIOD, |period| period.parse().unwrap()),
+                slot: slot(command)?.unwrap_or(0),
+                timestamp: now(),
+                location: location(command),
+                algorithm: command.value_of("ALGORITHM").unwrap().into(),
+                // no panic - clap validates the value
+                counter: command.value_of("COUNTER").map_or(0, |counter| counter.parse().unwrap()),
+                issuer: command.value_of("ISSUER").map(String::from),
             }));
         }
 
         if let Some(command) = args.subcommand_matches("authenticate") {
-            let timestamp = match command.value_of("timestamp") {
-                Some(s) => s.parse()?,
-                None => {
-                    let since_epoch = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap();
-                    since_epoch.as_secs()
-                }
-            };
+            if command.is_present("ALL") {
+                return Ok(Command::AuthenticateAll(AuthenticateAll { timestamp: timestamp(command)? }));
+            }
+            // no panic - clap validates the value
+            if let Some(count) = command.value_of("PRECOMPUTE") {
+                return Ok(Command::Precompute(Precompute {
+                    label: command.value_of("label").unwrap().into(),
+                    slot: slot(command)?,
+                    timestamp: timestamp(command)?,
+                    count: count.parse().unwrap(),
+                }));
+            }
             return Ok(Command::Authenticate(Authenticate {
                 label: command.value_of("label").unwrap().into(),
-                timestamp,
+                slot: slot(command)?,
+                timestamp: timestamp(command)?,
+            }));
+        }
+
+        if let Some(command) = args.subcommand_matches("verify") {
+            return Ok(Command::Verify(Verify {
+                label: command.value_of("label").unwrap().into(),
+                slot: slot(command)?,
+                timestamp: timestamp(command)?,
+                otp: Otp(command.value_of("otp").unwrap().into()),
+            }));
+        }
+
+        if args.subcommand_matches("list").is_some() {
+            return Ok(Command::List);
+        }
+
+        if args.subcommand_matches("labels").is_some() {
+            return Ok(Command::Labels);
+        }
+
+        if args.subcommand_matches("stats").is_some() {
+            return Ok(Command::Stats(Stats {
+                timestamp: now(),
+            }));
+        }
+
+        if let Some(command) = args.subcommand_matches("prune") {
+            return Ok(Command::Prune(Prune {
+                timestamp: now(),
+                unused_for_seconds: parse_duration(command.value_of("unused-for").unwrap())?,
+                delete: command.is_present("delete"),
+            }));
+        }
+
+        if args.subcommand_matches("trash").is_some() {
+            return Ok(Command::Trash(Trash {
+                timestamp: now(),
+            }));
+        }
+
+        if let Some(command) = args.subcommand_matches("undelete") {
+            return Ok(Command::Undelete(Undelete {
+                label: command.value_of("label").unwrap().into(),
+                slot: slot(command)?,
+            }));
+        }
+
+        if let Some(command) = args.subcommand_matches("repair") {
+            return Ok(Command::Repair(Repair {
+                delete: command.is_present("delete"),
+            }));
+        }
+
+        if let Some(command) = args.subcommand_matches("recovery") {
+            if let Some(command) = command.subcommand_matches("add") {
+                return Ok(Command::AddRecoveryCodes(AddRecoveryCodes {
+                    label: command.value_of("label").unwrap().into(),
+                    slot: slot(command)?,
+                    codes: command.values_of("codes").unwrap().map(Into::into).collect(),
+                }));
+            }
+            if let Some(command) = command.subcommand_matches("show") {
+                return Ok(Command::ShowRecoveryCodes(ShowRecoveryCodes {
+                    label: command.value_of("label").unwrap().into(),
+                    slot: slot(command)?,
+                }));
+            }
+            if let Some(command) = command.subcommand_matches("use") {
+                return Ok(Command::UseRecoveryCode(UseRecoveryCode {
+                    label: command.value_of("label").unwrap().into(),
+                    slot: slot(command)?,
+                    code: command.value_of("code").map(Into::into),
+                    timestamp: now(),
+                }));
+            }
+        }
+
+        if let Some(command) = args.subcommand_matches("chalresp") {
+            if let Some(command) = command.subcommand_matches("register") {
+                return Ok(Command::RegisterChallengeResponse(RegisterChallengeResponse {
+                    label: command.value_of("label").unwrap().into(),
+                    hex_secret: command.value_of("SECRET").map(Into::into),
+                    timestamp: now(),
+                }));
+            }
+            if let Some(command) = command.subcommand_matches("respond") {
+                return Ok(Command::RespondToChallenge(RespondToChallenge {
+                    label: command.value_of("label").unwrap().into(),
+                    // no panic - clap validates the value
+                    challenge: data_encoding::HEXLOWER_PERMISSIVE.decode(command.value_of("challenge").unwrap().as_bytes()).unwrap(),
+                }));
+            }
+            if let Some(command) = command.subcommand_matches("delete") {
+                return Ok(Command::DeleteChallengeResponse(DeleteChallengeResponse {
+                    label: command.value_of("label").unwrap().into(),
+                }));
+            }
+        }
+
+        if let Some(command) = args.subcommand_matches("policy") {
+            if command.subcommand_matches("show").is_some() {
+                return Ok(Command::ShowPolicy);
+            }
+            if let Some(command) = command.subcommand_matches("set") {
+                return Ok(Command::SetPolicy(SetPolicy {
+                    action: command.value_of("command").unwrap().parse()?,
+                    requirement: command.value_of("requirement").unwrap().parse()?,
+                }));
+            }
+        }
+        if let Some(command) = args.subcommand_matches("issuer") {
+            if command.subcommand_matches("show").is_some() {
+                return Ok(Command::ShowIssuers);
+            }
+            if let Some(command) = command.subcommand_matches("set") {
+                return Ok(Command::SetIssuer(SetIssuer {
+                    id: command.value_of("id").unwrap().into(),
+                    name: command.value_of("name").unwrap().into(),
+                    icon: command.value_of("icon").unwrap().into(),
+                }));
+            }
+            if let Some(command) = command.subcommand_matches("reset") {
+                return Ok(Command::ResetIssuer(ResetIssuer { id: command.value_of("id").unwrap().into() }));
+            }
+        }
+        Err(anyhow::anyhow!("Unexpected case"))
+    }
+}
+
+impl TryFrom<&'_ clap::ArgMatches<'static>> for crate::secrets::Command {
+    type Error = Error;
+    fn try_from(args: &clap::ArgMatches<'static>) -> Result<Self> {
+        use crate::secrets::{Add, Command, Delete, Get};
+        let args = args.subcommand_matches("secret")
+            .ok_or_else(|| anyhow::anyhow!("Unexpected case"))?;
+
+        if let Some(command) = args.subcommand_matches("add") {
+            let value: crate::secret::SecretString = match command.value_of("value") {
+                Some(value) => value.into(),
+                None => rpassword::read_password_from_tty(Some("Secret: "))?.into(),
+            };
+            return Ok(Command::Add(Add {
+                name: command.value_of("name").unwrap().into(),
+                value,
+                // no panic - clap enforces the value's existence and validity
+                requirement: command.value_of("REQUIRE").unwrap().parse()?,
+                timestamp: now(),
             }));
         }
+
+        if let Some(command) = args.subcommand_matches("get") {
+            return Ok(Command::Get(Get {
+                name: command.value_of("name").unwrap().into(),
+            }));
+        }
+
+        if args.subcommand_matches("list").is_some() {
+            return Ok(Command::List);
+        }
+
+        if let Some(command) = args.subcommand_matches("delete") {
+            return Ok(Command::Delete(Delete {
+                name: command.value_of("name").unwrap().into(),
+            }));
+        }
+        Err(anyhow::anyhow!("Unexpected case"))
+    }
+}
+
+impl TryFrom<&'_ clap::ArgMatches<'static>> for crate::runner::Command {
+    type Error = Error;
+    fn try_from(args: &clap::ArgMatches<'static>) -> Result<Self> {
+        use crate::runner::{Command, ExportState, ImportState};
+
+        // no panic - clap enforces the values' existence
+        if let Some(args) = args.subcommand_matches("state") {
+            if let Some(command) = args.subcommand_matches("export") {
+                return Ok(Command::ExportState(ExportState {
+                    file: command.value_of("file").unwrap().into(),
+                }));
+            }
+            if let Some(command) = args.subcommand_matches("import") {
+                return Ok(Command::ImportState(ImportState {
+                    file: command.value_of("file").unwrap().into(),
+                    force: command.is_present("FORCE"),
+                }));
+            }
+        }
+
+        if let Some(args) = args.subcommand_matches("storage") {
+            if args.subcommand_matches("gc").is_some() {
+                return Ok(Command::CollectGarbage);
+            }
+        }
         Err(anyhow::anyhow!("Unexpected case"))
     }
 }
 
+/// the supplied timestamp, or the current time
+fn timestamp(command: &clap::ArgMatches<'static>) -> Result<u64> {
+    Ok(match command.value_of("TIMESTAMP") {
+        Some(s) => s.parse()?,
+        None => now(),
+    })
+}
+
+/// The passphrase of the state file, if it is to be encrypted
+///
+/// With `--cache-passphrase`, a passphrase cached in the OS keychain is used, and a
+/// prompted-for passphrase is cached.
+pub fn passphrase(args: &clap::ArgMatches<'static>, state_file: &str) -> Result<Option<crate::secret::SecretString>> {
+    if !args.is_present("ENCRYPT-STATE") {
+        return Ok(None);
+    }
+    #[cfg(feature = "keychain")]
+    if args.is_present("CACHE-PASSPHRASE") {
+        if let Some(passphrase) = crate::keychain::load(state_file, now()) {
+            return Ok(Some(passphrase));
+        }
+    }
+    #[cfg(not(feature = "keychain"))]
+    if args.is_present("CACHE-PASSPHRASE") {
+        return Err(anyhow::anyhow!("Caching the passphrase needs the `keychain` feature"));
+    }
+
+    let passphrase: crate::secret::SecretString = match std::env::var("TOTP_STATE_PASSPHRASE") {
+        Ok(passphrase) => passphrase,
+        Err(_) => rpassword::read_password_from_tty(Some("State file passphrase: "))?,
+    }.into();
+    if passphrase.expose().is_empty() {
+        return Err(anyhow::anyhow!("The passphrase must not be empty"));
+    }
+
+    #[cfg(feature = "keychain")]
+    if let Some(period) = args.value_of("CACHE-PASSPHRASE") {
+        let expires_at = now().checked_add(parse_duration(period)?)
+            .ok_or_else(|| anyhow::anyhow!("Invalid duration {}, it ends too far in the future", period))?;
+        crate::keychain::store(state_file, &passphrase, expires_at)?;
+    }
+    #[cfg(not(feature = "keychain"))]
+    let _ = state_file;
+
+    Ok(Some(passphrase))
+}
+
+/// the current time, as seconds since the UNIX epoch
+pub fn now() -> u64 {
+    let since_epoch = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap();
+    since_epoch.as_secs()
+}
+
+/// how to format responses for stdout
+pub fn style(args: &clap::ArgMatches<'static>) -> Result<crate::output::Style> {
+    // no panic - clap enforces the value's existence and validity
+    let style = crate::output::Style::detect(args.value_of("COLOR").unwrap().parse()?);
+    let style = match args.value_of("LANG") {
+        Some(lang) => style.lang(lang.parse()?),
+        None => style,
+    };
+    Ok(match args.is_present("ACCESSIBLE") {
+        true => style.accessible(args.is_present("SPELL-CODES")),
+        false => style,
+    })
+}
+
+/// how long to wait for confirmation of user presence
+pub fn presence_timeout(args: &clap::ArgMatches<'static>) -> Result<core::time::Duration> {
+    // no panic - clap enforces the value's existence
+    Ok(core::time::Duration::from_secs(parse_duration(args.value_of("PRESENCE-TIMEOUT").unwrap())?))
+}
+
+/// the quota of the Trussed client, if any; the last one given for it counts
+pub fn quota(args: &clap::ArgMatches<'static>, client: &str) -> Result<Option<u64>> {
+    let mut quota = None;
+    for value in args.values_of("QUOTA").into_iter().flatten() {
+        let (name, bytes) = parse_quota(value)?;
+        if name == client {
+            quota = Some(bytes);
+        }
+    }
+    Ok(quota)
+}
+
+/// the configuration file, if any
+fn config(args: &clap::ArgMatches<'static>) -> Result<crate::config::Config> {
+    match args.value_of("CONFIG") {
+        Some(path) => crate::config::read(path),
+        None => Ok(crate::config::Config::default()),
+    }
+}
+
+/// the hooks to run on events: those of the configuration file, if any, then those given with --hook
+pub fn hooks(args: &clap::ArgMatches<'static>) -> Result<Vec<(crate::middleware::HookEvent, String)>> {
+    let mut hooks = config(args)?.hooks()?;
+    for hook in args.values_of("HOOK").into_iter().flatten() {
+        hooks.push(parse_hook(hook)?);
+    }
+    Ok(hooks)
+}
+
+/// the number of failures with a credential that is an event: --failure-threshold,
+/// else the configuration file's, else the authenticator's default
+pub fn failure_threshold(args: &clap::ArgMatches<'static>) -> Result<u32> {
+    if let Some(threshold) = args.value_of("FAILURE-THRESHOLD") {
+        // no panic - clap validates the value
+        return Ok(threshold.parse().unwrap());
+    }
+    Ok(config(args)?.failure_threshold.unwrap_or(crate::authenticator::FAILURE_THRESHOLD))
+}
+
+/// parses hooks like `failure=notify-send oops` into the event and the shell command
+fn parse_hook(s: &str) -> Result<(crate::middleware::HookEvent, String)> {
+    match s.find('=') {
+        Some(i) => Ok((s[..i].parse()?, s[i + 1..].into())),
+        None => Err(anyhow::anyhow!("Invalid hook {}, expected EVENT=COMMAND", s)),
+    }
+}
+
+/// how long to wait before typing the OTP
+pub fn type_delay(command: &clap::ArgMatches<'static>) -> Result<core::time::Duration> {
+    // no panic - clap enforces the value's existence
+    Ok(core::time::Duration::from_secs(parse_duration(command.value_of("TYPE-DELAY").unwrap())?))
+}
+
+/// parses quotas like `totp=64K` or `secrets=4096` into the client and bytes
+fn parse_quota(s: &str) -> Result<(&str, u64)> {
+    let (client, size) = match s.find('=') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return Err(anyhow::anyhow!("Invalid quota {}, expected CLIENT=SIZE", s)),
+    };
+    let bytes = match size.strip_suffix('K') {
+        Some(kibibytes) => kibibytes.parse::<u64>().ok().and_then(|kibibytes| kibibytes.checked_mul(1024)),
+        None => size.parse().ok(),
+    }.ok_or_else(|| anyhow::anyhow!("Invalid size {} in quota {}, expected bytes or KiB (e.g. 64K)", size, s))?;
+    Ok((client, bytes))
+}
+
+/// parses durations like `365d`, `12h`, `30m`, `90s` or `90` into seconds
+fn parse_duration(s: &str) -> Result<u64> {
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => (&s[..i], unit),
+        _ => (s, 's'),
+    };
+    let factor = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(anyhow::anyhow!("Unknown unit {} in duration {}, expected one of d, h, m, s", unit, s)),
+    };
+    number.parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(factor))
+        .ok_or_else(|| anyhow::anyhow!("Invalid duration {}", s))
+}
+
+/// the supplied slot, if any
+fn slot(command: &clap::ArgMatches<'static>) -> Result<Option<u8>> {
+    Ok(match command.value_of("SLOT") {
+        Some(s) => Some(s.parse()?),
+        None => None,
+    })
+}
+
+/// the supplied location; not the external store, which this runner only keeps in RAM
+fn location(command: &clap::ArgMatches<'static>) -> trussed::types::Location {
+    use trussed::types::Location;
+    // no panic - clap enforces the value's existence and validity
+    match command.value_of("LOCATION").unwrap() {
+        "volatile" => Location::Volatile,
+        _ => Location::Internal,
+    }
+}
@@ -0,0 +1,55 @@
+//! The runner's configuration file, for settings that are tedious to pass with each run.
+//!
+//! The file (TOML) is given with `--config`, or in `TOTP_CONFIG`. For now, it declares the
+//! hooks run on security events (cf. `middleware::Hooks`), in addition to those given
+//! with `--hook`, and when failures with a credential are an event, unless given with
+//! `--failure-threshold`:
+//!
+//! ```toml
+//! failure_threshold = 3
+//!
+//! [hooks]
+//! failure = ["notify-send \"TOTP: $TOTP_ERROR\""]
+//! failure-threshold = ["notify-send \"TOTP: too many failures with $TOTP_LABEL\""]
+//! deleted = ["logger -t totp \"$TOTP_COMMAND deleted $TOTP_LABEL\""]
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::middleware::HookEvent;
+use crate::Result;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// The contents of a configuration file
+pub struct Config {
+    /// Number of failures with a credential at which the `failure-threshold` event is raised
+    #[serde(default)]
+    pub failure_threshold: Option<u32>,
+    /// Shell commands by event, e.g. `failure = ["notify-send oops"]`
+    #[serde(default)]
+    pub hooks: BTreeMap<String, Vec<String>>,
+}
+
+/// Reads and parses a configuration file
+pub fn read(path: impl AsRef<Path>) -> Result<Config> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Could not read the configuration file {}: {}", path.display(), e))?;
+    toml::from_str(&contents).map_err(|e| anyhow::anyhow!("Invalid configuration file: {}", e))
+}
+
+impl Config {
+    /// The hooks, by event, in the order they are declared for each event
+    pub fn hooks(&self) -> Result<Vec<(HookEvent, String)>> {
+        let mut hooks = Vec::new();
+        for (event, commands) in self.hooks.iter() {
+            let event: HookEvent = event.parse()?;
+            hooks.extend(commands.iter().map(|command| (event, command.clone())));
+        }
+        Ok(hooks)
+    }
+}
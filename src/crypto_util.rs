@@ -0,0 +1,49 @@
+//! Small cryptographic helpers that don't belong into Trussed.
+//!
+//! ## Threat model
+//!
+//! Whenever the runner compares data derived from a secret (e.g. a one-time password computed
+//! by Trussed) with data supplied by a possibly malicious caller, the time taken by the comparison
+//! must not depend on how many leading bytes match. Otherwise, measuring response times allows
+//! guessing the secret-derived value byte by byte, instead of having to brute-force all of it.
+//!
+//! The lengths of the compared values are considered public.
+
+use subtle::ConstantTimeEq as _;
+
+/// Compares two byte strings in time depending only on their lengths
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.ct_eq(b).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::constant_time_eq;
+
+    #[test]
+    fn equal() {
+        assert!(constant_time_eq(b"287082", b"287082"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn unequal() {
+        assert!(!constant_time_eq(b"287082", b"081804"));
+    }
+
+    #[test]
+    fn mismatch_in_first_or_last_byte() {
+        assert!(!constant_time_eq(b"287082", b"387082"));
+        assert!(!constant_time_eq(b"287082", b"287083"));
+    }
+
+    #[test]
+    fn length_mismatch() {
+        assert!(!constant_time_eq(b"287082", b"28708"));
+        assert!(!constant_time_eq(b"28708", b"287082"));
+        assert!(!constant_time_eq(b"287082", b""));
+    }
+}
@@ -0,0 +1,111 @@
+//! Redacted dumps of the state file, for debugging.
+//!
+//! The littlefs filesystem is walked as is, and the records of this app are decoded
+//! where their format is known. Keys, and files of unknown format, are only shown
+//! with their size, so dumps can be shared in bug reports.
+
+use littlefs2::path::Path;
+use serde_json::{json, Value};
+use trussed::store::Store as _;
+use trussed::types::PathBuf;
+
+use crate::algorithm::Parameters;
+use crate::authenticator::{ChallengeResponse, Credential, Trashed};
+use crate::integrity::INTEGRITY_NAME;
+use crate::issuers::{Overrides, OVERRIDES_NAME};
+use crate::paths;
+use crate::platform::store::Store;
+use crate::policy::{Action, Policy};
+use crate::usage::Usage;
+use crate::Result;
+
+type Filesystem = littlefs2::fs::Filesystem<'static, crate::platform::store::FileFlash>;
+
+/// Dumps the persistent storage as a tree of directories and files, sorted by name
+pub fn dump(store: Store) -> Result<Value> {
+    directory(store.ifs(), &PathBuf::from("/"))
+}
+
+fn directory(fs: &Filesystem, path: &Path) -> Result<Value> {
+    let mut entries: Vec<(PathBuf, bool)> = fs.read_dir_and_then(path, |dir| {
+        let mut entries = Vec::new();
+        for entry in dir {
+            let entry = entry?;
+            let name = entry.file_name().as_ref();
+            if name != "." && name != ".." {
+                entries.push((PathBuf::from(entry.path()), entry.metadata().is_dir()));
+            }
+        }
+        Ok(entries)
+    }).map_err(|e| anyhow::anyhow!("Could not read directory {}: {:?}", path.as_ref(), e))?;
+    entries.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+
+    let entries = entries.iter()
+        .map(|(path, is_dir)| match is_dir {
+            true => directory(fs, path),
+            false => file(fs, path),
+        })
+        .collect::<Result<Vec<Value>>>()?;
+    Ok(json!({ "path": path.as_ref(), "entries": entries }))
+}
+
+fn file(fs: &Filesystem, path: &Path) -> Result<Value> {
+    let data = fs.read::<crate::platform::store::consts::U1024>(path)
+        .map_err(|e| anyhow::anyhow!("Could not read file {}: {:?}", path.as_ref(), e))?;
+    let (record, contents) = decode(path.as_ref(), &data);
+    let mut value = json!({ "path": path.as_ref(), "size": data.len(), "record": record });
+    if let Some(contents) = contents {
+        value["contents"] = contents;
+    }
+    Ok(value)
+}
+
+/// The type of the record, from the directory it is in, and its redacted contents, if known
+fn decode(path: &str, data: &[u8]) -> (&'static str, Option<Value>) {
+    // quarantined records keep their own path below the quarantine directory
+    if path.split('/').any(|component| component == paths::QUARANTINE_DIR) {
+        return ("quarantined", None);
+    }
+    let mut components = path.rsplit('/').skip(1);
+    match components.next().unwrap_or("") {
+        paths::CREDENTIALS_DIR => ("credential", Credential::from_bytes(data).ok().map(|credential| credential_json(&credential))),
+        paths::TRASH_DIR => ("deleted credential", Trashed::from_bytes(data).ok().map(|trashed| {
+            let mut value = credential_json(trashed.credential());
+            value["deleted_at"] = trashed.deleted_at().into();
+            value
+        })),
+        paths::USAGE_DIR => ("usage", postcard::from_bytes::<Usage>(data).ok().and_then(|usage| serde_json::to_value(usage).ok())),
+        paths::PARAMETERS_DIR => ("OTP parameters", Parameters::from_bytes(data).ok().and_then(|parameters| serde_json::to_value(parameters).ok())),
+        paths::CONFIG_DIR if path.rsplit('/').next() == Some(OVERRIDES_NAME) => ("issuer overrides", postcard::from_bytes::<Overrides>(data).ok()
+            .and_then(|overrides| serde_json::to_value(overrides).ok())),
+        paths::CONFIG_DIR if path.rsplit('/').next() == Some(INTEGRITY_NAME) => ("integrity MAC", None),
+        paths::CONFIG_DIR => ("configuration", Policy::from_bytes(data).ok().map(|policy| {
+            Action::ALL.iter()
+                .map(|action| (action.name().to_string(), Value::from(policy.requirement(*action).to_string())))
+                .collect::<serde_json::Map<String, Value>>()
+                .into()
+        })),
+        // sealed by Trussed, there is nothing to decode
+        paths::RECOVERY_DIR => ("recovery codes", None),
+        paths::CHALLENGE_RESPONSE_DIR => ("challenge-response key", ChallengeResponse::from_bytes(data).ok().map(|key| {
+            json!({ "label": key.label(), "created_at": key.created_at() })
+        })),
+        paths::ITEMS_DIR => ("secret", None),
+        // Trussed's key store
+        "sec" | "pub" => ("key", None),
+        _ => ("unknown", None),
+    }
+}
+
+/// Everything about a credential, except its secret, which only Trussed knows
+fn credential_json(credential: &Credential) -> Value {
+    json!({
+        "label": credential.label(),
+        "slot": credential.slot(),
+        "period_seconds": credential.period_seconds(),
+        "created_at": credential.created_at(),
+        "last_used": credential.last_used(),
+        "secret_length": credential.secret_length(),
+        "issuer": credential.issuer(),
+    })
+}
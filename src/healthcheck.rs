@@ -0,0 +1,154 @@
+//! Checks whether the runner is able to work, for scripts and monitoring (e.g. systemd's
+//! `ExecCondition`).
+//!
+//! The state file is mounted read-only, so checking never changes it, and each check
+//! reports what it found instead of failing the whole run. Later checks depend on earlier
+//! ones, e.g. the Trussed service can't respond without a mounted state file, and are
+//! skipped if those fail.
+
+use serde::Serialize;
+use trussed::try_syscall;
+
+use crate::platform::{self, rng, store};
+use crate::secret::SecretString;
+
+/// Times before this are surely wrong, the tutorial didn't exist yet (2021-01-01)
+const EARLIEST: u64 = 1_609_459_200;
+/// Times after this are surely wrong as well (2100-01-01)
+const LATEST: u64 = 4_102_444_800;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+/// The outcome of one check
+pub struct Check {
+    /// What was checked: clock, rng, state, lock, mount or service
+    pub check: &'static str,
+    /// Whether the check passed
+    pub ok: bool,
+    /// What was found, or why the check failed
+    pub detail: String,
+}
+
+impl Check {
+    fn new(check: &'static str, result: Result<String, impl core::fmt::Display>) -> Self {
+        match result {
+            Ok(detail) => Self { check, ok: true, detail },
+            Err(error) => Self { check, ok: false, detail: error.to_string() },
+        }
+    }
+
+    fn skipped(check: &'static str, failed: &str) -> Self {
+        Self { check, ok: false, detail: format!("skipped, as the {} check failed", failed) }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+/// The outcome of all checks
+pub struct Report {
+    /// Whether all checks passed
+    pub healthy: bool,
+    /// The individual checks, in the order they ran
+    pub checks: Vec<Check>,
+}
+
+/// Runs all checks against the state file
+///
+/// The passphrase is the outcome of asking for it, if the state file is encrypted.
+pub fn check(
+    state_path: impl AsRef<std::path::Path>,
+    passphrase: crate::Result<Option<SecretString>>,
+    rng: &rng::RngSource,
+    now: u64,
+) -> Report {
+    let state_path = state_path.as_ref();
+    let mut checks = vec![clock(now)];
+
+    let rng = rng::Rng::new(rng);
+    checks.push(Check::new("rng", rng.as_ref().map(|_| "passed its health tests".into())));
+
+    let encrypted = state(state_path);
+    checks.push(Check::new("state", encrypted.as_ref()
+        .map(|&encrypted| format!("{} ({})", state_path.display(), if encrypted { "encrypted" } else { "plain" }))));
+    let passphrase = match encrypted {
+        Ok(encrypted) => {
+            let passphrase = lock(encrypted, passphrase);
+            checks.push(Check::new("lock", passphrase.as_ref()
+                .map(|passphrase| String::from(match passphrase {
+                    Some(_) => "encrypted, unlocked with the passphrase",
+                    None => "not encrypted",
+                }))));
+            passphrase.ok()
+        }
+        Err(_) => {
+            checks.push(Check::skipped("lock", "state"));
+            None
+        }
+    };
+
+    let mounted = match passphrase {
+        Some(passphrase) => {
+            let mounted = store::init_store(state_path, 0, passphrase.as_ref(), true, false);
+            checks.push(Check::new("mount", mounted.as_ref().map(|_| "mountable read-only".into())));
+            mounted.ok()
+        }
+        None => {
+            checks.push(Check::skipped("mount", "lock"));
+            None
+        }
+    };
+
+    checks.push(match (mounted, rng) {
+        (Some((store, _sync_handle)), Ok(rng)) => Check::new("service", service(store, rng)),
+        (None, _) => Check::skipped("service", "mount"),
+        (_, Err(_)) => Check::skipped("service", "rng"),
+    });
+
+    Report { healthy: checks.iter().all(|check| check.ok), checks }
+}
+
+fn clock(now: u64) -> Check {
+    Check::new("clock", match now {
+        now if now < EARLIEST => Err(anyhow::anyhow!("{} is too early, the clock is not set", now)),
+        now if now >= LATEST => Err(anyhow::anyhow!("{} is too late, the clock is off", now)),
+        now => Ok(format!("{} seconds since the UNIX epoch", now)),
+    })
+}
+
+/// Whether the state file is encrypted, if it is accessible
+fn state(path: &std::path::Path) -> crate::Result<bool> {
+    let metadata = std::fs::metadata(path)
+        .map_err(|e| anyhow::anyhow!("Could not access the state file at {}: {}", path.display(), e))?;
+    std::fs::File::open(path)
+        .map_err(|e| anyhow::anyhow!("Could not open the state file at {}: {}", path.display(), e))?;
+    match metadata.len() {
+        store::FileFlash::SIZE => Ok(false),
+        store::FileFlash::ENCRYPTED_SIZE => Ok(true),
+        len => Err(anyhow::anyhow!("The state file has an unexpected size of {} bytes", len)),
+    }
+}
+
+/// The passphrase to unlock the state file with, which must be given if it is encrypted
+fn lock(
+    encrypted: bool,
+    passphrase: crate::Result<Option<SecretString>>,
+) -> crate::Result<Option<SecretString>> {
+    match (encrypted, passphrase?) {
+        (true, None) => Err(anyhow::anyhow!("The state file is encrypted, use --encrypt-state")),
+        (false, Some(_)) => Err(anyhow::anyhow!("The state file is not encrypted")),
+        (_, passphrase) => Ok(passphrase),
+    }
+}
+
+/// Makes a request to the Trussed service; as there is no ping, it asks for random bytes
+fn service(store: store::Store, rng: rng::Rng) -> crate::Result<String> {
+    use trussed::client::CryptoClient as _;
+    let platform = platform::Platform::new(rng, store, platform::UserInterface::new(platform::Presence::Deny));
+    let mut service = trussed::service::Service::new(platform);
+    let mut client = service.try_as_new_client("healthcheck")
+        .map_err(|_| anyhow::anyhow!("The Trussed service has no room for another client"))?;
+    let reply = try_syscall!(client.random_bytes(16))
+        .map_err(|e| anyhow::anyhow!("The Trussed service failed to respond: {:?}", e))?;
+    match reply.bytes.len() {
+        16 => Ok("responds".into()),
+        len => Err(anyhow::anyhow!("The Trussed service responded with {} instead of 16 bytes", len)),
+    }
+}
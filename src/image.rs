@@ -0,0 +1,94 @@
+//! Export and import of the state file's raw image, e.g. for backups.
+//!
+//! An exported image is the state file as is (encrypted or not), framed by a header
+//! and a SHA-256 trailer:
+//!
+//! ```text
+//! "TOTPIMG\0" | version (1 byte) | length of the image (8 bytes, little endian) | image | SHA-256
+//! ```
+//!
+//! The hash covers the header and the image, so truncated or corrupted exports are
+//! refused on import, before the state file is touched.
+
+use std::path::Path;
+
+use sha2::{Digest as _, Sha256};
+
+use crate::platform::store::{wear_path, FileFlash};
+use crate::Result;
+
+const MAGIC: &[u8; 8] = b"TOTPIMG\0";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + 8;
+const TRAILER_SIZE: usize = 32;
+
+/// Writes the state file's image to the export file
+pub fn export(state_file: impl AsRef<Path>, export_file: impl AsRef<Path>) -> Result<()> {
+    let image = std::fs::read(state_file.as_ref())
+        .map_err(|e| anyhow::anyhow!("Could not read the state file: {}", e))?;
+    check_size(image.len() as u64)?;
+
+    let mut exported = Vec::with_capacity(HEADER_SIZE + image.len() + TRAILER_SIZE);
+    exported.extend_from_slice(MAGIC);
+    exported.push(VERSION);
+    exported.extend_from_slice(&(image.len() as u64).to_le_bytes());
+    exported.extend_from_slice(&image);
+    let digest = Sha256::digest(&exported);
+    exported.extend_from_slice(&digest);
+    std::fs::write(export_file, exported)?;
+    Ok(())
+}
+
+/// Replaces the state file with the image in the export file, after verifying it
+///
+/// An existing state file is only replaced if forced to.
+pub fn import(export_file: impl AsRef<Path>, state_file: impl AsRef<Path>, force: bool) -> Result<()> {
+    let exported = std::fs::read(export_file)?;
+    let image = verify(&exported)?;
+
+    let state_file = state_file.as_ref();
+    if state_file.exists() && !force {
+        return Err(anyhow::anyhow!("There already is a state file at {}, use --force to replace it", state_file.display()));
+    }
+    // written next to the state file and renamed, so it is replaced entirely or not at all
+    let mut temporary = state_file.as_os_str().to_owned();
+    temporary.push(".import");
+    std::fs::write(&temporary, image)?;
+    std::fs::rename(&temporary, state_file)?;
+    // the erase cycles counted belong to the replaced image
+    std::fs::remove_file(wear_path(state_file)).ok();
+    Ok(())
+}
+
+/// Checks the framing and hash of an export, returning the image
+fn verify(exported: &[u8]) -> Result<&[u8]> {
+    if exported.len() < HEADER_SIZE + TRAILER_SIZE || &exported[..MAGIC.len()] != MAGIC {
+        return Err(anyhow::anyhow!("This is not an exported state file"));
+    }
+    let version = exported[MAGIC.len()];
+    if version != VERSION {
+        return Err(anyhow::anyhow!("Exported state files of version {} are not supported", version));
+    }
+    // no panic - the length was checked above
+    let mut length = [0u8; 8];
+    length.copy_from_slice(&exported[MAGIC.len() + 1..HEADER_SIZE]);
+    let length = u64::from_le_bytes(length);
+    let actual = (exported.len() - HEADER_SIZE - TRAILER_SIZE) as u64;
+    if length != actual {
+        return Err(anyhow::anyhow!("The export is truncated or extended: it should hold {} bytes, but holds {}", length, actual));
+    }
+
+    let (framed, digest) = exported.split_at(exported.len() - TRAILER_SIZE);
+    if Sha256::digest(framed).as_slice() != digest {
+        return Err(anyhow::anyhow!("The export is corrupted, its SHA-256 doesn't match"));
+    }
+    check_size(length)?;
+    Ok(&framed[HEADER_SIZE..])
+}
+
+fn check_size(size: u64) -> Result<()> {
+    match size {
+        FileFlash::SIZE | FileFlash::ENCRYPTED_SIZE => Ok(()),
+        _ => Err(anyhow::anyhow!("A state file of {} bytes is not supported", size)),
+    }
+}
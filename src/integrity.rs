@@ -0,0 +1,87 @@
+//! A MAC over the records that decide what the app allows, against edits of the state file.
+//!
+//! The policy, and the index of keys (the credentials with their OTP parameters, deleted
+//! credentials and challenge-response keys, in the internal store), are hashed, and the hash
+//! is authenticated with an HMAC-SHA256 key held by Trussed. The app updates the MAC after
+//! each command; the runner checks it before each run, and refuses to go on if the records
+//! were changed outside the app, until the user accepts them, confirming their presence.
+//!
+//! This only keeps out those who can't use Trussed's keys. Unless the state file is encrypted
+//! (`--encrypt-state`), the MAC's key is in the state file as well, so whoever edits it can
+//! recompute the MAC. Encrypted, the MAC catches what the encryption misses: blocks that are
+//! zeroed, or rolled back on their own. A state file without a MAC, e.g. from before there
+//! was one, is accepted as it is.
+
+use serde::{Deserialize, Serialize};
+use trussed::{syscall, try_syscall, Bytes};
+use trussed::types::{KeyId, Location, Mechanism, Signature, SignatureSerialization};
+
+use crate::crypto_util::constant_time_eq;
+use crate::paths;
+use crate::Result;
+
+/// The name of the record in the configuration directory
+pub const INTEGRITY_NAME: &str = "integrity";
+const KEY_LENGTH: usize = 32;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// The MAC over the digest of the records, with the key that computed it
+pub struct Integrity {
+    key: KeyId,
+    mac: Signature,
+}
+
+impl Integrity {
+    /// Authenticates the digest with the given key, or a newly injected one in the internal store
+    ///
+    /// Fails if the given key is gone, e.g. deleted along with the record.
+    pub fn compute<T: trussed::Client>(trussed: &mut T, key: Option<KeyId>, digest: &[u8]) -> Result<Self> {
+        let key = match key {
+            Some(key) => key,
+            None => {
+                let random = syscall!(trussed.random_bytes(KEY_LENGTH)).bytes;
+                syscall!(trussed.unsafe_inject_shared_key(&random, Location::Internal)).key
+            }
+        };
+        let mac = try_syscall!(trussed.sign(Mechanism::HmacSha256, key, digest, SignatureSerialization::Raw))
+            .map_err(|_| anyhow::anyhow!("The key of the integrity MAC is missing"))?
+            .signature;
+        Ok(Self { key, mac })
+    }
+
+    /// Whether the MAC authenticates the digest
+    pub fn verify<T: trussed::Client>(&self, trussed: &mut T, digest: &[u8]) -> bool {
+        Self::compute(trussed, Some(self.key), digest)
+            .map_or(false, |other| constant_time_eq(&self.mac, &other.mac))
+    }
+
+    /// The key that computed the MAC
+    pub fn key(&self) -> KeyId {
+        self.key
+    }
+
+    /// Loads the record, or `None` if there is none
+    pub fn load<T: trussed::Client>(trussed: &mut T) -> Result<Option<Self>> {
+        let serialized = match try_syscall!(trussed.read_file(Location::Internal, paths::config(INTEGRITY_NAME))) {
+            Ok(reply) => reply.data,
+            Err(_) => return Ok(None),
+        };
+        postcard::from_bytes(&serialized)
+            .map(Some)
+            .map_err(|_| anyhow::anyhow!("The integrity record is corrupted"))
+    }
+
+    /// Persists the record
+    pub fn store<T: trussed::Client>(&self, trussed: &mut T) -> Result<()> {
+        let mut buf = [0u8; 128];
+        let serialized = postcard::to_slice(self, &mut buf)
+            .map_err(|_| anyhow::anyhow!("postcard serialization error"))?;
+        syscall!(trussed.write_file(
+            Location::Internal,
+            paths::config(INTEGRITY_NAME),
+            Bytes::from_slice(&*serialized).unwrap(),
+            None
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! A catalog of well-known issuers, telling their display names and icons, for listings
+//! and frontends.
+//!
+//! Credentials optionally refer to an issuer by its id, e.g. `github`. The catalog built
+//! into the app covers common services; users can override entries, or add their own, in
+//! a configuration record persisted in Trussed. Icons are identified by their slug in
+//! Simple Icons (<https://simpleicons.org>), which frontends can map to images.
+
+use serde::{Deserialize, Serialize};
+use trussed::{syscall, try_syscall, Bytes, types::Location};
+
+use crate::paths;
+use crate::Result;
+
+/// Name of the configuration record of the overrides
+pub const OVERRIDES_NAME: &str = "issuers";
+/// Ids, display names and icons of issuers are short
+pub const MAX_ISSUER_FIELD_LENGTH: usize = 32;
+
+/// The built-in catalog: ids, display names and icons
+const BUILT_IN: &[(&str, &str, &str)] = &[
+    ("amazon", "Amazon", "amazon"),
+    ("aws", "Amazon Web Services", "amazonaws"),
+    ("bitwarden", "Bitwarden", "bitwarden"),
+    ("cloudflare", "Cloudflare", "cloudflare"),
+    ("discord", "Discord", "discord"),
+    ("dropbox", "Dropbox", "dropbox"),
+    ("facebook", "Facebook", "facebook"),
+    ("github", "GitHub", "github"),
+    ("gitlab", "GitLab", "gitlab"),
+    ("google", "Google", "google"),
+    ("microsoft", "Microsoft", "microsoft"),
+    ("paypal", "PayPal", "paypal"),
+    ("steam", "Steam", "steam"),
+];
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// How an issuer is shown
+pub struct Issuer {
+    /// What credentials refer to the issuer by, e.g. `github`
+    pub id: String,
+    /// The name to show, e.g. `GitHub`
+    pub name: String,
+    /// The slug of the icon in Simple Icons, e.g. `github`
+    pub icon: String,
+}
+
+/// Checks that an issuer id is short, and made of lowercase letters, digits and dashes
+pub fn check_id(id: &str) -> Result<()> {
+    let valid = !id.is_empty() && id.len() <= MAX_ISSUER_FIELD_LENGTH
+        && id.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-');
+    match valid {
+        true => Ok(()),
+        false => Err(anyhow::anyhow!(
+            "Invalid issuer {:?}, expected up to {} lowercase letters, digits and dashes", id, MAX_ISSUER_FIELD_LENGTH)),
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+/// The user's changes to the built-in catalog
+pub struct Overrides {
+    issuers: Vec<Issuer>,
+}
+
+impl Overrides {
+    /// Loads the persisted overrides, which are empty if there are none
+    pub fn load<T: trussed::Client>(trussed: &mut T) -> Result<Self> {
+        match try_syscall!(trussed.read_file(Location::Internal, paths::config(OVERRIDES_NAME))) {
+            Ok(reply) => postcard::from_bytes(reply.data.as_ref())
+                .map_err(|_| anyhow::anyhow!("The stored issuer overrides are corrupted")),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Persists the overrides, removing the record if there are none
+    pub fn store<T: trussed::Client>(&self, trussed: &mut T) -> Result<()> {
+        if self.issuers.is_empty() {
+            try_syscall!(trussed.remove_file(Location::Internal, paths::config(OVERRIDES_NAME))).ok();
+            return Ok(());
+        }
+        let mut buf = [0u8; 1024];
+        let serialized_overrides = postcard::to_slice(self, &mut buf)
+            .map_err(|_| anyhow::anyhow!("The issuer overrides don't fit into one record, reset some first"))?;
+        syscall!(trussed.write_file(
+            Location::Internal,
+            paths::config(OVERRIDES_NAME),
+            Bytes::from_slice(&*serialized_overrides).unwrap(),
+            None
+        ));
+        Ok(())
+    }
+
+    /// Overrides the issuer with the same id, or adds it
+    pub fn set(&mut self, issuer: Issuer) -> Result<()> {
+        check_id(&issuer.id)?;
+        for field in [&issuer.name, &issuer.icon].iter() {
+            if field.is_empty() || field.len() > MAX_ISSUER_FIELD_LENGTH {
+                return Err(anyhow::anyhow!(
+                    "Names and icons of issuers have 1 to {} bytes", MAX_ISSUER_FIELD_LENGTH));
+            }
+        }
+        self.issuers.retain(|other| other.id != issuer.id);
+        self.issuers.push(issuer);
+        Ok(())
+    }
+
+    /// Removes the override of the issuer, returning whether there was one
+    pub fn reset(&mut self, id: &str) -> bool {
+        let before = self.issuers.len();
+        self.issuers.retain(|issuer| issuer.id != id);
+        self.issuers.len() != before
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// The built-in catalog, with the user's overrides applied
+pub struct Catalog {
+    /// The issuers by id, each marked as overridden (or added) by the user or not
+    pub issuers: Vec<(Issuer, bool)>,
+}
+
+impl Catalog {
+    /// Applies the overrides to the built-in catalog
+    pub fn new(overrides: &Overrides) -> Self {
+        let mut issuers: Vec<(Issuer, bool)> = BUILT_IN.iter()
+            .filter(|(id, _, _)| !overrides.issuers.iter().any(|issuer| issuer.id == *id))
+            .map(|(id, name, icon)| (Issuer { id: (*id).into(), name: (*name).into(), icon: (*icon).into() }, false))
+            .chain(overrides.issuers.iter().map(|issuer| (issuer.clone(), true)))
+            .collect();
+        issuers.sort_by(|(a, _), (b, _)| a.id.cmp(&b.id));
+        Self { issuers }
+    }
+
+    /// The issuer with the id, if the catalog knows it
+    pub fn find(&self, id: &str) -> Option<&Issuer> {
+        self.issuers.iter().map(|(issuer, _)| issuer).find(|issuer| issuer.id == id)
+    }
+
+    /// The name to show for the issuer with the id, which is the id itself if unknown
+    pub fn name<'a>(&'a self, id: &'a str) -> &'a str {
+        self.find(id).map_or(id, |issuer| issuer.name.as_str())
+    }
+}
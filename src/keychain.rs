@@ -0,0 +1,42 @@
+//! Caching of the state file passphrase in the OS keychain (Secret Service, Keychain, DPAPI).
+//!
+//! This spares users typing the passphrase for each command, without it ever being written
+//! to disk in plaintext. Keychains have no notion of expiry, so the entry stores its expiry
+//! time in front of the passphrase, and expired entries are deleted when they are found.
+
+use crate::secret::SecretString;
+use crate::Result;
+
+const SERVICE: &str = "trussed-totp-pc-tutorial";
+
+/// One entry per state file, keyed by its canonical path
+fn entry(state_file: &str) -> keyring::Entry {
+    let path = std::fs::canonicalize(state_file)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| state_file.into());
+    keyring::Entry::new(SERVICE, &path)
+}
+
+/// The cached passphrase of the state file, unless there is none or it has expired
+pub fn load(state_file: &str, now: u64) -> Option<SecretString> {
+    let entry = entry(state_file);
+    let stored = SecretString::from(entry.get_password().ok()?);
+    let (expiry, passphrase) = stored.expose().split_once(':')?;
+    if expiry.parse::<u64>().ok()? <= now {
+        entry.delete_password().ok();
+        return None;
+    }
+    Some(passphrase.into())
+}
+
+/// Caches the passphrase of the state file until the expiry time
+pub fn store(state_file: &str, passphrase: &SecretString, expiry: u64) -> Result<()> {
+    let stored = SecretString::from(format!("{}:{}", expiry, passphrase.expose()));
+    entry(state_file).set_password(stored.expose())
+        .map_err(|e| anyhow::anyhow!("Could not cache the passphrase: {}", e))
+}
+
+/// Removes the cached passphrase of the state file, e.g. because it turned out to be wrong
+pub fn forget(state_file: &str) {
+    entry(state_file).delete_password().ok();
+}
@@ -1,4 +1,6 @@
 #![deny(missing_docs)]
+// only results go to stdout, cf. `output`
+#![deny(clippy::print_stdout, clippy::print_stderr)]
 //! # Trussed®-based TOTP authenticator, for PC.
 //!
 //! This is a demo implementation of a TOTP authenticator, built on [Trussed®][trussed].
@@ -43,9 +45,41 @@
 /// Here, we are somewhat untyped and just use `anyhow`.
 pub use anyhow::Result;
 
+pub mod algorithm;
 pub mod authenticator;
+#[cfg(feature = "autotype")]
+pub mod autotype;
+pub mod batch;
+pub mod bench;
 pub mod cli;
+pub mod config;
+pub mod crypto_util;
+pub mod dump;
+pub mod healthcheck;
+pub mod image;
+pub mod integrity;
+pub mod issuers;
+#[cfg(feature = "keychain")]
+pub mod keychain;
+pub mod logging;
+pub mod messages;
+pub mod middleware;
+pub mod output;
+pub mod paths;
 pub mod platform;
+pub mod policy;
+pub mod provision;
+pub mod quota;
+pub mod recovery;
+pub mod rollback;
+pub mod runner;
+pub mod sealed;
+pub mod secret;
+pub mod secrets;
+pub mod testing;
+pub mod trace;
+pub mod transcript;
+pub mod usage;
 
 #[cfg(feature = "include-main-in-lib-for-docs")]
 pub mod main;
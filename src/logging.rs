@@ -0,0 +1,68 @@
+//! Setup of logging, to stderr or to a file, with per-module log levels.
+//!
+//! Log levels are given in `env_logger`'s syntax, e.g. `info,tutorial::platform=debug`, either
+//! via `--log-level` or the `RUST_LOG` environment variable. Results are printed to stdout,
+//! logs never are.
+//!
+//! Secrets never reach the logs: they are held in the wrappers of `crate::secret`, whose
+//! `Debug` implementations redact them.
+
+use std::io::Write as _;
+use std::sync::Mutex;
+
+use crate::Result;
+
+/// Initializes logging; without a file, logs go to stderr.
+pub fn init(filters: Option<&str>, file: Option<&std::path::Path>) -> Result<()> {
+    let filters = match filters {
+        Some(filters) => filters.to_string(),
+        None => std::env::var("RUST_LOG").unwrap_or_default(),
+    };
+
+    match file {
+        None => {
+            pretty_env_logger::formatted_builder()
+                .parse_filters(&filters)
+                .try_init()?;
+        }
+        Some(path) => {
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| anyhow::anyhow!("Could not open log file {}: {}", path.display(), e))?;
+            let filter = env_logger::filter::Builder::new().parse(&filters).build();
+            log::set_max_level(filter.filter());
+            log::set_boxed_logger(Box::new(FileLogger { filter, file: Mutex::new(file) }))?;
+        }
+    }
+    Ok(())
+}
+
+/// Appends log records to a file, one per line
+struct FileLogger {
+    filter: env_logger::filter::Filter,
+    file: Mutex<std::fs::File>,
+}
+
+impl log::Log for FileLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.filter.matches(record) {
+            let since_epoch = std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            if let Ok(mut file) = self.file.lock() {
+                writeln!(file, "{}.{:03} {:<5} {}: {}",
+                    since_epoch.as_secs(), since_epoch.subsec_millis(),
+                    record.level(), record.target(), record.args()).ok();
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            file.flush().ok();
+        }
+    }
+}
@@ -1,12 +1,16 @@
+// only results go to stdout, cf. `output`
+#![deny(clippy::print_stdout, clippy::print_stderr)]
+
 use core::convert::TryFrom;
 
 use anyhow::Result;
-use log::info;
+use log::{debug, info, warn};
 
 // #[cfg(feature = "include-main-in-lib-for-docs")]
 // use crate::{authenticator, cli, platform};
 // #[cfg(not(feature = "include-main-in-lib-for-docs"))]
-use tutorial::{authenticator, cli, platform};
+use tutorial::{algorithm, authenticator, batch, bench, cli, image, logging, middleware, output, platform, policy, provision, quota, rollback, runner, secrets, trace, transcript};
+use tutorial::messages::Message;
 
 
 /// Simplified "runner" to demonstrate the TOTP authenticator app.
@@ -28,45 +32,893 @@ use tutorial::{authenticator, cli, platform};
 ///
 pub fn main() -> Result<()> {
 
-    pretty_env_logger::init();
+    let (args, state_file, cache_blocks) = cli::init_cli();
+
+    // scripts consuming the labels or a quiet OTP must not be confused by log output
+    let log_file = args.value_of("LOG-FILE").map(std::path::Path::new);
+    let quiet = args.subcommand_matches("authenticate").map_or(false, |args| args.is_present("QUIET"));
+    if (args.subcommand_matches("labels").is_none() && !quiet) || log_file.is_some() {
+        logging::init(args.value_of("LOG-LEVEL"), log_file)?;
+    }
     info!("Welcome to the tutorial.");
 
-    let (args, state_file) = cli::init_cli();
+    // replaying a transcript starts from a fresh state, in a directory of its own, which is
+    // removed with the files next to the state file (e.g. erase counts) when `main` returns
+    let replay_dir = match args.subcommand_matches("replay") {
+        Some(_) => Some(tempfile::Builder::new().prefix("trussed-totp-pc-tutorial-replay").tempdir()?),
+        None => None,
+    };
+    let state_file = match &replay_dir {
+        Some(dir) => dir.path().join("state.littlefs2").to_string_lossy().into_owned(),
+        None => state_file,
+    };
+
+    let read_only = args.is_present("READ-ONLY");
+
+    // exporting and importing copy the state file as is, without mounting it
+    if args.subcommand_matches("state").is_some() {
+        let command = runner::Command::try_from(&args)?;
+        let mut pipeline = pipeline(read_only, args.value_of("RECORD"), middleware::Hooks::new(cli::hooks(&args)?))?;
+        pipeline.dispatch(command, &mut |command| {
+            match command {
+                runner::Command::ExportState(export) => image::export(&state_file, &export.file)?,
+                runner::Command::ImportState(import) => image::import(&import.file, &state_file, import.force)?,
+                runner::Command::CollectGarbage => return Err(anyhow::anyhow!("Unexpected case")),
+            }
+            Ok(String::new())
+        })?;
+        return Ok(());
+    }
+
+    // checking health never changes (or creates) the state file, and reports failures as JSON
+    if args.subcommand_matches("healthcheck").is_some() {
+        // no panic - clap enforces the value's existence and validity
+        let rng = args.value_of("RNG").unwrap().parse()?;
+        let passphrase = cli::passphrase(&args, &state_file);
+        let report = tutorial::healthcheck::check(&state_file, passphrase, &rng, cli::now());
+        output::result(&format!("{}\n", serde_json::to_string_pretty(&report)?));
+        return match report.healthy {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!("The health check failed")),
+        };
+    }
+
+    // other commands create a state file as well, but leave it to the defaults
+    let new_state = !read_only && !std::path::Path::new(&state_file).exists();
+    if new_state && args.subcommand_matches("setup").is_none() && args.subcommand_matches("replay").is_none() {
+        output::diagnostic(&format!("Creating a new state file at {}, `setup` walks you through configuring it", state_file));
+    }
+
+    // the arguments are checked before mounting the state file, so mistakes fail fast
+    // the secrets app is a second app, with a client (and hence storage namespace) of its own
+    let secrets_app = args.subcommand_matches("secret").is_some();
+    let client_id = if secrets_app { secrets::CLIENT_ID } else { "totp" };
+    let client_quota = cli::quota(&args, client_id)?;
+    let presence_timeout = cli::presence_timeout(&args)?;
+    let hooks = middleware::Hooks::new(cli::hooks(&args)?);
+    let failure_threshold = cli::failure_threshold(&args)?;
+    let secrets_command = match secrets_app {
+        true => Some(secrets::Command::try_from(&args)?),
+        false => None,
+    };
+    // the "args" come in over the CLI "interface", and are "deserialized" for processing
+    // using `Command`'s implementation of `TryFrom`, the standard Trait for fallible type conversion
+    let command = match args.subcommand_name() {
+        Some(name) if cli::RUNNER_SUBCOMMANDS.contains(&name) || secrets_app => None,
+        _ => Some(authenticator::Command::try_from(&args)?),
+    };
+
+    // Ctrl-C must not cut short writing back the state file
+    platform::signals::install()?;
 
     // setup platform (in our case, PC)
-    let trussed_platform = platform::init_platform(state_file);
+    // no panic - clap enforces the value's existence and validity
+    let presence = args.value_of("UI").unwrap().parse()?;
+    let rng = args.value_of("RNG").unwrap().parse()?;
+    let passphrase = cli::passphrase(&args, &state_file)?;
+    let platform = platform::init_platform(
+        &state_file, cache_blocks, presence, passphrase.as_ref(), read_only, args.is_present("REFORMAT"), &rng);
+    // don't keep a wrong passphrase cached
+    #[cfg(feature = "keychain")]
+    if platform.is_err() && args.is_present("CACHE-PASSPHRASE") {
+        tutorial::keychain::forget(&state_file);
+    }
+    let (trussed_platform, sync_handle, seed_file) = platform?;
+    // the next run starts from fresh entropy, even if this one fails
+    let _seed = Defer(|| if let Some(seed_file) = &seed_file {
+        if let Err(error) = seed_file.write_back() {
+            warn!("could not write back the RNG seed: {}", error);
+        }
+    });
+    // the store is `Copy`, keeping a copy allows the runner to inspect it
+    let store = trussed::Platform::store(&trussed_platform);
 
     // setup Trussed
     let mut trussed_service = trussed::service::Service::new(trussed_platform);
-    let client_id = "totp";
     // In real life, `trussed_service.try_new_client` has an additional parameter that is a `Syscall`
     // implementation; giving the client a way to signal the ambient runtime to call the service.
     // Here, we use the service's implementation of `Syscall`, where it simply calls itself :)
     let trussed_client = trussed_service.try_as_new_client(client_id).unwrap();
+    // the client's quota keeps it from filling up the state file shared with the other apps
+    let trussed_client = quota::Quota::new(trussed_client, store, client_id, client_quota);
+    // the client is decorated, to trace the syscalls the app makes, if asked for
+    let tracing = args.is_present("TRACE-TRUSSED");
+    let mut trussed_client = trace::Traced::new(trussed_client, tracing);
+    let statistics = trussed_client.statistics();
+    let _summary = Defer(|| if tracing { summarize(&statistics) });
+
+    if let Some(command) = secrets_command {
+        let mut secrets = secrets::Secrets::new(trussed_client);
+        secrets.set_presence_timeout(presence_timeout);
+        let recording = args.value_of("RECORD");
+        // recorded responses must not depend on the terminal
+        let style = match recording {
+            Some(_) => output::Style::plain(),
+            None => cli::style(&args)?,
+        };
+        let mut pipeline = pipeline(read_only, recording, hooks)?;
+        let result = pipeline.dispatch(command, &mut |command| respond_secrets(&mut secrets, command, &style));
+        sync_handle.sync()?;
+        output::result(&result?);
+        return Ok(());
+    }
+
+    // the storage is a concern of the runner, not of the app
+    let storage = args.subcommand_matches("storage");
+    if storage.and_then(|args| args.subcommand_matches("health")).is_some() {
+        let health = platform::store::health(store, &sync_handle)
+            .map_err(|e| anyhow::anyhow!("Could not inspect the state file: {:?}", e))?;
+        output::result(&format!("block size:   {} bytes\n", health.block_size));
+        output::result(&format!("blocks:       {} ({} free)\n", health.block_count, health.free_blocks));
+        for usage in health.clients.iter() {
+            let quota = match cli::quota(&args, &usage.client)? {
+                Some(quota) => format!(", quota {}", quota),
+                None => String::new(),
+            };
+            output::result(&format!("files:        {} in {} ({} bytes{})\n", usage.files, usage.client, usage.bytes, quota));
+        }
+        let erase_counts = &health.erase_counts;
+        output::result(&format!("erase cycles: {} total, {} max per block\n",
+            erase_counts.iter().map(|&count| count as u64).sum::<u64>(),
+            erase_counts.iter().max().unwrap_or(&0)));
+        return Ok(());
+    }
+
+    // so is looking into the state file, in its entirety
+    if let Some(args) = args.subcommand_matches("debug") {
+        if args.subcommand_matches("dump-state").is_some() {
+            output::result(&format!("{}\n", serde_json::to_string_pretty(&tutorial::dump::dump(store)?)?));
+        }
+        return Ok(());
+    }
+
+    // benchmarks are a concern of the runner, not of the app
+    if let Some(args) = args.subcommand_matches("bench") {
+        // no panic - clap enforces the value's existence and validity
+        let iterations = args.value_of("ITERATIONS").unwrap().parse()?;
+        // a directory of its own, removed with all files the flash keeps next to the scratch file
+        let scratch_dir = tempfile::Builder::new().prefix("trussed-totp-pc-tutorial-bench").tempdir()?;
+        let scratch_path = scratch_dir.path().join("scratch.littlefs2");
+        for cache in &[0, cache_blocks] {
+            for measurement in bench::flash(&scratch_path, iterations, *cache)? {
+                output::result(&format!("{}\n", measurement));
+            }
+        }
+        for measurement in bench::trussed(&mut trussed_client, iterations)? {
+            output::result(&format!("{}\n", measurement));
+        }
+        let batching = bench::authenticate_all(&mut trussed_client, iterations)?;
+        for measurement in batching.iter() {
+            output::result(&format!("{}\n", measurement));
+        }
+        output::result(&format!("{:<40} {:>10.1}x\n", "authenticate --all: speedup", batching[1].speedup_over(&batching[0])));
+        sync_handle.sync()?;
+        return Ok(());
+    }
 
     // setup authenticator
     let mut authenticator = authenticator::Authenticator::new(trussed_client);
+    // the app reports progress back to the runner, which could drive e.g. a spinner
+    // and what happened to the credentials, which runs hooks
+    let reported = hooks.reported();
+    authenticator.set_event_handler(move |event| {
+        debug!("authenticator event: {:?}", event);
+        match event {
+            authenticator::Event::Deleted { label } => reported.report(middleware::HookEvent::Deleted, &label),
+            authenticator::Event::FailureThreshold { label } => reported.report(middleware::HookEvent::FailureThreshold, &label),
+            _ => {}
+        }
+    });
+    authenticator.set_presence_timeout(presence_timeout);
+    authenticator.set_failure_threshold(failure_threshold);
+    authenticator.set_read_only(read_only);
 
+    // counters behind the record mean an old state file was restored, whose codes were used
+    // (a replay starts from a fresh state, of which there is no record)
+    let counters_path = rollback::path(&state_file);
+    let mut counters = match args.subcommand_matches("replay") {
+        Some(_) => None,
+        None => Some(rollback::Counters::load(&counters_path)?),
+    };
+    if let Some(counters) = counters.as_mut() {
+        let credentials = authenticator.counter_based();
+        let rolled_back = counters.rolled_back(&credentials);
+        for entry in rolled_back.iter() {
+            warn!("the counter of {} (slot {}) is behind the {} last seen", entry.label, entry.slot, entry.counter);
+        }
+        if !rolled_back.is_empty() {
+            if !args.is_present("ALLOW-ROLLBACK") {
+                return Err(anyhow::anyhow!(
+                    "The state file is older than the HOTP counters last seen, so codes would repeat; \
+                    resynchronize the counters with the servers and pass --allow-rollback"));
+            }
+            counters.accept(&credentials);
+            if !read_only {
+                counters.store(&counters_path)?;
+            }
+        }
+    }
+
+    // a policy or credentials changed outside the app mean the state file was edited
+    // (a replay starts from a fresh state, which has no MAC yet)
+    if counters.is_some() && !authenticator.verify_integrity() {
+        if !args.is_present("ACCEPT-MODIFIED-STATE") {
+            return Err(anyhow::anyhow!(
+                "The policy or credentials in the state file were changed outside the app; \
+                if that was you, pass --accept-modified-state and confirm your presence"));
+        }
+        authenticator.accept_integrity()?;
+        sync_handle.sync()?;
+    }
+
+    // collecting garbage takes both the app, knowing which keys are used, and the store
+    if storage.and_then(|args| args.subcommand_matches("gc")).is_some() {
+        use std::fmt::Write as _;
+        let command = runner::Command::try_from(&args)?;
+        let mut pipeline = pipeline(read_only, args.value_of("RECORD"), hooks.clone())?;
+        let response = pipeline.dispatch(command, &mut |_| {
+            let referenced: Vec<String> = authenticator.referenced_keys()?.iter()
+                .map(|key| String::from_utf8_lossy(&key.hex()).into_owned())
+                .collect();
+            let garbage = platform::store::collect_garbage(store, client_id, &referenced)
+                .map_err(|e| anyhow::anyhow!("Could not collect garbage: {:?}", e))?;
+            sync_handle.sync()?;
+            let mut response = String::new();
+            for name in garbage.removed.iter() {
+                writeln!(response, "removed: unreferenced key {}", name)?;
+            }
+            for name in garbage.missing.iter() {
+                writeln!(response, "missing: referenced key {}", name)?;
+            }
+            writeln!(response, "reclaimed {} blocks", garbage.reclaimed_blocks)?;
+            Ok(response)
+        })?;
+        output::result(&response);
+        return Ok(());
+    }
 
     // The "runner"'s actual "scheduling" part starts here
     info!("Let's go!");
+    let recording = args.value_of("RECORD");
+    // recorded responses must not depend on the terminal
+    let style = match recording {
+        Some(_) => output::Style::plain(),
+        None => cli::style(&args)?,
+    };
+    let style = match args.subcommand_matches("authenticate").map_or(false, |args| args.is_present("JSON")) {
+        true => style.json(),
+        false => style,
+    };
 
-    // the "args" come in over the CLI "interface", and are "deserialized" for processing
-    // using `Command`'s implementation of `TryFrom`, the standard Trait for fallible type conversion
-    let command = authenticator::Command::try_from(&args)?;
+    if let Some(args) = args.subcommand_matches("replay") {
+        // no panic - clap enforces the value's existence
+        let entries = transcript::read(args.value_of("transcript").unwrap())?;
+        let mut replaying = pipeline(read_only, None, middleware::Hooks::default())?;
+        for (i, entry) in entries.into_iter().enumerate() {
+            let result = dispatch(&mut authenticator, &mut replaying, entry.command.clone(), &output::Style::plain());
+            sync_handle.sync()?;
+            let replayed = transcript::Entry::new(&entry.command, &result);
+            if replayed == entry {
+                output::result(&format!("#{} {:?}: as recorded\n", i, entry.command));
+            } else {
+                output::result(&format!("#{} {:?}: recorded {:?}, replayed {:?}\n", i, entry.command, entry.response, replayed.response));
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(args) = args.subcommand_matches("batch") {
+        // no panic - clap enforces the value's existence
+        let commands = batch::read(args.value_of("batch").unwrap())?;
+        let mut pipeline = pipeline(read_only, recording, hooks.clone())?;
+        let mut failures = 0;
+        for (i, command) in commands.into_iter().enumerate() {
+            let result = dispatch(&mut authenticator, &mut pipeline, command, &style);
+            sync_handle.sync()?;
+            match result {
+                Ok(response) => output::result(&format!("#{}: ok\n{}", i, response)),
+                Err(error) => {
+                    failures += 1;
+                    output::result(&format!("#{}: failed: {}\n", i, error));
+                }
+            }
+        }
+        record_counters(&mut authenticator, counters.as_mut(), &counters_path, read_only)?;
+        if failures > 0 {
+            return Err(anyhow::anyhow!("{} commands of the batch failed", failures));
+        }
+        return Ok(());
+    }
+
+    if let Some(args) = args.subcommand_matches("provision") {
+        // no panic - clap enforces the value's existence
+        let path = std::path::Path::new(args.value_of("FILE").unwrap());
+        let provision = provision::read(path)?;
+        let listing = authenticator.list()?;
+        let policy = authenticator.policy()?;
+        let base_dir = path.parent().unwrap_or_else(|| std::path::Path::new(""));
+        let mut steps = provision.plan(&listing, &policy, base_dir, cli::now())?;
+
+        let mut pipeline = pipeline(read_only, recording, hooks.clone())?;
+        let mut failures = 0;
+        for step in steps.iter_mut() {
+            if let Some(command) = step.command.take() {
+                step.outcome = match dispatch(&mut authenticator, &mut pipeline, command, &style) {
+                    Ok(_) => "changed".into(),
+                    Err(error) => {
+                        failures += 1;
+                        format!("failed: {}", error)
+                    }
+                };
+                sync_handle.sync()?;
+            }
+        }
+        record_counters(&mut authenticator, counters.as_mut(), &counters_path, read_only)?;
+        // the summary is for machines
+        output::result(&format!("{}\n", serde_json::to_string_pretty(&steps)?));
+        if failures > 0 {
+            return Err(anyhow::anyhow!("{} steps of the provisioning failed", failures));
+        }
+        return Ok(());
+    }
+
+    if args.subcommand_matches("setup").is_some() {
+        if !new_state {
+            output::diagnostic(&format!("Using the existing state file at {}", state_file));
+        }
+        let mut pipeline = pipeline(read_only, recording, hooks.clone())?;
+        setup(&mut authenticator, &mut pipeline, &style)?;
+        sync_handle.sync()?;
+        output::diagnostic("Done. The state file is encrypted only with --encrypt-state, and user presence is");
+        output::diagnostic("confirmed as --ui says (stdin by default); pass these with each command.");
+        return Ok(());
+    }
+
+    // picking the command takes the app, knowing the labels
+    let command = match (command, args.subcommand_matches("pick")) {
+        (Some(command), _) => command,
+        // no panic - clap enforces the value's existence
+        (None, Some(args)) => pick(&mut authenticator, args.value_of("picker").unwrap())?,
+        (None, None) => return Err(anyhow::anyhow!("Unexpected case")),
+    };
+
+    // the backup is shown by the runner, so it never ends up in a response or transcript
+    let backup = match (&command, args.subcommand_matches("register")) {
+        (authenticator::Command::Register(register), Some(args)) if args.is_present("BACKUP-WORDS") => {
+            Some(tutorial::secret::SecretBytes::from_base32(&register.base32_secret)?.to_backup())
+        }
+        _ => None,
+    };
+
+    // typing the OTP is up to the runner, asking before it is generated (and counted)
+    let typing = args.subcommand_matches("authenticate").filter(|args| args.is_present("TYPE"));
+    #[cfg(not(feature = "autotype"))]
+    if typing.is_some() {
+        return Err(anyhow::anyhow!("Typing the OTP needs the `autotype` feature"));
+    }
+    #[cfg(feature = "autotype")]
+    if typing.map_or(false, |args| !args.is_present("YES")) && !tutorial::autotype::confirm(&style)? {
+        return Err(anyhow::anyhow!("Typing the OTP was not confirmed"));
+    }
 
     // the command is "dispatched" into the application
+    let result = dispatch(&mut authenticator, &mut pipeline(read_only, recording, hooks.clone())?, command, &style);
+
+    // write back what the command changed, even if it failed halfway
+    sync_handle.sync()?;
+    record_counters(&mut authenticator, counters.as_mut(), &counters_path, read_only)?;
+
+    #[cfg(feature = "autotype")]
+    if let Some(args) = typing {
+        // the accessible style spaces the OTP out
+        let otp: String = result?.split_whitespace().collect();
+        tutorial::autotype::type_text(&otp, cli::type_delay(args)?);
+        return Ok(());
+    }
+
+    // just the digits, even if the accessible style spaces them out
+    if quiet {
+        let otp: String = result?.split_whitespace().collect();
+        output::result(&otp);
+        return Ok(());
+    }
+
+    // the application response is "dispatched" back over the CLI
+    output::result(&result?);
+    if let Some(backup) = backup {
+        output::result(&format!("{}\n", style.message(Message::WriteDownBackup, &[&backup.expose()])));
+        output::diagnostic(&style.message(Message::RestoreBackup, &[]));
+    }
+    Ok(())
+}
+
+/// Runs a closure when dropped, i.e. on each way out of `main`
+struct Defer<F: FnMut()>(F);
+
+impl<F: FnMut()> Drop for Defer<F> {
+    fn drop(&mut self) {
+        (self.0)()
+    }
+}
+
+/// Moves the record of HOTP counters forward to the state's, which nothing can take back
+fn record_counters<T>(
+    authenticator: &mut authenticator::Authenticator<T>,
+    counters: Option<&mut rollback::Counters>,
+    path: &std::path::Path,
+    read_only: bool,
+) -> Result<()>
+where
+    T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
+{
+    match counters {
+        Some(counters) if !read_only => {
+            let before = counters.clone();
+            counters.record(&authenticator.counter_based());
+            if *counters != before {
+                counters.store(path)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Prints the aggregated statistics of all traced syscalls
+fn summarize(statistics: &trace::Statistics) {
+    output::diagnostic(&format!("{:<24}{:>8}{:>8}{:>14}", "syscall", "count", "errors", "total"));
+    for (name, syscall) in statistics.borrow().iter() {
+        output::diagnostic(&format!("{:<24}{:>8}{:>8}{:>14?}", name, syscall.count, syscall.errors, syscall.total));
+    }
+}
+
+/// Lets the user pick one of the registered labels with an external picker,
+/// resulting in the command to authenticate with it
+fn pick<T>(authenticator: &mut authenticator::Authenticator<T>, picker: &str) -> Result<authenticator::Command>
+where
+    T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
+{
+    use std::io::Write as _;
+    use std::process::{Command, Stdio};
+
+    let labels = dispatch(authenticator, &mut pipeline(false, None, Vec::new())?, authenticator::Command::Labels, &output::Style::plain())?;
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(picker)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Could not run picker `{}`: {}", picker, e))?;
+    // no panic - stdin is piped
+    child.stdin.take().unwrap().write_all(labels.as_bytes())?;
+    let output = child.wait_with_output()?;
+
+    let label = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || label.is_empty() {
+        return Err(anyhow::anyhow!("No label was picked"));
+    }
+
+    Ok(authenticator::Command::Authenticate(authenticator::Authenticate {
+        label,
+        slot: None,
+        timestamp: cli::now(),
+    }))
+}
+
+/// The layers commands (of either app) pass through: hooks and the transcript (if recording),
+/// so refused commands are seen by both, then refusing changes (if read-only), then logging
+fn pipeline<C: middleware::Dispatch>(
+    read_only: bool,
+    recording: Option<&str>,
+    hooks: middleware::Hooks,
+) -> Result<middleware::Pipeline<C>> {
+    // even without hooks, so the events reported for each command are taken with it
+    let mut pipeline = middleware::Pipeline::new().layer(hooks);
+    if let Some(transcript) = recording {
+        pipeline = pipeline.layer(middleware::Recording(transcript::Recorder::open(transcript)?));
+    }
+    if read_only {
+        pipeline = pipeline.layer(middleware::ReadOnly);
+    }
+    Ok(pipeline.layer(middleware::Logging))
+}
+
+/// Asks which commands require user presence, and changes the policy accordingly,
+/// through the app's commands (so each change is confirmed, and recorded if asked for)
+fn setup<T>(
+    authenticator: &mut authenticator::Authenticator<T>,
+    pipeline: &mut middleware::Pipeline,
+    style: &output::Style,
+) -> Result<()>
+where
+    T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
+{
+    let current = authenticator.policy()?;
+    let mut changes = Vec::new();
+    for action in policy::Action::ALL.iter().copied() {
+        let presence = current.requirement(action) == policy::Requirement::Presence;
+        let question = format!("Require user presence for `{}`?", action.name());
+        let requirement = match ask(style, &question, presence)? {
+            true => policy::Requirement::Presence,
+            false => policy::Requirement::Nothing,
+        };
+        if requirement != current.requirement(action) {
+            changes.push(authenticator::SetPolicy { action, requirement });
+        }
+    }
+
+    if !changes.is_empty() {
+        output::diagnostic("Changing the policy requires confirming your presence, once per change.");
+    }
+    for change in changes {
+        dispatch(authenticator, pipeline, authenticator::Command::SetPolicy(change), style)?;
+    }
+    output::result(&dispatch(authenticator, pipeline, authenticator::Command::ShowPolicy, style)?);
+    Ok(())
+}
+
+/// Asks a yes/no question on stderr, reading the answer from stdin
+fn ask(style: &output::Style, question: &str, default: bool) -> Result<bool> {
+    use std::io::BufRead as _;
+    output::prompt(&style.question(question, default))?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(match answer.trim() {
+        "y" | "Y" | "yes" => true,
+        "n" | "N" | "no" => false,
+        _ => default,
+    })
+}
+
+/// Dispatches a `Command` through the pipeline into the app
+fn dispatch<T>(
+    authenticator: &mut authenticator::Authenticator<T>,
+    pipeline: &mut middleware::Pipeline,
+    command: authenticator::Command,
+    style: &output::Style,
+) -> Result<String>
+where
+    T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
+{
+    let result = pipeline.dispatch(command, &mut |command| respond(authenticator, command, style));
+    // even if the command failed halfway, what it changed is the app's doing
+    authenticator.update_integrity()?;
+    result
+}
+
+/// Processes a `Command` in the app, and formats its response for the CLI
+fn respond<T>(authenticator: &mut authenticator::Authenticator<T>, command: authenticator::Command, style: &output::Style) -> Result<String>
+where
+    T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
+{
+    use std::fmt::Write as _;
+    let mut response = String::new();
+
     match command {
         authenticator::Command::Register(register) => {
             authenticator.register(&register)?;
         }
         authenticator::Command::Authenticate(authenticate) => {
-            let otp = authenticator.authenticate(&authenticate)?;
+            let authenticated = authenticator.authenticate(&authenticate)?;
 
-            // the application response is "dispatched" back over the CLI
-            println!("{}", &otp);
+            match style.is_json() {
+                true => writeln!(response, "{}", serde_json::to_string(&authenticated)?)?,
+                false => writeln!(response, "{}", style.code(&authenticated.otp.0))?,
+            }
+        }
+        authenticator::Command::AuthenticateAll(authenticate_all) => {
+            let otps = authenticator.authenticate_all(&authenticate_all)?;
+            if style.is_json() {
+                writeln!(response, "{}", serde_json::to_string(&otps)?)?;
+                return Ok(response);
+            }
+            let mut table = output::Table::new();
+            for otp in otps.iter() {
+                let label = match otp.slot {
+                    0 => otp.label.clone(),
+                    slot => style.message(Message::SlottedLabel, &[&otp.label, &slot]),
+                };
+                // all of these are time-based, so they expire
+                let remaining = otp.authenticated.remaining_seconds.unwrap_or(0);
+                table.row(vec![
+                    (label, output::Paint::Plain),
+                    (style.code(&otp.authenticated.otp.0), output::Paint::Bold),
+                    (style.message(Message::Remaining, &[&remaining]), output::Paint::Dim),
+                ]);
+            }
+            response.push_str(&table.render(style));
+        }
+        authenticator::Command::Precompute(precompute) => {
+            let windows = authenticator.precompute(&precompute)?;
+            let until = windows.last().map_or(precompute.timestamp, |window| window.end);
+
+            // marked as precomputed, so they are not mistaken for codes from the authenticator
+            if style.is_json() {
+                let precomputed = serde_json::json!({ "precomputed": true, "codes": windows });
+                writeln!(response, "{}", serde_json::to_string(&precomputed)?)?;
+                return Ok(response);
+            }
+            writeln!(response, "{}", style.paint(output::Paint::Red, &style.message(Message::Precomputed, &[&datetime(until)])))?;
+            let mut table = output::Table::new();
+            for window in windows.iter() {
+                table.row(vec![
+                    (style.code(&window.otp.0), output::Paint::Bold),
+                    (style.message(Message::ValidFromTo, &[&datetime(window.start), &datetime(window.end)]), output::Paint::Plain),
+                ]);
+            }
+            response.push_str(&table.render(style));
+        }
+        authenticator::Command::Verify(verify) => {
+            if !authenticator.verify(&verify)? {
+                return Err(anyhow::anyhow!(style.message(Message::InvalidOtp, &[])));
+            }
+            writeln!(response, "{}", style.paint(output::Paint::Green, &style.message(Message::Valid, &[])))?;
+        }
+        authenticator::Command::List => {
+            let listing = authenticator.list()?;
+            let catalog = authenticator.issuers()?;
+            let mut table = output::Table::new();
+            for credential in listing.credentials.iter() {
+                let last_used = match credential.last_used() {
+                    Some(timestamp) => (style.message(Message::LastUsed, &[&date(timestamp)]), output::Paint::Plain),
+                    None => (style.message(Message::LastUsedNever, &[]), output::Paint::Dim),
+                };
+                let mut row = vec![
+                    (slotted_label(credential, style), output::Paint::Bold),
+                    (style.message(Message::Created, &[&date(credential.created_at())]), output::Paint::Plain),
+                    last_used,
+                ];
+                // last, so the columns before stay where scripts expect them
+                if let Some(issuer) = credential.issuer() {
+                    row.push((catalog.name(issuer).into(), output::Paint::Plain));
+                }
+                table.row(row);
+            }
+            response.push_str(&table.render(style));
+            for filename in listing.corrupted.iter() {
+                let corrupted = style.paint(output::Paint::Red, &style.message(Message::Corrupted, &[]));
+                writeln!(response, "{}", style.message(Message::UseRepair, &[&corrupted, filename]))?;
+            }
+        }
+        authenticator::Command::Labels => {
+            let mut labels: Vec<String> = authenticator.list()?.credentials.iter()
+                .map(|credential| credential.label().into())
+                .collect();
+            // credentials in several slots share their label
+            labels.sort();
+            labels.dedup();
+            for label in labels.iter() {
+                writeln!(response, "{}", label)?;
+            }
+        }
+        authenticator::Command::Prune(prune) => {
+            let stale = authenticator.prune(&prune)?;
+            let message = if prune.delete { Message::Deleted } else { Message::Stale };
+            for credential in stale.iter() {
+                let last_used = credential.last_used().unwrap_or(credential.created_at());
+                writeln!(response, "{}",
+                    style.message(message, &[&credential.label(), &credential.slot(), &date(last_used)]))?;
+            }
+        }
+        authenticator::Command::Repair(repair) => {
+            let repaired = authenticator.repair(&repair)?;
+            for filename in repaired.iter() {
+                let message = if repair.delete { Message::DeletedFile } else { Message::Quarantined };
+                writeln!(response, "{}", style.message(message, &[filename]))?;
+            }
+        }
+        authenticator::Command::ShowPolicy => {
+            let policy = authenticator.policy()?;
+            let mut table = output::Table::new();
+            for action in policy::Action::ALL.iter() {
+                table.row(vec![
+                    (action.name().into(), output::Paint::Bold),
+                    (policy.requirement(*action).to_string(), output::Paint::Plain),
+                ]);
+            }
+            // aligned even when piped, as before tables
+            response.push_str(&table.render(&style.aligned()));
+        }
+        authenticator::Command::SetPolicy(set_policy) => {
+            authenticator.set_policy(&set_policy)?;
+        }
+        authenticator::Command::ShowIssuers => {
+            let mut table = output::Table::new();
+            for (issuer, overridden) in authenticator.issuers()?.issuers.iter() {
+                let source = match overridden {
+                    true => (style.message(Message::Overridden, &[]), output::Paint::Plain),
+                    false => (style.message(Message::BuiltIn, &[]), output::Paint::Dim),
+                };
+                table.row(vec![
+                    (issuer.id.clone(), output::Paint::Bold),
+                    (issuer.name.clone(), output::Paint::Plain),
+                    (issuer.icon.clone(), output::Paint::Plain),
+                    source,
+                ]);
+            }
+            response.push_str(&table.render(style));
+        }
+        authenticator::Command::SetIssuer(set_issuer) => {
+            authenticator.set_issuer(&set_issuer)?;
+        }
+        authenticator::Command::ResetIssuer(reset_issuer) => {
+            authenticator.reset_issuer(&reset_issuer)?;
+        }
+        authenticator::Command::Trash(trash) => {
+            let mut table = output::Table::new();
+            for trashed in authenticator.trash(&trash)?.iter() {
+                let credential = trashed.credential();
+                table.row(vec![
+                    (style.message(Message::SlottedLabel, &[&credential.label(), &credential.slot()]), output::Paint::Bold),
+                    (style.message(Message::DeletedAt, &[&date(trashed.deleted_at())]), output::Paint::Plain),
+                    (style.message(Message::PurgedAfter, &[&date(trashed.purged_at())]), output::Paint::Dim),
+                ]);
+            }
+            response.push_str(&table.render(style));
+        }
+        authenticator::Command::Undelete(undelete) => {
+            let credential = authenticator.undelete(&undelete)?;
+            writeln!(response, "{}", style.message(Message::Restored, &[&credential.label(), &credential.slot()]))?;
+        }
+        authenticator::Command::AddRecoveryCodes(add_recovery_codes) => {
+            authenticator.add_recovery_codes(&add_recovery_codes)?;
+        }
+        authenticator::Command::ShowRecoveryCodes(show_recovery_codes) => {
+            let mut table = output::Table::new();
+            for code in authenticator.show_recovery_codes(&show_recovery_codes)?.iter() {
+                // used codes are of no use anymore
+                table.row(match code.used_at() {
+                    Some(timestamp) => vec![
+                        (code.code().expose().into(), output::Paint::Dim),
+                        (style.message(Message::Used, &[&date(timestamp)]), output::Paint::Dim),
+                    ],
+                    None => vec![
+                        (code.code().expose().into(), output::Paint::Bold),
+                        (style.message(Message::Unused, &[]), output::Paint::Plain),
+                    ],
+                });
+            }
+            response.push_str(&table.render(style));
+        }
+        authenticator::Command::UseRecoveryCode(use_recovery_code) => {
+            let code = authenticator.use_recovery_code(&use_recovery_code)?;
+            writeln!(response, "{}", code.code().expose())?;
+        }
+        authenticator::Command::RegisterChallengeResponse(register) => {
+            authenticator.register_challenge_response(&register)?;
+        }
+        authenticator::Command::RespondToChallenge(respond) => {
+            writeln!(response, "{}", output::hex(&authenticator.respond_to_challenge(&respond)?))?;
+        }
+        authenticator::Command::DeleteChallengeResponse(delete) => {
+            authenticator.delete_challenge_response(&delete)?;
+        }
+        authenticator::Command::Stats(stats) => {
+            let mut credentials = authenticator.list()?.credentials;
+            // most used first
+            credentials.sort_by(|a, b| b.usage().count().cmp(&a.usage().count())
+                .then_with(|| a.label().cmp(b.label())));
+            let (mut count, mut this_week, mut failures) = (0u64, 0u64, 0u64);
+            let mut table = output::Table::new();
+            let failures_paint = |failures| match failures {
+                0 => output::Paint::Plain,
+                _ => output::Paint::Red,
+            };
+            for credential in credentials.iter() {
+                let usage = credential.usage();
+                let week = usage.count_in_week_of(stats.timestamp);
+                table.row(vec![
+                    (slotted_label(credential, style), output::Paint::Plain),
+                    (style.message(Message::Otps, &[&usage.count()]), output::Paint::Plain),
+                    (style.message(Message::ThisWeek, &[&week]), output::Paint::Plain),
+                    (style.message(Message::Failures, &[&usage.failures()]), failures_paint(usage.failures() as u64)),
+                ]);
+                count += usage.count() as u64;
+                this_week += week as u64;
+                failures += usage.failures() as u64;
+            }
+            table.row(vec![
+                (style.message(Message::Total, &[]), output::Paint::Bold),
+                (style.message(Message::Otps, &[&count]), output::Paint::Bold),
+                (style.message(Message::ThisWeek, &[&this_week]), output::Paint::Bold),
+                (style.message(Message::Failures, &[&failures]), failures_paint(failures)),
+            ]);
+            response.push_str(&table.render(style));
         }
     }
 
-    Ok(())
+    Ok(response)
+}
+
+/// Processes a command in the secrets app, and formats its response for the CLI
+fn respond_secrets<T>(secrets: &mut secrets::Secrets<T>, command: secrets::Command, style: &output::Style) -> Result<String>
+where
+    T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
+{
+    use std::fmt::Write as _;
+    let mut response = String::new();
+
+    match command {
+        secrets::Command::Add(add) => {
+            secrets.add(&add)?;
+        }
+        secrets::Command::Get(get) => {
+            writeln!(response, "{}", secrets.get(&get)?.expose())?;
+        }
+        secrets::Command::List => {
+            let mut table = output::Table::new();
+            for item in secrets.list()?.iter() {
+                table.row(vec![
+                    (item.name().into(), output::Paint::Bold),
+                    (style.message(Message::Requires, &[&item.requirement()]), output::Paint::Plain),
+                    (style.message(Message::Created, &[&date(item.created_at())]), output::Paint::Dim),
+                ]);
+            }
+            response.push_str(&table.render(style));
+        }
+        secrets::Command::Delete(delete) => {
+            secrets.delete(&delete)?;
+        }
+    }
+
+    Ok(response)
+}
+
+/// The label of a credential, with its slot unless it is the default one,
+/// its location unless it is the internal one, and its algorithm unless it is TOTP
+fn slotted_label(credential: &authenticator::Credential, style: &output::Style) -> String {
+    let mut label = match credential.slot() {
+        0 => credential.label().into(),
+        slot => style.message(Message::SlottedLabel, &[&credential.label(), &slot]),
+    };
+    if credential.location() != trussed::types::Location::Internal {
+        label.push_str(&format!(" ({:?})", credential.location()).to_lowercase());
+    }
+    let algorithm = credential.parameters().algorithm();
+    if algorithm != algorithm::DEFAULT_ALGORITHM {
+        label.push_str(&format!(" ({})", algorithm));
+    }
+    label
+}
+
+/// Formats a timestamp (seconds since UNIX epoch) as a UTC date and time, e.g. `2021-03-14 15:09:26`
+fn datetime(timestamp: u64) -> String {
+    let seconds = timestamp % 86_400;
+    format!("{} {:02}:{:02}:{:02}", date(timestamp), seconds / 3_600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Formats a timestamp (seconds since UNIX epoch) as a UTC date, e.g. `2021-03-14`
+fn date(timestamp: u64) -> String {
+    // cf. http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (timestamp / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
 }
@@ -0,0 +1,163 @@
+//! The catalog of user-facing messages, in English and German.
+//!
+//! Responses are formatted by the runner, which looks up each message by its key in the
+//! language of the output `Style`. Templates have `{}` placeholders, filled in order, so
+//! translations are free to move the words around them. Transcripts and piped output of
+//! scripts are in English, whatever the locale, as they are matched against.
+
+use core::fmt::Display;
+use core::str::FromStr;
+
+/// Languages of the messages, as passed with `--lang`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lang {
+    /// English, the default
+    En,
+    /// German
+    De,
+}
+
+impl FromStr for Lang {
+    type Err = anyhow::Error;
+
+    /// Parses `en` or `de`, as well as locales such as `de_DE.UTF-8`
+    fn from_str(s: &str) -> crate::Result<Self> {
+        let language = s.split(|c| c == '_' || c == '-' || c == '.').next().unwrap_or("");
+        match language {
+            "en" | "C" | "POSIX" => Ok(Self::En),
+            "de" => Ok(Self::De),
+            _ => Err(anyhow::anyhow!("unknown language {:?}, expected en or de", s)),
+        }
+    }
+}
+
+impl Lang {
+    /// Detects the language of the locale, cf. `LC_ALL`, `LC_MESSAGES` and `LANG`, falling
+    /// back to English for unset or unsupported locales
+    pub fn detect() -> Self {
+        ["LC_ALL", "LC_MESSAGES", "LANG"].iter()
+            .filter_map(|variable| std::env::var(variable).ok())
+            .find(|value| !value.is_empty())
+            .and_then(|locale| locale.parse().ok())
+            .unwrap_or(Lang::En)
+    }
+}
+
+/// Keys of the messages
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(missing_docs)]
+pub enum Message {
+    Valid,
+    InvalidOtp,
+    Precomputed,
+    ValidFromTo,
+    Remaining,
+    Created,
+    LastUsed,
+    LastUsedNever,
+    Corrupted,
+    UseRepair,
+    Stale,
+    Deleted,
+    DeletedFile,
+    DeletedAt,
+    Quarantined,
+    Restored,
+    SlottedLabel,
+    PurgedAfter,
+    Used,
+    Unused,
+    Otps,
+    ThisWeek,
+    Failures,
+    Total,
+    Requires,
+    WriteDownBackup,
+    RestoreBackup,
+    BuiltIn,
+    Overridden,
+}
+
+impl Message {
+    fn template(self, lang: Lang) -> &'static str {
+        use Message::*;
+        match lang {
+            Lang::En => match self {
+                Valid => "valid",
+                InvalidOtp => "The OTP is not valid",
+                Precomputed => "PRECOMPUTED codes, usable without the authenticator until {} UTC; keep them like the secret",
+                ValidFromTo => "valid {} to {}",
+                Remaining => "{}s left",
+                Created => "created {}",
+                LastUsed => "last used {}",
+                LastUsedNever => "last used never",
+                Corrupted => "corrupted:",
+                UseRepair => "{} {} (use `repair` to quarantine it)",
+                Stale => "stale: {} (slot {}, last used {})",
+                Deleted => "deleted: {} (slot {}, last used {})",
+                DeletedFile => "deleted: {}",
+                DeletedAt => "deleted {}",
+                Quarantined => "quarantined: {}",
+                Restored => "restored: {} (slot {})",
+                SlottedLabel => "{} (slot {})",
+                PurgedAfter => "purged after {}",
+                Used => "used {}",
+                Unused => "unused",
+                Otps => "{} OTPs",
+                ThisWeek => "{} this week",
+                Failures => "{} failures",
+                Total => "total",
+                Requires => "requires {}",
+                WriteDownBackup => "Write down this backup, it is not shown again: {}",
+                RestoreBackup => "Restore it with `register --from-words`.",
+                BuiltIn => "built-in",
+                Overridden => "overridden",
+            },
+            Lang::De => match self {
+                Valid => "gültig",
+                InvalidOtp => "Das OTP ist nicht gültig",
+                Precomputed => "VORAUSBERECHNETE Codes, ohne den Authenticator verwendbar bis {} UTC; wie das Geheimnis aufbewahren",
+                ValidFromTo => "gültig {} bis {}",
+                Remaining => "noch {}s",
+                Created => "erstellt {}",
+                LastUsed => "zuletzt verwendet {}",
+                LastUsedNever => "nie verwendet",
+                Corrupted => "beschädigt:",
+                UseRepair => "{} {} (`repair` stellt die Datei unter Quarantäne)",
+                Stale => "veraltet: {} (Slot {}, zuletzt verwendet {})",
+                Deleted => "gelöscht: {} (Slot {}, zuletzt verwendet {})",
+                DeletedFile => "gelöscht: {}",
+                DeletedAt => "gelöscht {}",
+                Quarantined => "unter Quarantäne: {}",
+                Restored => "wiederhergestellt: {} (Slot {})",
+                SlottedLabel => "{} (Slot {})",
+                PurgedAfter => "endgültig gelöscht nach {}",
+                Used => "verwendet {}",
+                Unused => "unverwendet",
+                Otps => "{} OTPs",
+                ThisWeek => "{} diese Woche",
+                Failures => "{} Fehlschläge",
+                Total => "gesamt",
+                Requires => "erfordert {}",
+                WriteDownBackup => "Notieren Sie diese Sicherung, sie wird nicht erneut angezeigt: {}",
+                RestoreBackup => "Wiederherstellen mit `register --from-words`.",
+                BuiltIn => "eingebaut",
+                Overridden => "angepasst",
+            },
+        }
+    }
+
+    /// The message in the language, with its placeholders filled in order
+    pub fn format(self, lang: Lang, args: &[&dyn Display]) -> String {
+        let mut args = args.iter();
+        let mut parts = self.template(lang).split("{}");
+        let mut formatted = String::from(parts.next().unwrap_or(""));
+        for part in parts {
+            if let Some(arg) = args.next() {
+                formatted.push_str(&arg.to_string());
+            }
+            formatted.push_str(part);
+        }
+        formatted
+    }
+}
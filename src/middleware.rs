@@ -0,0 +1,332 @@
+//! Layers that dispatched commands pass through on their way to the app.
+//!
+//! Concerns of the runner rather than of the app, such as logging, read-only mode,
+//! recording transcripts or running hooks, are each a `Layer`. A `Pipeline` chains them,
+//! in the order they were added, so runners compose the ones they need instead of
+//! hand-coding them around each dispatch. What must hold whichever runner drives the app, such as the policy,
+//! stays in the app, which enforces it through Trussed.
+
+use core::cell::RefCell;
+use core::str::FromStr;
+use std::rc::Rc;
+
+use log::{info, warn};
+
+use crate::authenticator::Command;
+use crate::runner;
+use crate::secrets;
+use crate::transcript::Recorder;
+use crate::Result;
+
+/// What the layers need to know about the commands of an app
+///
+/// The commands of both apps, and of the runner itself, pass through a pipeline, so each
+/// runs the same hooks, and is refused or recorded the same way.
+pub trait Dispatch: Clone + core::fmt::Debug + serde::Serialize {
+    /// The Trussed client of the app, which tells its commands apart in transcripts
+    const APP: &'static str;
+    /// The name of the corresponding CLI subcommand, e.g. `chalresp respond`
+    fn name(&self) -> &'static str;
+    /// The label (or name) of what the command is about, if any
+    fn label(&self) -> Option<&str>;
+    /// Whether the command changes stored state, i.e. is refused in read-only mode
+    fn is_mutating(&self) -> bool;
+    /// The command with its secrets replaced by dummies, as transcripts record it
+    fn redacted(&self) -> Self;
+    /// Whether a successful response reveals secrets, so transcripts must redact it
+    fn reveals_secrets(&self) -> bool;
+    /// The events of the command, if it succeeded
+    fn events(&self) -> Vec<HookEvent>;
+}
+
+impl Dispatch for Command {
+    const APP: &'static str = "totp";
+    fn name(&self) -> &'static str {
+        Command::name(self)
+    }
+    fn label(&self) -> Option<&str> {
+        Command::label(self)
+    }
+    fn is_mutating(&self) -> bool {
+        Command::is_mutating(self)
+    }
+    fn redacted(&self) -> Self {
+        Command::redacted(self)
+    }
+    fn reveals_secrets(&self) -> bool {
+        matches!(self, Command::ShowRecoveryCodes(_) | Command::UseRecoveryCode(_))
+    }
+    fn events(&self) -> Vec<HookEvent> {
+        // deletions are reported by the authenticator, cf. `authenticator::Event::Deleted`
+        match self {
+            Command::SetPolicy(_) => vec![HookEvent::PolicyChanged],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Dispatch for secrets::Command {
+    const APP: &'static str = secrets::CLIENT_ID;
+    fn name(&self) -> &'static str {
+        match self {
+            secrets::Command::Add(_) => "secret add",
+            secrets::Command::Get(_) => "secret get",
+            secrets::Command::List => "secret list",
+            secrets::Command::Delete(_) => "secret delete",
+        }
+    }
+    fn label(&self) -> Option<&str> {
+        match self {
+            secrets::Command::Add(secrets::Add { name, .. }) | secrets::Command::Get(secrets::Get { name })
+            | secrets::Command::Delete(secrets::Delete { name }) => Some(name),
+            secrets::Command::List => None,
+        }
+    }
+    fn is_mutating(&self) -> bool {
+        secrets::Command::is_mutating(self)
+    }
+    fn redacted(&self) -> Self {
+        secrets::Command::redacted(self)
+    }
+    fn reveals_secrets(&self) -> bool {
+        matches!(self, secrets::Command::Get(_))
+    }
+    fn events(&self) -> Vec<HookEvent> {
+        match self {
+            secrets::Command::Delete(_) => vec![HookEvent::Deleted],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Dispatch for runner::Command {
+    const APP: &'static str = "runner";
+    fn name(&self) -> &'static str {
+        runner::Command::name(self)
+    }
+    fn label(&self) -> Option<&str> {
+        None
+    }
+    fn is_mutating(&self) -> bool {
+        runner::Command::is_mutating(self)
+    }
+    fn redacted(&self) -> Self {
+        self.clone()
+    }
+    fn reveals_secrets(&self) -> bool {
+        false
+    }
+    fn events(&self) -> Vec<HookEvent> {
+        Vec::new()
+    }
+}
+
+/// The rest of the pipeline, ending in the app
+pub type Next<'a, C = Command> = &'a mut dyn FnMut(C) -> Result<String>;
+
+/// A step of the pipeline, which may inspect, refuse or pass on a command
+pub trait Layer<C = Command> {
+    /// Handles the command, usually by passing it on to `next` and returning its response
+    fn handle(&mut self, command: C, next: Next<'_, C>) -> Result<String>;
+}
+
+/// Layers in the order commands pass through them
+pub struct Pipeline<C = Command> {
+    layers: Vec<Box<dyn Layer<C>>>,
+}
+
+impl<C> Default for Pipeline<C> {
+    fn default() -> Self {
+        Self { layers: Vec::new() }
+    }
+}
+
+impl<C> Pipeline<C> {
+    /// Constructor, for a pipeline passing commands straight to the app
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a layer, which sees commands after the layers added before
+    pub fn layer(mut self, layer: impl Layer<C> + 'static) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    /// Passes the command through all layers, and then to the app
+    pub fn dispatch(&mut self, command: C, app: Next<'_, C>) -> Result<String> {
+        run(&mut self.layers, command, app)
+    }
+}
+
+fn run<C>(layers: &mut [Box<dyn Layer<C>>], command: C, app: Next<'_, C>) -> Result<String> {
+    match layers.split_first_mut() {
+        Some((layer, rest)) => layer.handle(command, &mut |command| run(rest, command, app)),
+        None => app(command),
+    }
+}
+
+/// Logs each command, and how long it took
+pub struct Logging;
+
+impl<C: Dispatch> Layer<C> for Logging {
+    fn handle(&mut self, command: C, next: Next<'_, C>) -> Result<String> {
+        // secrets in commands are redacted by their `Debug` implementation
+        info!("dispatching {:?}", command);
+        let start = std::time::Instant::now();
+        let result = next(command);
+        match &result {
+            Ok(_) => info!("dispatched in {:?}", start.elapsed()),
+            Err(error) => warn!("failed after {:?}: {}", start.elapsed(), error),
+        }
+        result
+    }
+}
+
+/// Refuses commands that change the state, cf. `Dispatch::is_mutating`
+pub struct ReadOnly;
+
+impl<C: Dispatch> Layer<C> for ReadOnly {
+    fn handle(&mut self, command: C, next: Next<'_, C>) -> Result<String> {
+        if command.is_mutating() {
+            warn!("refusing {:?} in read-only mode", command);
+            return Err(ReadOnlyRefusal("This command changes the state".into()).into());
+        }
+        next(command)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}, which --read-only forbids")]
+/// The error of commands refused in read-only mode, by `ReadOnly` or the app itself,
+/// which hooks don't take for a failure
+pub struct ReadOnlyRefusal(pub String);
+
+/// Appends each command, and its outcome, to a transcript
+pub struct Recording(pub Recorder);
+
+impl<C: Dispatch> Layer<C> for Recording {
+    fn handle(&mut self, command: C, next: Next<'_, C>) -> Result<String> {
+        let result = next(command.clone());
+        self.0.record(&command, &result)?;
+        result
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Security events that hooks can run on
+pub enum HookEvent {
+    /// A command failed, e.g. an invalid OTP, or denied user presence
+    Failure,
+    /// The failures with a credential reached the threshold, cf. `Authenticator::set_failure_threshold`
+    FailureThreshold,
+    /// A credential, key or secret was deleted, or replaced by a new one
+    Deleted,
+    /// The policy was changed
+    PolicyChanged,
+}
+
+impl HookEvent {
+    /// The name hooks are configured with, e.g. `failure`
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Failure => "failure",
+            HookEvent::FailureThreshold => "failure-threshold",
+            HookEvent::Deleted => "deleted",
+            HookEvent::PolicyChanged => "policy-changed",
+        }
+    }
+
+    /// The events of a dispatched command, cf. `Dispatch::events`
+    fn of<C: Dispatch>(command: &C, result: &Result<String>) -> Vec<Self> {
+        match result {
+            Ok(_) => command.events(),
+            // asked for, so nothing went wrong
+            Err(error) if error.is::<ReadOnlyRefusal>() => Vec::new(),
+            Err(_) => vec![HookEvent::Failure],
+        }
+    }
+}
+
+impl FromStr for HookEvent {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        [HookEvent::Failure, HookEvent::FailureThreshold, HookEvent::Deleted, HookEvent::PolicyChanged].iter().copied()
+            .find(|event| event.name() == s)
+            .ok_or_else(|| anyhow::anyhow!(
+                "Unknown event {}, expected one of: failure, failure-threshold, deleted, policy-changed", s))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+/// Events the app reports while processing a command, each about what the label names,
+/// e.g. every credential a `prune` deleted
+pub struct Reported(Rc<RefCell<Vec<(HookEvent, String)>>>);
+
+impl Reported {
+    /// Reports an event of the command being processed
+    pub fn report(&self, event: HookEvent, label: &str) {
+        self.0.borrow_mut().push((event, label.into()));
+    }
+
+    fn take(&self) -> Vec<(HookEvent, String)> {
+        core::mem::take(&mut *self.0.borrow_mut())
+    }
+}
+
+#[derive(Clone, Default)]
+/// Runs shell commands on security events, passing the details in environment variables:
+/// `TOTP_EVENT`, `TOTP_COMMAND` (e.g. `verify`), `TOTP_LABEL` and `TOTP_ERROR`, if any
+///
+/// The events are those of the command (cf. `Dispatch::events`), followed by those the app
+/// reported while processing it (cf. `Hooks::reported`).
+/// Hooks can't change the outcome of the command; if they fail, that is only logged.
+pub struct Hooks {
+    hooks: Vec<(HookEvent, String)>,
+    reported: Reported,
+}
+
+impl Hooks {
+    /// Constructor, from the shell commands to run on each event
+    pub fn new(hooks: Vec<(HookEvent, String)>) -> Self {
+        Self { hooks, reported: Reported::default() }
+    }
+
+    /// Where the app reports events, shared by the clones of these hooks
+    pub fn reported(&self) -> Reported {
+        self.reported.clone()
+    }
+}
+
+impl<C: Dispatch> Layer<C> for Hooks {
+    fn handle(&mut self, command: C, next: Next<'_, C>) -> Result<String> {
+        let result = next(command.clone());
+        let mut events: Vec<(HookEvent, Option<String>)> = HookEvent::of(&command, &result).into_iter()
+            .map(|event| (event, command.label().map(String::from)))
+            .collect();
+        events.extend(self.reported.take().into_iter().map(|(event, label)| (event, Some(label))));
+        for (event, label) in events {
+            for (_, hook) in self.hooks.iter().filter(|(hooked, _)| *hooked == event) {
+                let mut process = std::process::Command::new("sh");
+                process.arg("-c").arg(hook)
+                    .env("TOTP_EVENT", event.name())
+                    .env("TOTP_COMMAND", command.name())
+                    .stdin(std::process::Stdio::null())
+                    // the response goes to stdout
+                    .stdout(std::process::Stdio::null());
+                if let Some(label) = &label {
+                    process.env("TOTP_LABEL", label);
+                }
+                if let Err(error) = &result {
+                    process.env("TOTP_ERROR", error.to_string());
+                }
+                match process.status() {
+                    Ok(status) if status.success() => info!("ran {} hook `{}`", event.name(), hook),
+                    Ok(status) => warn!("{} hook `{}` failed: {}", event.name(), hook, status),
+                    Err(error) => warn!("could not run {} hook `{}`: {}", event.name(), hook, error),
+                }
+            }
+        }
+        result
+    }
+}
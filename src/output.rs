@@ -0,0 +1,251 @@
+//! Formatting of the CLI's responses for humans.
+//!
+//! Tables are aligned and colored on a terminal. When the output goes to a pipe or
+//! is recorded, they are printed as tab-separated values, so scripts and transcripts
+//! don't depend on where they were produced. For the same reason, messages are in the
+//! locale's language only on a terminal, unless `--lang` asks for one.
+//!
+//! The accessible style (`--accessible`) is meant for screen readers: no colors, OTPs in
+//! groups of digits or one character per line, and prompts without symbols in brackets.
+//!
+//! Only results go to stdout, so piping and capturing a command's output gets just that.
+//! Status messages, warnings and prompts go to stderr, like the log. Everything is printed
+//! through `result`, `diagnostic` and `prompt`, which the crate's `clippy::print_stdout`
+//! and `clippy::print_stderr` lints enforce.
+
+use core::fmt::Display;
+use core::str::FromStr;
+
+use crate::messages::{Lang, Message};
+
+/// Prints (part of) the result of a command to stdout, as is
+#[allow(clippy::print_stdout)]
+pub fn result(text: &str) {
+    print!("{}", text);
+}
+
+/// Prints a status message or warning to stderr, on a line of its own
+#[allow(clippy::print_stderr)]
+pub fn diagnostic(line: &str) {
+    eprintln!("{}", line);
+}
+
+/// Prints a prompt to stderr, so the answer is typed right after it
+#[allow(clippy::print_stderr)]
+pub fn prompt(text: &str) -> std::io::Result<()> {
+    use std::io::Write as _;
+    eprint!("{}", text);
+    std::io::stderr().flush()
+}
+
+/// Whether to use colors, as passed with `--color`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    /// Colors on a terminal, unless `NO_COLOR` is set
+    Auto,
+    /// Colors, even when piped
+    Always,
+    /// No colors
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> crate::Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(anyhow::anyhow!("unknown color choice {:?}, expected auto, always or never", s)),
+        }
+    }
+}
+
+/// Colors and text attributes, as ANSI escape codes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Paint {
+    /// No attributes
+    Plain,
+    /// Labels and headers
+    Bold,
+    /// Less relevant details
+    Dim,
+    /// Failures and problems
+    Red,
+    /// Success
+    Green,
+}
+
+impl Paint {
+    fn code(self) -> Option<&'static str> {
+        match self {
+            Paint::Plain => None,
+            Paint::Bold => Some("1"),
+            Paint::Dim => Some("2"),
+            Paint::Red => Some("31"),
+            Paint::Green => Some("32"),
+        }
+    }
+}
+
+/// How responses are formatted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Style {
+    color: bool,
+    align: bool,
+    json: bool,
+    lang: Lang,
+    accessible: bool,
+    spelled: bool,
+}
+
+impl Style {
+    /// Tab-separated and uncolored, for pipes and transcripts
+    pub fn plain() -> Self {
+        Self { color: false, align: false, json: false, lang: Lang::En, accessible: false, spelled: false }
+    }
+
+    /// The same style, with tables aligned even if not on a terminal
+    pub fn aligned(&self) -> Self {
+        Self { align: true, ..*self }
+    }
+
+    /// The same style, with responses that have a structure printed as JSON, for scripts and frontends
+    pub fn json(&self) -> Self {
+        Self { json: true, ..*self }
+    }
+
+    /// The same style, with messages in the given language
+    pub fn lang(&self, lang: Lang) -> Self {
+        Self { lang, ..*self }
+    }
+
+    /// The same style, for screen readers: uncolored, with OTPs grouped or, if spelled,
+    /// one character per line
+    pub fn accessible(&self, spelled: bool) -> Self {
+        Self { color: false, accessible: true, spelled, ..*self }
+    }
+
+    /// Whether structured responses are printed as JSON
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    /// Detects what stdout supports, cf. <https://no-color.org>
+    pub fn detect(choice: ColorChoice) -> Self {
+        let terminal = atty::is(atty::Stream::Stdout);
+        let color = match choice {
+            ColorChoice::Auto => terminal && std::env::var_os("NO_COLOR").map_or(true, |value| value.is_empty()),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        };
+        let lang = match terminal {
+            true => Lang::detect(),
+            false => Lang::En,
+        };
+        Self { color, align: terminal, json: false, lang, accessible: false, spelled: false }
+    }
+
+    /// Lays out an OTP, e.g. `287082` as `287 082` in the accessible style
+    pub fn code(&self, code: &str) -> String {
+        let characters: Vec<char> = code.chars().collect();
+        match (self.accessible, self.spelled) {
+            (false, _) => code.into(),
+            (true, true) => characters.iter().map(char::to_string).collect::<Vec<_>>().join("\n"),
+            (true, false) => {
+                // eight digits read best as two groups of four
+                let group = if characters.len() == 8 { 4 } else { 3 };
+                characters.chunks(group).map(|chunk| chunk.iter().collect::<String>()).collect::<Vec<_>>().join(" ")
+            }
+        }
+    }
+
+    /// A yes/no question, with a hint at the default answer
+    pub fn question(&self, question: &str, default: bool) -> String {
+        match (self.accessible, default) {
+            (false, true) => format!("{} [Y/n] ", question),
+            (false, false) => format!("{} [y/N] ", question),
+            (true, true) => format!("{} Answer yes or no, the default is yes: ", question),
+            (true, false) => format!("{} Answer yes or no, the default is no: ", question),
+        }
+    }
+
+    /// The message in the style's language, with its placeholders filled in order
+    pub fn message(&self, message: Message, args: &[&dyn Display]) -> String {
+        message.format(self.lang, args)
+    }
+
+    /// Applies the paint, if colors are used
+    pub fn paint(&self, paint: Paint, text: &str) -> String {
+        match paint.code() {
+            Some(code) if self.color => format!("\x1b[{}m{}\x1b[0m", code, text),
+            _ => text.into(),
+        }
+    }
+}
+
+/// Rows of cells, rendered in aligned columns
+#[derive(Clone, Debug, Default)]
+pub struct Table {
+    rows: Vec<Vec<(String, Paint)>>,
+}
+
+impl Table {
+    /// Constructor, for an empty table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a row of painted cells
+    pub fn row(&mut self, cells: impl IntoIterator<Item = (String, Paint)>) {
+        self.rows.push(cells.into_iter().collect());
+    }
+
+    /// Whether no rows were appended
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Renders the table, one line per row
+    pub fn render(&self, style: &Style) -> String {
+        let mut widths: Vec<usize> = Vec::new();
+        for row in self.rows.iter() {
+            for (i, (text, _)) in row.iter().enumerate() {
+                let width = text.chars().count();
+                match widths.get_mut(i) {
+                    Some(max) => *max = (*max).max(width),
+                    None => widths.push(width),
+                }
+            }
+        }
+
+        let mut rendered = String::new();
+        for row in self.rows.iter() {
+            for (i, (text, paint)) in row.iter().enumerate() {
+                let last = i + 1 == row.len();
+                rendered.push_str(&style.paint(*paint, text));
+                if last {
+                    break;
+                }
+                match style.align {
+                    // padding after painting, so escape codes don't count towards the width
+                    true => rendered.extend(core::iter::repeat(' ').take(widths[i] - text.chars().count() + 2)),
+                    false => rendered.push('\t'),
+                }
+            }
+            rendered.push('\n');
+        }
+        rendered
+    }
+}
+
+/// Formats bytes as lowercase hexadecimal digits, e.g. for filenames and hashes
+pub fn hex(bytes: &[u8]) -> String {
+    data_encoding::HEXLOWER.encode(bytes)
+}
+
+/// Formats bytes as padded base64, e.g. for keys
+pub fn base64(bytes: &[u8]) -> String {
+    data_encoding::BASE64.encode(bytes)
+}
@@ -0,0 +1,79 @@
+//! The layout of the files the app stores via Trussed.
+//!
+//! Trussed already separates the files of its clients (e.g. the TOTP app's files end up
+//! below `/totp/`). Within that namespace, each kind of record gets its own directory, so
+//! records can be enumerated without guessing from file names, and other apps sharing
+//! these helpers can't collide with it.
+
+use littlefs2::path::Path;
+use trussed::types::PathBuf;
+
+/// Directory of the TOTP credentials
+pub const CREDENTIALS_DIR: &str = "credentials";
+/// Directory that unreadable records are moved to
+pub const QUARANTINE_DIR: &str = "quarantine";
+/// Directory of deleted credentials, which can still be restored
+pub const TRASH_DIR: &str = "trash";
+/// Directory of the usage counters of the credentials
+pub const USAGE_DIR: &str = "usage";
+/// Directory of configuration records, such as the policy
+pub const CONFIG_DIR: &str = "config";
+/// Directory of the OTP algorithms and counters of the credentials
+pub const PARAMETERS_DIR: &str = "parameters";
+/// Directory of the sealed recovery codes of the credentials
+pub const RECOVERY_DIR: &str = "recovery";
+/// Directory of the HMAC challenge-response keys
+pub const CHALLENGE_RESPONSE_DIR: &str = "chalresp";
+/// Directory of the secrets app's secrets
+pub const ITEMS_DIR: &str = "items";
+
+fn join(dir: &str, filename: &Path) -> PathBuf {
+    let mut path = PathBuf::from(dir);
+    path.push(filename);
+    path
+}
+
+/// Path of a credential file
+pub fn credential(filename: &Path) -> PathBuf {
+    join(CREDENTIALS_DIR, filename)
+}
+
+/// Path of a quarantined record, given its own path, e.g. `quarantine/parameters/…`
+pub fn quarantined(path: &Path) -> PathBuf {
+    join(QUARANTINE_DIR, path)
+}
+
+/// Path of a deleted credential
+pub fn trashed(filename: &Path) -> PathBuf {
+    join(TRASH_DIR, filename)
+}
+
+/// Path of the usage counters of a credential
+pub fn usage(filename: &Path) -> PathBuf {
+    join(USAGE_DIR, filename)
+}
+
+/// Path of the OTP algorithm and counter of a credential
+pub fn parameters(filename: &Path) -> PathBuf {
+    join(PARAMETERS_DIR, filename)
+}
+
+/// Path of the recovery codes of a credential
+pub fn recovery(filename: &Path) -> PathBuf {
+    join(RECOVERY_DIR, filename)
+}
+
+/// Path of an HMAC challenge-response key's metadata
+pub fn challenge_response(filename: &Path) -> PathBuf {
+    join(CHALLENGE_RESPONSE_DIR, filename)
+}
+
+/// Path of a secret of the secrets app
+pub fn item(filename: &Path) -> PathBuf {
+    join(ITEMS_DIR, filename)
+}
+
+/// Path of a configuration record
+pub fn config(name: &str) -> PathBuf {
+    join(CONFIG_DIR, &PathBuf::from(name))
+}
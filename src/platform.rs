@@ -2,62 +2,204 @@
 //! Implementation of `trussed::Platform` trait for our platform, PC
 
 use log::info;
+use sha2::{Digest as _, Sha256};
+use zeroize::Zeroizing;
 
 use trussed::platform::{consent, reboot, ui};
 
+pub mod rng;
+pub mod signals;
 pub mod store;
 
 trussed::platform!(Platform,
-    R: chacha20::ChaCha8Rng,
+    R: rng::Rng,
     S: store::Store,
     UI: UserInterface,
 );
 
 /// sets up the platform components and then itself
-pub fn init_platform(state_path: impl AsRef<std::path::Path>) -> Platform {
-    use trussed::service::SeedableRng;
-    let rng = chacha20::ChaCha8Rng::from_rng(rand_core::OsRng).unwrap();
-    let store = store::init_store(state_path);
-    let ui = UserInterface::new();
+///
+/// The returned handle must be used to write back cached state before exiting.
+/// With a passphrase, the state file is encrypted. A state file that can't be mounted is only
+/// reformatted if asked to, cf. `store::init_store`. Randomness comes from the given source,
+/// which must pass its health tests, cf. `rng`. Seeded by the OS, the returned seed file
+/// must be written back before exiting as well.
+pub fn init_platform(
+    state_path: impl AsRef<std::path::Path>,
+    cache_blocks: usize,
+    presence: Presence,
+    passphrase: Option<&crate::secret::SecretString>,
+    read_only: bool,
+    reformat: bool,
+    rng: &rng::RngSource,
+) -> crate::Result<(Platform, store::SyncHandle, Option<SeedFile>)> {
+    let (rng, seed_file) = match rng {
+        rng::RngSource::ChaCha => {
+            let (seed_file, seed) = SeedFile::churn(seed_path(&state_path))?;
+            (rng::Rng::from_seed(*seed), Some(seed_file))
+        }
+        source => (rng::Rng::new(source)?, None),
+    };
+    let (store, sync_handle) = store::init_store(state_path, cache_blocks, passphrase, read_only, reformat)?;
+    let ui = UserInterface::new(presence);
 
     let platform = Platform::new(rng, store, ui);
 
-    platform
+    Ok((platform, sync_handle, seed_file))
+}
+
+/// The file in which the RNG seed is kept across runs with a state file
+pub fn seed_path(state_path: impl AsRef<std::path::Path>) -> std::path::PathBuf {
+    let mut path = state_path.as_ref().as_os_str().to_owned();
+    path.push(".seed");
+    path.into()
+}
+
+/// A seed persisted across runs, like OpenSSH's `prng_seed`
+///
+/// Short-lived runs on entropy-starved systems (VMs, containers) then still get good
+/// randomness, as long as either the stored seed or the OS's entropy is good.
+pub struct SeedFile {
+    path: std::path::PathBuf,
+    /// Derived from the same inputs as the run's seed, but independent of it
+    pool: Zeroizing<[u8; 32]>,
+}
+
+impl SeedFile {
+    /// Mixes the stored seed, if any, with entropy from the OS, returning the seed for this run
+    fn churn(path: std::path::PathBuf) -> crate::Result<(Self, Zeroizing<[u8; 32]>)> {
+        let stored = Zeroizing::new(std::fs::read(&path).unwrap_or_default());
+        let entropy = rng::os_entropy()?;
+        let derive = |purpose: &[u8]| -> Zeroizing<[u8; 32]> {
+            let digest = Sha256::new().chain(purpose).chain(&*entropy).chain(&*stored).finalize();
+            Zeroizing::new(digest.into())
+        };
+        let seed = derive(b"seed");
+        let pool = derive(b"pool");
+        Ok((Self { path, pool }, seed))
+    }
+
+    /// Replaces the stored seed with fresh entropy, atomically
+    pub fn write_back(&self) -> crate::Result<()> {
+        let entropy = rng::os_entropy()?;
+        let seed: Zeroizing<[u8; 32]> = Zeroizing::new(
+            Sha256::new().chain(b"next").chain(&*self.pool).chain(&*entropy).finalize().into());
+        let mut temporary = self.path.as_os_str().to_owned();
+        temporary.push(".new");
+        std::fs::write(&temporary, &*seed)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// How user presence is checked
+///
+/// The test backends allow exercising the error paths of apps without manual interaction.
+pub enum Presence {
+    /// Wait for the user to press ENTER
+    Stdin,
+    /// Always deny presence
+    Deny,
+    /// Confirm presence after a delay, e.g. to run into the app's timeout
+    Delay(core::time::Duration),
+    /// Randomly confirm or deny presence
+    Flaky,
+}
+
+impl core::str::FromStr for Presence {
+    type Err = anyhow::Error;
+
+    /// Parses `stdin`, `test:deny`, `test:delay[:MILLISECONDS]` or `test:flaky`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let delay = |milliseconds: &str| -> Result<Self, Self::Err> {
+            let milliseconds = milliseconds.parse()
+                .map_err(|_| anyhow::anyhow!("Invalid delay in milliseconds: {}", milliseconds))?;
+            Ok(Presence::Delay(core::time::Duration::from_millis(milliseconds)))
+        };
+        match s {
+            "stdin" => Ok(Presence::Stdin),
+            "test:deny" => Ok(Presence::Deny),
+            "test:delay" => delay("1000"),
+            "test:flaky" => Ok(Presence::Flaky),
+            _ => match s.strip_prefix("test:delay:") {
+                Some(milliseconds) => delay(milliseconds),
+                None => Err(anyhow::anyhow!("Unknown user interface: {}", s)),
+            },
+        }
+    }
 }
 
 /// Implementation of `trussed::platform::UserInterface` trait
 pub struct UserInterface {
     start_time: std::time::Instant,
+    presence: Presence,
+    /// Receives a message for each line read from stdin, once reading has started
+    stdin_lines: Option<std::sync::mpsc::Receiver<()>>,
 }
 
 impl UserInterface {
-    pub fn new() -> Self {
+    pub fn new(presence: Presence) -> Self {
         Self {
             start_time: std::time::Instant::now(),
+            presence,
+            stdin_lines: None,
         }
     }
+
+    /// Reads stdin in a background thread, so checking for presence never blocks,
+    /// and Trussed can time out waiting for it
+    fn stdin_lines(&mut self) -> &std::sync::mpsc::Receiver<()> {
+        self.stdin_lines.get_or_insert_with(|| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                use std::io::BufRead as _;
+                for _ in std::io::stdin().lock().lines() {
+                    if sender.send(()).is_err() {
+                        break;
+                    }
+                }
+            });
+            receiver
+        })
+    }
 }
 
 impl trussed::platform::UserInterface for UserInterface
 {
     /// Prompt user to type a word for confirmation
     fn check_user_presence(&mut self) -> consent::Level {
-        use std::io::Read as _;
-        // This is not nice - we should "peek" and return Level::None
-        // if there is no key pressed yet (unbuffered read from stdin).
-        // Couldn't get this to work (without pulling in ncurses or similar).
-        std::io::stdin().bytes().next();
-        consent::Level::Normal
+        match self.presence {
+            Presence::Stdin => match self.stdin_lines().try_recv() {
+                Ok(()) => consent::Level::Normal,
+                Err(_) => {
+                    // Trussed polls in a loop, don't spin
+                    std::thread::sleep(core::time::Duration::from_millis(10));
+                    consent::Level::None
+                }
+            },
+            Presence::Deny => consent::Level::None,
+            Presence::Delay(delay) => {
+                std::thread::sleep(delay);
+                consent::Level::Normal
+            }
+            Presence::Flaky => {
+                use rand_core::RngCore as _;
+                match rand_core::OsRng.next_u32() % 2 {
+                    0 => consent::Level::None,
+                    _ => consent::Level::Normal,
+                }
+            }
+        }
     }
 
     fn set_status(&mut self, status: ui::Status) {
         info!("Set status: {:?}", status);
 
-        if status == ui::Status::WaitingForUserPresence {
-            use std::io::{Write as _};
-            let mut stdout = std::io::stdout();
-            write!(stdout, "Press ENTER to confirm (Ctrl-C to abort): ").ok();
-            stdout.flush().unwrap();
+        if status == ui::Status::WaitingForUserPresence && self.presence == Presence::Stdin {
+            // lines entered before the prompt don't count
+            while self.stdin_lines().try_recv().is_ok() {}
+            crate::output::prompt("Press ENTER to confirm (Ctrl-C to abort): ").ok();
         }
     }
 
@@ -0,0 +1,152 @@
+//! Random number generators for the platform, selected with `--rng`.
+//!
+//! Whatever the source, the entropy drawn from the operating system is health tested
+//! (cf. NIST SP 800-90B, section 4.4) before it is used, so a clearly broken source is
+//! refused instead of silently producing predictable keys. The raw bytes are tested, as
+//! ChaCha8's output passes the tests whatever its seed.
+
+use std::path::PathBuf;
+
+use log::warn;
+use rand_core::{CryptoRng, RngCore, SeedableRng as _};
+use sha2::{Digest as _, Sha256};
+use zeroize::Zeroizing;
+
+/// Minimum entropy per byte the health tests assume; real sources provide (almost) 8 bits
+const ASSUMED_ENTROPY_BITS: usize = 4;
+/// Acceptable false positive rate of the health tests, as a power of two
+const FALSE_POSITIVE_BITS: usize = 40;
+/// Cutoff of the repetition count test
+const REPETITION_CUTOFF: usize = 1 + (FALSE_POSITIVE_BITS + ASSUMED_ENTROPY_BITS - 1) / ASSUMED_ENTROPY_BITS;
+/// Window size of the adaptive proportion test
+const PROPORTION_WINDOW: usize = 512;
+/// Cutoff of the adaptive proportion test, the binomial critical value for the assumptions above
+const PROPORTION_CUTOFF: usize = 79;
+/// Number of bytes sampled by the health tests
+const SAMPLE_SIZE: usize = 4 * PROPORTION_WINDOW;
+
+#[derive(Clone, Debug, PartialEq)]
+/// Where randomness comes from
+pub enum RngSource {
+    /// ChaCha8, seeded from the operating system
+    ChaCha,
+    /// The operating system's generator, for each request
+    Os,
+    /// ChaCha8, seeded from the hash of a file, so runs can be reproduced (insecure!)
+    SeedFile(PathBuf),
+}
+
+impl core::str::FromStr for RngSource {
+    type Err = anyhow::Error;
+
+    /// Parses `chacha`, `os` or `seed:FILE`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chacha" => Ok(RngSource::ChaCha),
+            "os" => Ok(RngSource::Os),
+            _ => match s.strip_prefix("seed:") {
+                Some(path) if !path.is_empty() => Ok(RngSource::SeedFile(path.into())),
+                _ => Err(anyhow::anyhow!("Unknown RNG: {}, expected chacha, os or seed:FILE", s)),
+            },
+        }
+    }
+}
+
+/// The platform's random number generator
+pub enum Rng {
+    /// Cf. `RngSource::ChaCha` and `RngSource::SeedFile`
+    ChaCha(chacha20::ChaCha8Rng),
+    /// Cf. `RngSource::Os`
+    Os(rand_core::OsRng),
+}
+
+impl Rng {
+    /// Sets up the generator, refusing it unless the entropy from the OS passes the health tests
+    pub fn new(source: &RngSource) -> crate::Result<Self> {
+        match source {
+            RngSource::ChaCha => Ok(Rng::from_seed(*os_entropy()?)),
+            RngSource::Os => {
+                // its output is used as is, so a sample of it is tested
+                os_sample()?;
+                Ok(Rng::Os(rand_core::OsRng))
+            }
+            RngSource::SeedFile(path) => {
+                let seed = std::fs::read(path)
+                    .map_err(|e| anyhow::anyhow!("Could not read the seed file {}: {}", path.display(), e))?;
+                warn!("the RNG is seeded from {}, keys are predictable", path.display());
+                Ok(Rng::from_seed(Sha256::digest(&seed).into()))
+            }
+        }
+    }
+
+    /// Sets up ChaCha8 with the seed, which must come from tested entropy, cf. `os_entropy`
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Rng::ChaCha(chacha20::ChaCha8Rng::from_seed(seed))
+    }
+}
+
+/// Entropy from the operating system for seeding: a sample that passed the health tests,
+/// condensed with SHA-256
+pub fn os_entropy() -> crate::Result<Zeroizing<[u8; 32]>> {
+    let sample = os_sample()?;
+    Ok(Zeroizing::new(Sha256::digest(&*sample).into()))
+}
+
+/// Draws a sample from the operating system's generator, refusing it unless it passes the health tests
+fn os_sample() -> crate::Result<Zeroizing<Vec<u8>>> {
+    let mut sample = Zeroizing::new(vec![0u8; SAMPLE_SIZE]);
+    rand_core::OsRng.try_fill_bytes(&mut sample)
+        .map_err(|e| anyhow::anyhow!("Could not get entropy from the OS: {}", e))?;
+    health_test(&sample)?;
+    Ok(sample)
+}
+
+/// The repetition count and adaptive proportion tests, cf. NIST SP 800-90B, section 4.4
+fn health_test(sample: &[u8]) -> crate::Result<()> {
+    let mut repetitions = 1;
+    for pair in sample.windows(2) {
+        repetitions = if pair[0] == pair[1] { repetitions + 1 } else { 1 };
+        if repetitions >= REPETITION_CUTOFF {
+            return Err(anyhow::anyhow!("The RNG failed its health test, repeating a byte {} times", repetitions));
+        }
+    }
+    for window in sample.chunks_exact(PROPORTION_WINDOW) {
+        let count = window.iter().filter(|&&byte| byte == window[0]).count();
+        if count >= PROPORTION_CUTOFF {
+            return Err(anyhow::anyhow!("The RNG failed its health test, repeating a byte {} times in {}", count, PROPORTION_WINDOW));
+        }
+    }
+    Ok(())
+}
+
+impl RngCore for Rng {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            Rng::ChaCha(rng) => rng.next_u32(),
+            Rng::Os(rng) => rng.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            Rng::ChaCha(rng) => rng.next_u64(),
+            Rng::Os(rng) => rng.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            Rng::ChaCha(rng) => rng.fill_bytes(dest),
+            Rng::Os(rng) => rng.fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        match self {
+            Rng::ChaCha(rng) => rng.try_fill_bytes(dest),
+            Rng::Os(rng) => rng.try_fill_bytes(dest),
+        }
+    }
+}
+
+impl CryptoRng for Rng {}
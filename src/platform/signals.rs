@@ -0,0 +1,72 @@
+//! Keeps SIGINT and SIGTERM from cutting short the write-back of the state file.
+//!
+//! The `FileFlash` caches blocks, and writes back the changed ones in the order they were
+//! last changed, cf. `store::BlockCache`. Killed halfway through, the state file could end
+//! up with littlefs metadata pointing at blocks that were never written. So while a
+//! write-back runs, these signals are deferred: the write-back completes, and then fails
+//! with `ErrorKind::Interrupted`, so the runner unwinds as on any error, zeroizing the
+//! secrets it holds. Outside of write-backs, the state file is as littlefs left it, and
+//! the signals exit right away, as they would by default.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+const IDLE: u8 = 0;
+const DEFERRING: u8 = 1;
+const EXITING: u8 = 2;
+
+static STATE: AtomicU8 = AtomicU8::new(IDLE);
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Handles SIGINT and SIGTERM as described above, for the rest of the process
+///
+/// Only the first call installs the handler. Without it, signals are not deferred.
+pub fn install() -> std::io::Result<()> {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    let mut result = Ok(());
+    INSTALL.call_once(|| result = handle());
+    result
+}
+
+#[cfg(unix)]
+fn handle() -> std::io::Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    let mut signals = signal_hook::iterator::Signals::new(&[SIGINT, SIGTERM])?;
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            // set first, so a write-back ending concurrently can't miss it
+            PENDING.store(true, Ordering::SeqCst);
+            if STATE.compare_exchange(IDLE, EXITING, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                std::process::exit(128 + signal);
+            }
+            log::warn!("deferring signal {} until the state file is written back", signal);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn handle() -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Runs the write-back with SIGINT and SIGTERM deferred, cf. `interrupted`
+pub fn deferred<T>(write_back: impl FnOnce() -> T) -> T {
+    if STATE.compare_exchange(IDLE, DEFERRING, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        // a signal is exiting the process, don't start writing
+        loop {
+            std::thread::park();
+        }
+    }
+    let result = write_back();
+    STATE.store(IDLE, Ordering::SeqCst);
+    result
+}
+
+/// Fails if a signal was deferred, so the runner stops once the write-back is complete
+pub fn interrupted() -> std::io::Result<()> {
+    match PENDING.swap(false, Ordering::SeqCst) {
+        true => Err(std::io::Error::new(std::io::ErrorKind::Interrupted,
+            "Interrupted, after completing the write-back of the state file")),
+        false => Ok(()),
+    }
+}
@@ -3,14 +3,21 @@
 //!
 //! Here, we use a single binary file-backed littlefs implementation for
 //! persistent storage, and RAM array-backed implementations for the volatile storage.
-use std::{fs::File, io::{Seek as _, SeekFrom}};
+use std::{cell::RefCell, collections::BTreeMap, convert::TryInto as _, fs::File, io::{Read as _, Seek as _, SeekFrom, Write as _}, rc::Rc};
 
 pub use generic_array::{GenericArray, typenum::{consts, U16, U128, U256, U512, U1022}};
 use littlefs2::const_ram_storage;
-use log::info;
-use trussed::types::{LfsResult, LfsStorage};
+use littlefs2::driver::Storage as _;
+use littlefs2::path::Path;
+use log::{info, warn};
+use trussed::types::{LfsResult, LfsStorage, PathBuf};
 
-const_ram_storage!(VolatileStorage, 1024);
+use crate::secret::SecretString;
+
+pub mod encryption;
+
+// large enough for a few credentials registered with `--location volatile`
+const_ram_storage!(VolatileStorage, 8192);
 // currently, `trussed` needs a dummy parameter here
 const_ram_storage!(ExternalStorage, 1024);
 
@@ -20,31 +27,442 @@ trussed::store!(Store,
     Volatile: VolatileStorage
 );
 
-pub fn init_store(state_path: impl AsRef<std::path::Path>) -> Store {
-    let filesystem = FileFlash::new(state_path);
-    Store::attach_else_format(filesystem, ExternalStorage::new(), VolatileStorage::new())
+/// Mounts the store; the returned handle writes back the blocks cached by the `FileFlash`.
+///
+/// With a passphrase, the state file is encrypted, cf. `encryption`.
+/// Read-only, the state file must exist, and changes are kept in memory only.
+/// Only a new state file is formatted: an existing one that can't be mounted fails, unless
+/// `reformat` is given, in which case it is backed up (cf. `corrupted_path`) and replaced
+/// with an empty one.
+pub fn init_store(
+    state_path: impl AsRef<std::path::Path>,
+    cache_blocks: usize,
+    passphrase: Option<&SecretString>,
+    read_only: bool,
+    reformat: bool,
+) -> crate::Result<(Store, SyncHandle)> {
+    let state_path = state_path.as_ref();
+    let existed = state_path.exists();
+    let mut filesystem = FileFlash::new(state_path, cache_blocks, passphrase, read_only)?;
+    if existed && !littlefs2::fs::Filesystem::is_mountable(&mut filesystem) {
+        if read_only || !reformat {
+            return Err(anyhow::anyhow!(
+                "The state file at {} is corrupted, littlefs can't mount it{}", state_path.display(),
+                if read_only { "" } else { "; --reformat backs it up and starts over with an empty one" }));
+        }
+        let backup = corrupted_path(state_path);
+        if backup.exists() {
+            return Err(anyhow::anyhow!(
+                "There already is a backup of a corrupted state file at {}, move it away first", backup.display()));
+        }
+        std::fs::copy(state_path, &backup)?;
+        warn!("reformatting the state file, after backing it up to {}", backup.display());
+        crate::output::diagnostic(&format!("Backed up the corrupted state file to {}", backup.display()));
+    }
+    let sync_handle = filesystem.sync_handle();
+    let store = Store::attach_else_format(filesystem, ExternalStorage::new(), VolatileStorage::new());
+    Ok((store, sync_handle))
 }
 
-pub struct FileFlash {
+/// Keeps blocks of the state file in memory, writing back dirty blocks on `sync`.
+///
+/// This avoids opening, seeking and reading the state file for each of the many small
+/// accesses littlefs makes. Dirty blocks are written back in the order of their last
+/// change, which is the order littlefs relies on: it writes new data and metadata blocks
+/// before committing the metadata that refers to them.
+struct BlockCache {
+    file: File,
+    /// Maximum number of cached blocks; zero means writing through
+    capacity: usize,
+    blocks: BTreeMap<usize, Vec<u8>>,
+    /// Indices of the changed blocks, the most recently changed last
+    dirty: Vec<usize>,
+    wear: EraseCounts,
+    /// Seals the blocks on disk, if the state file is encrypted
+    cipher: Option<encryption::Cipher>,
+    /// Keeps all changed blocks in memory, never writing them back
+    read_only: bool,
+}
+
+/// Counts how often each block of the state file was erased, over its lifetime.
+///
+/// littlefs itself doesn't persist this, so we keep the counters in a file next
+/// to the state file, cf. `wear_path`.
+struct EraseCounts {
     path: std::path::PathBuf,
+    counts: Vec<u32>,
+    changed: bool,
+}
+
+impl EraseCounts {
+    fn load(path: std::path::PathBuf) -> Self {
+        let mut counts = vec![0u32; FileFlash::BLOCK_COUNT];
+        match std::fs::read(&path) {
+            Ok(data) if data.len() == 4 * FileFlash::BLOCK_COUNT => {
+                for (count, bytes) in counts.iter_mut().zip(data.chunks_exact(4)) {
+                    *count = u32::from_le_bytes(bytes.try_into().unwrap());
+                }
+            }
+            // counting starts now for state files from before we counted
+            _ => info!("starting to count erase cycles"),
+        }
+        Self { path, counts, changed: false }
+    }
+
+    fn record(&mut self, index: usize) {
+        self.counts[index] = self.counts[index].saturating_add(1);
+        self.changed = true;
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        if self.changed {
+            let data: Vec<u8> = self.counts.iter().flat_map(|count| count.to_le_bytes().to_vec()).collect();
+            std::fs::write(&self.path, data)?;
+            self.changed = false;
+        }
+        Ok(())
+    }
+}
+
+/// The file in which the erase cycles of the blocks of a state file are counted
+pub fn wear_path(state_path: impl AsRef<std::path::Path>) -> std::path::PathBuf {
+    let mut path = state_path.as_ref().as_os_str().to_owned();
+    path.push(".wear");
+    path.into()
+}
+
+/// The file to which a state file that can't be mounted is backed up, before reformatting it
+pub fn corrupted_path(state_path: impl AsRef<std::path::Path>) -> std::path::PathBuf {
+    let mut path = state_path.as_ref().as_os_str().to_owned();
+    path.push(".corrupted");
+    path.into()
+}
+
+impl BlockCache {
+    fn block(&mut self, index: usize) -> std::io::Result<&mut Vec<u8>> {
+        if !self.blocks.contains_key(&index) {
+            if self.blocks.len() >= self.capacity.max(1) {
+                // make room by dropping the clean blocks; changed blocks stay until `sync`
+                // writes them back all together, and all 64 KiB fit in memory anyway
+                let dirty = &self.dirty;
+                self.blocks.retain(|index, _| dirty.contains(index));
+            }
+            let block = self.read_block(index)?;
+            self.blocks.insert(index, block);
+        }
+        Ok(self.blocks.get_mut(&index).unwrap())
+    }
+
+    fn mark_dirty(&mut self, index: usize) -> std::io::Result<()> {
+        self.dirty.retain(|&other| other != index);
+        self.dirty.push(index);
+        if self.capacity == 0 && !self.read_only {
+            self.sync()?;
+            self.blocks.clear();
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        if self.read_only {
+            if !self.dirty.is_empty() {
+                info!("read-only, discarding changes to {} blocks", self.dirty.len());
+            }
+            return Ok(());
+        }
+        let dirty = core::mem::take(&mut self.dirty);
+        if !dirty.is_empty() {
+            info!("writing back {} blocks", dirty.len());
+        }
+        // cut short, the state file could refer to blocks that were not written back
+        super::signals::deferred(|| {
+            for index in dirty {
+                self.write_block(index)?;
+            }
+            self.file.flush()?;
+            self.wear.sync()
+        })
+    }
+
+    /// Offset and size of a block in the state file
+    fn location(&self, index: usize) -> (u64, usize) {
+        match self.cipher {
+            None => ((index * FileFlash::BLOCK_SIZE) as u64, FileFlash::BLOCK_SIZE),
+            Some(_) => {
+                let size = FileFlash::BLOCK_SIZE + encryption::OVERHEAD;
+                ((encryption::HEADER_SIZE + index * size) as u64, size)
+            }
+        }
+    }
+
+    fn read_block(&mut self, index: usize) -> std::io::Result<Vec<u8>> {
+        let (offset, size) = self.location(index);
+        let mut block = vec![0u8; size];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut block)?;
+        match &self.cipher {
+            None => Ok(block),
+            Some(cipher) => cipher.unseal(index, &block),
+        }
+    }
+
+    fn write_block(&mut self, index: usize) -> std::io::Result<()> {
+        let (offset, _) = self.location(index);
+        let block = &self.blocks[&index];
+        let sealed;
+        let data = match &self.cipher {
+            None => block,
+            Some(cipher) => {
+                sealed = cipher.seal(index, block);
+                &sealed
+            }
+        };
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+}
+
+/// Allows the runner to write back the blocks a `FileFlash` has cached,
+/// once the Trussed service has taken ownership of it.
+#[derive(Clone)]
+pub struct SyncHandle(Rc<RefCell<BlockCache>>);
+
+impl SyncHandle {
+    /// Writes back the cached blocks; fails if a signal arrived meanwhile, cf. `signals`
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.0.borrow_mut().sync()?;
+        super::signals::interrupted()
+    }
+
+    /// How often each block has been erased, over the lifetime of the state file
+    pub fn erase_counts(&self) -> Vec<u32> {
+        self.0.borrow().wear.counts.clone()
+    }
+}
+
+/// What a Trussed client stores in the persistent storage, i.e. in its top-level directory
+pub struct ClientUsage {
+    pub client: String,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Usage statistics of the persistent storage
+pub struct Health {
+    pub block_size: usize,
+    pub block_count: usize,
+    pub free_blocks: usize,
+    /// Usage of each Trussed client
+    pub clients: Vec<ClientUsage>,
+    /// How often each block has been erased, over the lifetime of the state file
+    pub erase_counts: Vec<u32>,
+}
+
+/// Collects usage statistics of the persistent storage
+pub fn health(store: Store, sync_handle: &SyncHandle) -> LfsResult<Health> {
+    use trussed::store::Store as _;
+    let fs = store.ifs();
+
+    let mut clients = Vec::new();
+    fs.read_dir_and_then(&PathBuf::from("/"), |dir| {
+        for entry in dir {
+            let entry = entry?;
+            if entry.metadata().is_dir() && !is_dot(entry.file_name()) {
+                let (files, bytes) = count_files(fs, entry.path())?;
+                clients.push(ClientUsage { client: String::from(entry.file_name().as_ref()), files, bytes });
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(Health {
+        block_size: FileFlash::BLOCK_SIZE,
+        block_count: FileFlash::BLOCK_COUNT,
+        free_blocks: fs.available_blocks()?,
+        clients,
+        erase_counts: sync_handle.erase_counts(),
+    })
+}
+
+/// The outcome of collecting garbage, cf. `collect_garbage`
+pub struct Garbage {
+    /// Names of the key files removed
+    pub removed: Vec<String>,
+    /// Names of referenced keys without a file
+    pub missing: Vec<String>,
+    /// Number of blocks freed
+    pub reclaimed_blocks: usize,
+}
+
+/// Removes the client's secret keys which are not referenced, given as the names of their files
+pub fn collect_garbage(store: Store, client: &str, referenced: &[String]) -> LfsResult<Garbage> {
+    use trussed::store::Store as _;
+    let fs = store.ifs();
+    let available = fs.available_blocks()?;
+
+    let mut dir = PathBuf::from(client);
+    dir.push(&PathBuf::from("sec"));
+    let mut existing = Vec::new();
+    // there is no directory until the client stores its first key
+    if fs.exists(&dir) {
+        fs.read_dir_and_then(&dir, |entries| {
+            for entry in entries {
+                let entry = entry?;
+                if entry.metadata().is_file() {
+                    existing.push(String::from(entry.file_name().as_ref()));
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    let mut removed = Vec::new();
+    for name in existing.iter().filter(|name| !referenced.contains(name)) {
+        let mut path = dir.clone();
+        path.push(&PathBuf::from(name.as_str()));
+        info!("removing unreferenced key {}", name);
+        fs.remove(&path)?;
+        removed.push(name.clone());
+    }
+    let missing = referenced.iter().filter(|name| !existing.contains(name)).cloned().collect();
+
+    Ok(Garbage {
+        removed,
+        missing,
+        reclaimed_blocks: fs.available_blocks()?.saturating_sub(available),
+    })
+}
+
+fn is_dot(file_name: &Path) -> bool {
+    file_name.as_ref() == "." || file_name.as_ref() == ".."
+}
+
+/// Counts the files below a directory, and their bytes, recursively
+fn count_files(fs: &littlefs2::fs::Filesystem<'static, FileFlash>, dir: &Path) -> LfsResult<(usize, u64)> {
+    fs.read_dir_and_then(dir, |entries| {
+        let (mut count, mut bytes) = (0, 0);
+        for entry in entries {
+            let entry = entry?;
+            if entry.metadata().is_file() {
+                count += 1;
+                bytes += entry.metadata().len() as u64;
+            } else if !is_dot(entry.file_name()) {
+                let (files, size) = count_files(fs, entry.path())?;
+                count += files;
+                bytes += size;
+            }
+        }
+        Ok((count, bytes))
+    })
+}
+
+/// The bytes a Trussed client stores in the persistent storage, in files and keys
+pub fn client_usage(store: Store, client: &str) -> LfsResult<u64> {
+    use trussed::store::Store as _;
+    let fs = store.ifs();
+    let dir = PathBuf::from(client);
+    // there is no directory until the client stores its first file
+    if !fs.exists(&dir) {
+        return Ok(0);
+    }
+    Ok(count_files(fs, &dir)?.1)
+}
+
+/// The size of a file a Trussed client stored in the persistent storage, if it exists
+pub fn file_size(store: Store, client: &str, path: &Path) -> Option<u64> {
+    use trussed::store::Store as _;
+    // Trussed keeps the files of each client below `/<client>/dat/`
+    let mut actual = PathBuf::from(client);
+    actual.push(&PathBuf::from("dat"));
+    actual.push(path);
+    store.ifs().metadata(&actual).ok().map(|metadata| metadata.len() as u64)
+}
+
+pub struct FileFlash {
+    cache: Rc<RefCell<BlockCache>>,
 }
 
 impl FileFlash {
-    const SIZE: u64 = 128*1024;
+    /// Size of a plain state file
+    pub const SIZE: u64 = 128*1024;
 
-    pub fn new(state_path: impl AsRef<std::path::Path>) -> Self {
+    /// Size of an encrypted state file
+    pub const ENCRYPTED_SIZE: u64 = (encryption::HEADER_SIZE
+        + Self::BLOCK_COUNT * (Self::BLOCK_SIZE + encryption::OVERHEAD)) as u64;
+
+    /// Opens the state file, creating it if necessary; with a passphrase, it is encrypted.
+    ///
+    /// Read-only, the state file is never created or written to.
+    pub fn new(
+        state_path: impl AsRef<std::path::Path>,
+        cache_blocks: usize,
+        passphrase: Option<&SecretString>,
+        read_only: bool,
+    ) -> crate::Result<Self> {
 
         let path: std::path::PathBuf = state_path.as_ref().into();
+        let size = match passphrase {
+            None => Self::SIZE,
+            Some(_) => Self::ENCRYPTED_SIZE,
+        };
 
+        let mut created = None;
         if let Ok(file) = File::open(&path) {
-            assert_eq!(file.metadata().unwrap().len(), Self::SIZE);
+            let len = file.metadata()?.len();
+            if len != size {
+                return Err(match len {
+                    Self::SIZE => anyhow::anyhow!("The state file is not encrypted"),
+                    Self::ENCRYPTED_SIZE => anyhow::anyhow!("The state file is encrypted, use --encrypt-state"),
+                    _ => anyhow::anyhow!("The state file has an unexpected size of {} bytes", len),
+                });
+            }
+        } else if read_only {
+            return Err(anyhow::anyhow!("There is no state file at {}", path.display()));
         } else {
-            // TODO: error handling
-            let file = File::create(&path).unwrap();
-            file.set_len(Self::SIZE).unwrap();
+            let mut file = File::create(&path)?;
+            file.set_len(size)?;
+            if let Some(passphrase) = passphrase {
+                let (cipher, data) = encryption::Cipher::create(passphrase)?;
+                file.write_all(&data)?;
+                created = Some(cipher);
+            }
             info!("Created new state file");
         }
-        Self { path }
+
+        let mut file = std::fs::OpenOptions::new().read(true).write(!read_only).open(&path)?;
+        let cipher = match (passphrase, created) {
+            (Some(passphrase), None) => {
+                let mut data = vec![0u8; encryption::HEADER_SIZE];
+                file.read_exact(&mut data)?;
+                Some(encryption::Cipher::open(passphrase, &data)?)
+            }
+            (_, created) => created,
+        };
+        let cache = BlockCache {
+            file,
+            capacity: cache_blocks,
+            blocks: BTreeMap::new(),
+            dirty: Vec::new(),
+            wear: EraseCounts::load(wear_path(&path)),
+            cipher,
+            read_only,
+        };
+        Ok(Self { cache: Rc::new(RefCell::new(cache)) })
+    }
+
+    pub fn sync_handle(&self) -> SyncHandle {
+        SyncHandle(self.cache.clone())
+    }
+}
+
+/// Reports a failure to access the state file to littlefs, which fails the operation
+///
+/// Blocks that can't be unsealed were tampered with, so they count as corrupted.
+fn lfs_error(error: std::io::Error) -> littlefs2::io::Error {
+    warn!("could not access the state file: {}", error);
+    match error.kind() {
+        std::io::ErrorKind::InvalidData => littlefs2::io::Error::Corruption,
+        _ => littlefs2::io::Error::Io,
     }
 }
 
@@ -54,7 +472,10 @@ impl littlefs2::driver::Storage for FileFlash {
     const BLOCK_SIZE: usize = 512;
 
     const BLOCK_COUNT: usize = 128;
-    const BLOCK_CYCLES: isize = -1;
+    // wear-leveling: littlefs moves metadata blocks after this many erases.
+    // This is a mount-time setting, not stored in the image, so existing
+    // state files just pick it up the next time they are mounted.
+    const BLOCK_CYCLES: isize = 500;
 
     type CACHE_SIZE = U512;
     type LOOKAHEADWORDS_SIZE = U16;
@@ -67,46 +488,33 @@ impl littlefs2::driver::Storage for FileFlash {
 
 
     fn read(&self, offset: usize, buffer: &mut [u8]) -> LfsResult<usize> {
-        use std::io::Read;
-
-        // debug!("reading {} bytes from {} in {:?}...", buffer.len(), offset, self.path);
-        let mut file = File::open(&self.path).unwrap();
-        file.seek(SeekFrom::Start(offset as _)).unwrap();
-        let bytes_read = file.read(buffer).unwrap();
-        assert_eq!(bytes_read, buffer.len());
-        // debug!("..ok");
-        Ok(bytes_read as _)
+        // littlefs never reads across block boundaries
+        let (index, start) = (offset / Self::BLOCK_SIZE, offset % Self::BLOCK_SIZE);
+        let mut cache = self.cache.borrow_mut();
+        let block = cache.block(index).map_err(lfs_error)?;
+        buffer.copy_from_slice(&block[start..][..buffer.len()]);
+        Ok(buffer.len())
     }
 
     fn write(&mut self, offset: usize, data: &[u8]) -> LfsResult<usize> {
-        use std::io::Write;
-
-        // debug!("writing {} bytes from {} in {:?}...", data.len(), offset, self.path);
-        // debug!("{:?}", data);
-        let mut file = std::fs::OpenOptions::new().write(true).open(&self.path).unwrap();
-        file.seek(SeekFrom::Start(offset as _)).unwrap();
-        let bytes_written = file.write(data).unwrap();
-        assert_eq!(bytes_written, data.len());
-        file.flush().unwrap();
-        // debug!("..ok");
-        Ok(bytes_written)
+        // littlefs never writes across block boundaries
+        let (index, start) = (offset / Self::BLOCK_SIZE, offset % Self::BLOCK_SIZE);
+        let mut cache = self.cache.borrow_mut();
+        let block = cache.block(index).map_err(lfs_error)?;
+        block[start..][..data.len()].copy_from_slice(data);
+        cache.mark_dirty(index).map_err(lfs_error)?;
+        Ok(data.len())
     }
 
     fn erase(&mut self, offset: usize, len: usize) -> LfsResult<usize> {
-        use std::io::Write;
-
-        // debug!("erasing {} bytes from {} in {:?}...", len, offset, self.path);
-        let mut file = std::fs::OpenOptions::new().write(true).open(&self.path).unwrap();
-        file.seek(SeekFrom::Start(offset as _)).unwrap();
-        let zero_block = [0xFFu8; Self::BLOCK_SIZE];
-        for _ in 0..(len/Self::BLOCK_SIZE) {
-            let bytes_written = file.write(&zero_block).unwrap();
-            assert_eq!(bytes_written, Self::BLOCK_SIZE);
+        let mut cache = self.cache.borrow_mut();
+        for index in (offset/Self::BLOCK_SIZE)..((offset + len)/Self::BLOCK_SIZE) {
+            let block = cache.block(index).map_err(lfs_error)?;
+            block.iter_mut().for_each(|byte| *byte = 0xFF);
+            cache.mark_dirty(index).map_err(lfs_error)?;
+            cache.wear.record(index);
         }
-        file.flush().unwrap();
-        // debug!("..ok");
         Ok(len)
     }
 
 }
-
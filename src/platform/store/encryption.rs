@@ -0,0 +1,111 @@
+//! Encryption of the state file at rest, with a key derived from a passphrase.
+//!
+//! The key is derived with Argon2 from the passphrase and a random salt, both stored in a
+//! header at the start of the state file. Each block is sealed with XChaCha20-Poly1305 under a
+//! fresh random nonce (large enough to never repeat in practice, even though littlefs rewrites
+//! blocks many times), and bound to its index as associated data, so blocks can't be swapped
+//! around unnoticed.
+//!
+//! Blocks that were never written (all zeros on disk) read as zeros, like in an unencrypted
+//! state file. Hence the encryption protects confidentiality and integrity of each block, but
+//! it doesn't detect all offline edits:
+//! - resetting a block to all zeros, i.e. to its initial state, goes unnoticed;
+//! - so does rolling back a block to an older version sealed under the same key, e.g. taken
+//!   from a copy of the state file, as nothing binds a block to the others or to a version.
+//!
+//! Restoring the whole state file from a backup is a rollback as well; for HOTP counters, the
+//! runner detects it by other means (cf. `rollback`).
+
+use chacha20poly1305::aead::{Aead as _, NewAead as _, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand_core::RngCore as _;
+use zeroize::Zeroizing;
+
+use crate::secret::SecretString;
+use crate::Result;
+
+const MAGIC: &[u8; 8] = b"TOTPENC1";
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+/// Size of the header, preceding the sealed blocks
+pub const HEADER_SIZE: usize = MAGIC.len() + SALT_SIZE + NONCE_SIZE + TAG_SIZE;
+/// Growth of each block through sealing
+pub const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+/// Seals and unseals the blocks of one state file
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Derives the key for a new state file, returning the header to store with it
+    pub fn create(passphrase: &SecretString) -> Result<(Self, Vec<u8>)> {
+        let mut salt = [0u8; SALT_SIZE];
+        rand_core::OsRng.fill_bytes(&mut salt);
+        let cipher = Self::derive(passphrase, &salt)?;
+
+        // the header proves knowledge of the key, so a wrong passphrase is detected on opening
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&cipher.seal_with(&[], MAGIC));
+        Ok((cipher, header))
+    }
+
+    /// Derives the key for an existing state file from its header, checking the passphrase
+    pub fn open(passphrase: &SecretString, header: &[u8]) -> Result<Self> {
+        if header.len() != HEADER_SIZE || &header[..MAGIC.len()] != MAGIC {
+            return Err(anyhow::anyhow!("The state file is not encrypted"));
+        }
+        let salt = &header[MAGIC.len()..][..SALT_SIZE];
+        let cipher = Self::derive(passphrase, salt)?;
+        cipher.unseal_with(&header[MAGIC.len() + SALT_SIZE..], MAGIC)
+            .map_err(|_| anyhow::anyhow!("Wrong passphrase for the state file"))?;
+        Ok(cipher)
+    }
+
+    /// Seals the block with the given index
+    pub fn seal(&self, index: usize, block: &[u8]) -> Vec<u8> {
+        self.seal_with(block, &(index as u64).to_le_bytes())
+    }
+
+    /// Unseals the block with the given index
+    pub fn unseal(&self, index: usize, sealed: &[u8]) -> std::io::Result<Vec<u8>> {
+        if sealed.iter().all(|&byte| byte == 0) {
+            return Ok(vec![0u8; sealed.len() - OVERHEAD]);
+        }
+        self.unseal_with(sealed, &(index as u64).to_le_bytes())
+            .map_err(|_| std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("block {} of the state file was tampered with", index),
+            ))
+    }
+
+    fn derive(passphrase: &SecretString, salt: &[u8]) -> Result<Self> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.expose().as_bytes(), salt, &mut *key)
+            .map_err(|e| anyhow::anyhow!("Could not derive the state file key: {}", e))?;
+        Ok(Self { aead: XChaCha20Poly1305::new(Key::from_slice(&*key)) })
+    }
+
+    /// Returns nonce, ciphertext and tag
+    fn seal_with(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand_core::OsRng.fill_bytes(&mut nonce);
+        // no panic - encryption only fails for messages beyond 256 GiB
+        let ciphertext = self.aead
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+            .unwrap();
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    fn unseal_with(&self, sealed: &[u8], aad: &[u8]) -> core::result::Result<Vec<u8>, chacha20poly1305::aead::Error> {
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.aead.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+    }
+}
@@ -0,0 +1,229 @@
+//! Declares which authorization each command of the app requires, persisted in Trussed.
+//!
+//! Instead of hand-rolling checks in each of its methods, the app looks up the
+//! `Requirement` for the `Action` at hand, and enforces that.
+
+use core::convert::TryInto as _;
+use core::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use trussed::{syscall, try_syscall, Bytes, types::Location};
+
+use crate::Result;
+use crate::paths;
+
+/// The name of the policy record in the configuration directory
+pub const POLICY_NAME: &str = "policy";
+
+#[derive(Clone, Copy, Debug, PartialEq, thiserror::Error)]
+/// Why user presence could not be confirmed
+pub enum PresenceError {
+    /// The user did not confirm in time
+    #[error("Timed out waiting for user presence")]
+    TimedOut,
+    /// The platform interrupted the wait
+    #[error("Could not obtain confirmation of user presence")]
+    NotConfirmed,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+/// What needs to happen before a command is processed
+pub enum Requirement {
+    /// The command is processed right away
+    Nothing,
+    /// The user needs to confirm their presence
+    Presence,
+}
+
+impl core::fmt::Display for Requirement {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Requirement::Nothing => "nothing",
+            Requirement::Presence => "presence",
+        })
+    }
+}
+
+impl FromStr for Requirement {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nothing" => Ok(Requirement::Nothing),
+            "presence" => Ok(Requirement::Presence),
+            _ => Err(anyhow::anyhow!("Unknown requirement {}, expected one of: nothing, presence", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+/// The commands a policy applies to
+#[allow(missing_docs)]
+pub enum Action {
+    Register,
+    Authenticate,
+    Verify,
+    List,
+    Prune,
+    Repair,
+    Precompute,
+}
+
+impl Action {
+    /// All actions, in the order they are shown
+    pub const ALL: [Action; 7] = [
+        Action::Register,
+        Action::Authenticate,
+        Action::Verify,
+        Action::List,
+        Action::Prune,
+        Action::Repair,
+        Action::Precompute,
+    ];
+
+    /// The name of the corresponding CLI subcommand, or `precompute` for `authenticate --precompute`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Register => "register",
+            Action::Authenticate => "authenticate",
+            Action::Verify => "verify",
+            Action::List => "list",
+            Action::Prune => "prune",
+            Action::Repair => "repair",
+            Action::Precompute => "precompute",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL.iter().copied()
+            .find(|action| action.name() == s)
+            .ok_or_else(|| anyhow::anyhow!("No policy applies to command {}", s))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// The requirement for each of the app's commands
+pub struct Policy {
+    register: Requirement,
+    authenticate: Requirement,
+    verify: Requirement,
+    list: Requirement,
+    prune: Requirement,
+    repair: Requirement,
+    precompute: Requirement,
+}
+
+#[derive(Deserialize)]
+/// A policy as stored before codes could be precomputed
+struct LegacyPolicy {
+    register: Requirement,
+    authenticate: Requirement,
+    verify: Requirement,
+    list: Requirement,
+    prune: Requirement,
+    repair: Requirement,
+}
+
+impl From<LegacyPolicy> for Policy {
+    fn from(legacy: LegacyPolicy) -> Self {
+        let LegacyPolicy { register, authenticate, verify, list, prune, repair } = legacy;
+        Self { register, authenticate, verify, list, prune, repair, precompute: Policy::default().precompute }
+    }
+}
+
+/// Using or deleting a secret requires user presence, everything else is allowed
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            register: Requirement::Nothing,
+            authenticate: Requirement::Presence,
+            verify: Requirement::Presence,
+            list: Requirement::Nothing,
+            prune: Requirement::Presence,
+            repair: Requirement::Nothing,
+            // codes that can be used offline, without the authenticator, are like a copy of the secret
+            precompute: Requirement::Presence,
+        }
+    }
+}
+
+impl Policy {
+    /// The requirement for processing the given action
+    pub fn requirement(&self, action: Action) -> Requirement {
+        *self.field(action)
+    }
+
+    /// Changes the requirement for processing the given action
+    pub fn set(&mut self, action: Action, requirement: Requirement) {
+        *self.field_mut(action) = requirement;
+    }
+
+    fn field(&self, action: Action) -> &Requirement {
+        match action {
+            Action::Register => &self.register,
+            Action::Authenticate => &self.authenticate,
+            Action::Verify => &self.verify,
+            Action::List => &self.list,
+            Action::Prune => &self.prune,
+            Action::Repair => &self.repair,
+            Action::Precompute => &self.precompute,
+        }
+    }
+
+    fn field_mut(&mut self, action: Action) -> &mut Requirement {
+        match action {
+            Action::Register => &mut self.register,
+            Action::Authenticate => &mut self.authenticate,
+            Action::Verify => &mut self.verify,
+            Action::List => &mut self.list,
+            Action::Prune => &mut self.prune,
+            Action::Repair => &mut self.repair,
+            Action::Precompute => &mut self.precompute,
+        }
+    }
+
+    /// Loads the persisted policy, falling back to the default if there is none
+    pub fn load<T: trussed::Client>(trussed: &mut T) -> Result<Self> {
+        let serialized_policy = match try_syscall!(trussed.read_file(Location::Internal, paths::config(POLICY_NAME))) {
+            Ok(reply) => reply.data,
+            Err(_) => return Ok(Self::default()),
+        };
+        Self::from_bytes(serialized_policy.as_ref())
+    }
+
+    /// Deserializes a policy as stored, failing (instead of panicking) on malformed input
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        postcard::from_bytes(bytes)
+            .or_else(|_| postcard::from_bytes::<LegacyPolicy>(bytes).map(Self::from))
+            .map_err(|_| anyhow::anyhow!("The stored policy is corrupted"))
+    }
+
+    /// Persists the policy
+    pub fn store<T: trussed::Client>(&self, trussed: &mut T) -> Result<()> {
+        let mut buf = [0u8; 64];
+        let serialized_policy = postcard::to_slice(self, &mut buf)
+            .map_err(|_| anyhow::anyhow!("postcard serialization error"))?;
+        syscall!(trussed.write_file(
+            Location::Internal,
+            paths::config(POLICY_NAME),
+            Bytes::from_slice(&*serialized_policy).unwrap(),
+            None
+        ));
+        Ok(())
+    }
+}
+
+/// Asks the user to confirm their presence, via the platform's user interface
+pub fn confirm_presence<T: trussed::Client>(trussed: &mut T, timeout: core::time::Duration) -> Result<()> {
+    let timeout_milliseconds = timeout.as_millis().try_into().unwrap_or(u32::MAX);
+    let consent = try_syscall!(trussed.confirm_user_present(timeout_milliseconds))
+        .map_err(|_| PresenceError::NotConfirmed)?
+        .result;
+    match consent {
+        Ok(()) => Ok(()),
+        Err(trussed::platform::consent::Error::TimedOut) => Err(PresenceError::TimedOut.into()),
+        Err(_) => Err(PresenceError::NotConfirmed.into()),
+    }
+}
@@ -0,0 +1,154 @@
+//! Provisioning from a declarative file, e.g. for fleets of VMs.
+//!
+//! The file (TOML) declares credentials, whose secrets are read from files of their own,
+//! and the policy. Provisioning plans the commands that bring the state in line with the
+//! file, which the runner dispatches like a batch. Credentials already registered under
+//! their label and slot are left alone, so provisioning again changes nothing.
+//!
+//! ```toml
+//! [policy]
+//! authenticate = "presence"
+//! list = "nothing"
+//!
+//! [[credential]]
+//! label = "alice@trussed.dev"
+//! secret_file = "secrets/alice"   # base32, relative to this file
+//! algorithm = "totp"              # optional, as are `slot`, `counter`, `period` and `issuer`
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::algorithm;
+use crate::authenticator::{Command, Listing, Register, SetPolicy, DEFAULT_PERIOD};
+use crate::policy::{Action, Policy, Requirement};
+use crate::Result;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// The contents of a provisioning file
+pub struct Provision {
+    /// Requirements by command, e.g. `authenticate = "presence"`
+    #[serde(default)]
+    pub policy: BTreeMap<String, String>,
+    /// The credentials to register
+    #[serde(default, rename = "credential")]
+    pub credentials: Vec<CredentialSpec>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// A credential in a provisioning file
+pub struct CredentialSpec {
+    /// Label for the credential, e.g. `alice@trussed.dev`
+    pub label: String,
+    /// File holding the base32 secret, relative to the provisioning file
+    pub secret_file: PathBuf,
+    /// Slot of the credential
+    #[serde(default)]
+    pub slot: u8,
+    /// One of `algorithm::ALGORITHMS`
+    #[serde(default = "default_algorithm")]
+    pub algorithm: String,
+    /// Counter of the first code, for counter-based algorithms
+    #[serde(default)]
+    pub counter: u64,
+    /// Seconds each code is valid for, for time-based algorithms
+    #[serde(default = "default_period")]
+    pub period: u64,
+    /// Id of the issuer in the catalog, cf. `issuers`
+    #[serde(default)]
+    pub issuer: Option<String>,
+}
+
+fn default_algorithm() -> String {
+    algorithm::DEFAULT_ALGORITHM.into()
+}
+
+fn default_period() -> u64 {
+    DEFAULT_PERIOD
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+/// What provisioning does about an entry of the file, as reported in the summary
+pub struct Step {
+    /// `credential` or `policy`
+    pub kind: &'static str,
+    /// The credential's label (and slot), or the command the policy applies to
+    pub name: String,
+    /// `unchanged`, `pending`, and once dispatched, `changed` or the error
+    pub outcome: String,
+    /// The command that brings the state in line, unless it already is
+    #[serde(skip)]
+    pub command: Option<Command>,
+}
+
+impl Step {
+    fn new(kind: &'static str, name: String, command: Option<Command>) -> Self {
+        let outcome = match command {
+            Some(_) => "pending",
+            None => "unchanged",
+        };
+        Self { kind, name, outcome: outcome.into(), command }
+    }
+}
+
+/// Reads a provisioning file
+pub fn read(path: impl AsRef<Path>) -> Result<Provision> {
+    let contents = std::fs::read_to_string(path)?;
+    toml::from_str(&contents).map_err(|e| anyhow::anyhow!("Invalid provisioning file: {}", e))
+}
+
+impl Provision {
+    /// The steps from the current credentials and policy to what the file declares;
+    /// credentials come first, as changing the policy may require user presence
+    pub fn plan(&self, listing: &Listing, policy: &Policy, base_dir: &Path, timestamp: u64) -> Result<Vec<Step>> {
+        if !listing.corrupted.is_empty() {
+            return Err(anyhow::anyhow!(
+                "{} credential files are corrupted (use `repair` to quarantine them)", listing.corrupted.len()));
+        }
+
+        let mut steps = Vec::new();
+        for spec in self.credentials.iter() {
+            algorithm::find(&spec.algorithm)?;
+            let registered = listing.credentials.iter()
+                .any(|credential| credential.label() == spec.label && credential.slot() == spec.slot);
+            let command = match registered {
+                true => None,
+                false => Some(Command::Register(Register {
+                    label: spec.label.clone(),
+                    base32_secret: read_secret(&base_dir.join(&spec.secret_file))?.as_str().into(),
+                    period_seconds: spec.period,
+                    slot: spec.slot,
+                    timestamp,
+                    location: trussed::types::Location::Internal,
+                    algorithm: spec.algorithm.clone(),
+                    counter: spec.counter,
+                    issuer: spec.issuer.clone(),
+                })),
+            };
+            steps.push(Step::new("credential", format!("{} (slot {})", spec.label, spec.slot), command));
+        }
+
+        for (name, requirement) in self.policy.iter() {
+            let action: Action = name.parse()?;
+            let requirement: Requirement = requirement.parse()?;
+            let command = match policy.requirement(action) == requirement {
+                true => None,
+                false => Some(Command::SetPolicy(SetPolicy { action, requirement })),
+            };
+            steps.push(Step::new("policy", name.clone(), command));
+        }
+        Ok(steps)
+    }
+}
+
+/// Reads a secret file, without its surrounding whitespace
+fn read_secret(path: &Path) -> Result<Zeroizing<String>> {
+    let contents = Zeroizing::new(std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Could not read the secret file {}: {}", path.display(), e))?);
+    Ok(Zeroizing::new(contents.trim().into()))
+}
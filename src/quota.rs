@@ -0,0 +1,86 @@
+//! Storage quotas per Trussed client, so one app can't fill up the state file for all.
+//!
+//! All clients share the internal store, i.e. the 128 KiB state file. `Quota` decorates an
+//! app's client, like `trace::Traced` does, and refuses requests that would make the app's
+//! files and keys (below `/<client>/`) exceed its quota. Refused requests fail with the
+//! error Trussed replies with when the state file itself is full.
+
+use core::task::Poll;
+
+use log::warn;
+use trussed::api::{Reply, Request};
+use trussed::client::{ClientResult, FutureResult, PollClient};
+use trussed::types::Location;
+
+use crate::platform::store::{self, Store};
+
+/// Wraps a Trussed client, refusing writes to the internal store beyond its quota, if any.
+pub struct Quota<C> {
+    inner: C,
+    store: Store,
+    client: String,
+    limit: Option<u64>,
+    refused: bool,
+}
+
+impl<C> Quota<C> {
+    /// Constructor, wraps the Trussed client of the named client. Without a limit,
+    /// requests are just passed through.
+    pub fn new(inner: C, store: Store, client: &str, limit: Option<u64>) -> Self {
+        Self { inner, store, client: client.into(), limit, refused: false }
+    }
+
+    /// Whether the request would take the client beyond its quota
+    fn exceeds(&self, request: &Request) -> bool {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let used = match store::client_usage(self.store, &self.client) {
+            Ok(used) => used,
+            // the request will run into the same problem
+            Err(_) => return false,
+        };
+        match request {
+            Request::WriteFile(request) if request.location == Location::Internal => {
+                let replaced = store::file_size(self.store, &self.client, &request.path).unwrap_or(0);
+                used.saturating_sub(replaced) + request.data.len() as u64 > limit
+            }
+            // new keys take a file of their own
+            Request::GenerateKey(_) | Request::UnsafeInjectSharedKey(_) => used >= limit,
+            _ => false,
+        }
+    }
+}
+
+impl<C: PollClient> PollClient for Quota<C> {
+    fn request<T: From<Reply>>(&mut self, req: impl Into<Request>) -> ClientResult<'_, T, Self> {
+        let request = req.into();
+        if self.exceeds(&request) {
+            warn!("refusing a request of {}, which would exceed its quota", self.client);
+            self.refused = true;
+        } else {
+            self.inner.request::<T>(request)?;
+        }
+        Ok(FutureResult::new(self))
+    }
+
+    fn poll(&mut self) -> Poll<core::result::Result<Reply, trussed::error::Error>> {
+        if self.refused {
+            self.refused = false;
+            return Poll::Ready(Err(trussed::error::Error::FilesystemWriteFailure));
+        }
+        self.inner.poll()
+    }
+}
+
+// The client traits only have provided methods, built on `PollClient`.
+impl<C: trussed::client::CertificateClient> trussed::client::CertificateClient for Quota<C> {}
+impl<C: trussed::client::CounterClient> trussed::client::CounterClient for Quota<C> {}
+impl<C: trussed::client::CryptoClient> trussed::client::CryptoClient for Quota<C> {}
+impl<C: trussed::client::FilesystemClient> trussed::client::FilesystemClient for Quota<C> {}
+impl<C: trussed::client::ManagementClient> trussed::client::ManagementClient for Quota<C> {}
+impl<C: trussed::client::UiClient> trussed::client::UiClient for Quota<C> {}
+impl<C: trussed::Client> trussed::Client for Quota<C> {}
+impl<C: trussed::client::mechanisms::Chacha8Poly1305> trussed::client::mechanisms::Chacha8Poly1305 for Quota<C> {}
+impl<C: trussed::client::mechanisms::Totp> trussed::client::mechanisms::Totp for Quota<C> {}
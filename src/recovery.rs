@@ -0,0 +1,143 @@
+//! Recovery codes, which services hand out alongside TOTP secrets.
+//!
+//! Each credential may have a list of recovery codes, sealed by Trussed (cf. `sealed`).
+//! The list is bound to the credential's file name, so it can't be passed off as another
+//! credential's.
+//!
+//! Codes are never removed from the list; using one marks it as consumed.
+
+use littlefs2::path::Path;
+use serde::{Deserialize, Serialize};
+use trussed::{syscall, try_syscall};
+use trussed::types::{KeyId, Location};
+use zeroize::Zeroizing;
+
+use crate::Result;
+use crate::paths;
+use crate::sealed::Sealed;
+use crate::secret::SecretString;
+
+/// Services issue 8 to 16 codes, this leaves room for a few more
+const MAX_CODES: usize = 20;
+const MAX_CODE_LENGTH: usize = 32;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A recovery code, and when it was used
+pub struct RecoveryCode {
+    code: SecretString,
+    used_at: Option<u64>,
+}
+
+impl RecoveryCode {
+    /// The code, as the service issued it
+    pub fn code(&self) -> &SecretString {
+        &self.code
+    }
+
+    /// Timestamp (seconds since UNIX epoch) when the code was used, if it was
+    pub fn used_at(&self) -> Option<u64> {
+        self.used_at
+    }
+
+    /// Whether the code is the same as the supplied one, ignoring case, whitespace and dashes
+    fn matches(&self, code: &str) -> bool {
+        normalize(self.code.expose()) == normalize(code)
+    }
+}
+
+fn normalize(code: &str) -> Zeroizing<String> {
+    Zeroizing::new(code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_lowercase())
+        .collect())
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+/// The recovery codes of a credential
+pub struct RecoveryCodes {
+    codes: Vec<RecoveryCode>,
+}
+
+impl RecoveryCodes {
+    /// All codes, in the order they were added
+    pub fn codes(&self) -> &[RecoveryCode] {
+        &self.codes
+    }
+
+    /// Appends codes, skipping those already in the list
+    pub fn add(&mut self, codes: &[SecretString]) -> Result<()> {
+        for code in codes.iter() {
+            let trimmed = code.expose().trim();
+            if trimmed.is_empty() || trimmed.chars().count() > MAX_CODE_LENGTH {
+                return Err(anyhow::anyhow!("Recovery codes must have 1 to {} characters", MAX_CODE_LENGTH));
+            }
+            if !self.codes.iter().any(|known| known.matches(trimmed)) {
+                self.codes.push(RecoveryCode { code: trimmed.into(), used_at: None });
+            }
+        }
+        if self.codes.len() > MAX_CODES {
+            return Err(anyhow::anyhow!("At most {} recovery codes can be kept per credential", MAX_CODES));
+        }
+        Ok(())
+    }
+
+    /// Marks the supplied code, or else the first unused one, as used at the timestamp
+    pub fn consume(&mut self, code: Option<&SecretString>, timestamp: u64) -> Result<RecoveryCode> {
+        let found = match code {
+            Some(code) => self.codes.iter_mut().find(|known| known.matches(code.expose()))
+                .ok_or_else(|| anyhow::anyhow!("This recovery code is not known"))?,
+            None => self.codes.iter_mut().find(|known| known.used_at.is_none())
+                .ok_or_else(|| anyhow::anyhow!("All recovery codes are used up"))?,
+        };
+        if let Some(used_at) = found.used_at {
+            return Err(anyhow::anyhow!("This recovery code was already used (at {})", used_at));
+        }
+        found.used_at = Some(timestamp);
+        Ok(found.clone())
+    }
+
+    /// Loads and unseals the recovery codes of the credential stored in the file,
+    /// starting afresh if there are none
+    ///
+    /// Recovery codes are kept in the internal store, whatever the credential's location.
+    pub fn load<T>(trussed: &mut T, filename: &Path) -> Result<Self>
+    where
+        T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
+    {
+        let sealed = match Sealed::load(trussed, paths::recovery(filename))? {
+            Some(sealed) => sealed,
+            None => return Ok(Self::default()),
+        };
+        let plaintext = sealed.unseal(trussed, filename.as_ref().as_bytes())?;
+        let codes = postcard::from_bytes(&plaintext)
+            .map_err(|_| anyhow::anyhow!("The stored recovery codes are corrupted"))?;
+        Ok(Self { codes })
+    }
+
+    /// Seals and persists the recovery codes of the credential stored in the file,
+    /// generating a key for them the first time
+    pub fn store<T>(&self, trussed: &mut T, filename: &Path) -> Result<()>
+    where
+        T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
+    {
+        let key = Self::key(trussed, filename)?;
+        let mut buf = Zeroizing::new([0u8; 1024]);
+        let plaintext = postcard::to_slice(&self.codes, buf.as_mut())
+            .map_err(|_| anyhow::anyhow!("postcard serialization error"))?;
+        Sealed::seal(trussed, key, plaintext, filename.as_ref().as_bytes())?
+            .store(trussed, paths::recovery(filename))
+    }
+
+    /// Removes the recovery codes of the credential stored in the file, with their key, if any
+    pub fn remove<T: trussed::Client>(trussed: &mut T, filename: &Path) {
+        if let Ok(Some(key)) = Self::key(trussed, filename) {
+            syscall!(trussed.delete(key));
+        }
+        try_syscall!(trussed.remove_file(Location::Internal, paths::recovery(filename))).ok();
+    }
+
+    /// The key sealing the recovery codes of the credential stored in the file, if any
+    pub fn key<T: trussed::Client>(trussed: &mut T, filename: &Path) -> Result<Option<KeyId>> {
+        Ok(Sealed::load(trussed, paths::recovery(filename))?.map(|sealed| sealed.key()))
+    }
+}
@@ -0,0 +1,105 @@
+//! Protection of counter-based credentials (HOTP) against restored backups of the state file.
+//!
+//! A counter must never repeat, or its codes can be replayed. Restoring an old copy of the
+//! state file takes the counters back along with it, so the runner keeps a record of the
+//! highest counters it has seen in a file next to the state file, which backups of the state
+//! file don't include. When the state is mounted, counters behind the record mean the state
+//! was rolled back; the runner refuses to go on, unless the user accepts the state's counters
+//! (after resynchronizing them with the servers).
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::authenticator::Credential;
+use crate::Result;
+
+/// Path of the counter record kept for a state file
+pub fn path(state_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = state_path.as_ref().as_os_str().to_owned();
+    path.push(".counters");
+    path.into()
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// The highest counter seen for a credential
+pub struct Entry {
+    /// Label of the credential
+    pub label: String,
+    /// Slot of the credential
+    pub slot: u8,
+    /// Registration of the credential, telling it apart from one registered again later
+    pub created_at: u64,
+    /// The counter of the credential's next code
+    pub counter: u64,
+}
+
+impl Entry {
+    fn of(credential: &Credential) -> Self {
+        Self {
+            label: credential.label().into(),
+            slot: credential.slot(),
+            created_at: credential.created_at(),
+            counter: credential.parameters().counter(),
+        }
+    }
+
+    fn is(&self, other: &Entry) -> bool {
+        (&self.label, self.slot, self.created_at) == (&other.label, other.slot, other.created_at)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+/// The record of counters, including those of credentials deleted since, whose state
+/// could come back with a backup
+pub struct Counters {
+    entries: Vec<Entry>,
+}
+
+impl Counters {
+    /// Reads the record, which is empty if there is none yet
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        match std::fs::read(path.as_ref()) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|e| {
+                anyhow::anyhow!("The counter record {} is corrupted: {}", path.as_ref().display(), e)
+            }),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Writes the record, atomically
+    pub fn store(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut temporary = path.as_ref().as_os_str().to_owned();
+        temporary.push(".new");
+        std::fs::write(&temporary, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// The record's entries of the credentials whose counters are behind it
+    pub fn rolled_back(&self, credentials: &[Credential]) -> Vec<&Entry> {
+        credentials.iter().map(Entry::of)
+            .filter_map(|current| self.entries.iter().find(|entry| entry.is(&current) && entry.counter > current.counter))
+            .collect()
+    }
+
+    /// Moves the record's counters forward to the credentials' counters
+    pub fn record(&mut self, credentials: &[Credential]) {
+        self.update(credentials, |recorded, current| *recorded = (*recorded).max(current));
+    }
+
+    /// Takes the credentials' counters as they are, even if they are behind the record
+    pub fn accept(&mut self, credentials: &[Credential]) {
+        self.update(credentials, |recorded, current| *recorded = current);
+    }
+
+    fn update(&mut self, credentials: &[Credential], update: impl Fn(&mut u64, u64)) {
+        for current in credentials.iter().map(Entry::of) {
+            match self.entries.iter_mut().find(|entry| entry.is(&current)) {
+                Some(entry) => update(&mut entry.counter, current.counter),
+                None => self.entries.push(current),
+            }
+        }
+    }
+}
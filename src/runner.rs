@@ -0,0 +1,52 @@
+//! Commands of the runner itself, rather than of an app, e.g. replacing the state file.
+//!
+//! No app processes them, but they pass through the same pipeline as the apps' commands
+//! (cf. `middleware`), so e.g. read-only mode refuses those changing the state like any
+//! other, and new ones inherit this by classifying themselves.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command of the runner: writing the state file's image to a file
+pub struct ExportState {
+    /// The file to export to
+    pub file: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command of the runner: replacing the state file with a verified export
+pub struct ImportState {
+    /// The file to import from
+    pub file: String,
+    /// Replace an existing state file
+    pub force: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// The commands of the runner
+#[allow(missing_docs)]
+pub enum Command {
+    ExportState(ExportState),
+    ImportState(ImportState),
+    CollectGarbage,
+}
+
+impl Command {
+    /// Whether the command changes stored state, i.e. is refused in read-only mode
+    pub fn is_mutating(&self) -> bool {
+        // no wildcard, so each new command must be classified
+        match self {
+            Command::ImportState(_) | Command::CollectGarbage => true,
+            Command::ExportState(_) => false,
+        }
+    }
+
+    /// The name of the corresponding CLI subcommand, e.g. `storage gc`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::ExportState(_) => "state export",
+            Command::ImportState(_) => "state import",
+            Command::CollectGarbage => "storage gc",
+        }
+    }
+}
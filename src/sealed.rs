@@ -0,0 +1,94 @@
+//! Records encrypted by Trussed, for data that must not be readable from the state file.
+//!
+//! The data is encrypted with a ChaCha8Poly1305 key that never leaves Trussed, so it can
+//! only be read through the app that sealed it, even if the state file itself isn't
+//! encrypted. Associated data binds a record to its purpose, e.g. the file name of the
+//! credential it belongs to, so it can't be passed off as another one.
+
+use serde::{Deserialize, Serialize};
+use trussed::{syscall, try_syscall, Bytes};
+use trussed::types::{KeyId, Location, Message, PathBuf, ShortData};
+use zeroize::Zeroizing;
+
+use crate::Result;
+
+/// The largest plaintext that can be sealed, so the sealed record still fits into a file
+pub const MAX_PLAINTEXT_LENGTH: usize = 896;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// Ciphertext, with the key that sealed it and what it takes to unseal it
+pub struct Sealed {
+    key: KeyId,
+    nonce: ShortData,
+    tag: ShortData,
+    ciphertext: Message,
+}
+
+impl Sealed {
+    /// Seals the plaintext with the given key, or a newly generated one in the internal store
+    pub fn seal<T>(trussed: &mut T, key: Option<KeyId>, plaintext: &[u8], associated_data: &[u8]) -> Result<Self>
+    where
+        T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
+    {
+        if plaintext.len() > MAX_PLAINTEXT_LENGTH {
+            return Err(anyhow::anyhow!("Only up to {} bytes can be sealed", MAX_PLAINTEXT_LENGTH));
+        }
+        let key = match key {
+            Some(key) => key,
+            None => syscall!(trussed.generate_chacha8poly1305_key(Location::Internal)).key,
+        };
+        // the nonce is generated by Trussed
+        let reply = syscall!(trussed.encrypt_chacha8poly1305(key, plaintext, associated_data, None));
+        Ok(Self { key, nonce: reply.nonce, tag: reply.tag, ciphertext: reply.ciphertext })
+    }
+
+    /// Unseals the plaintext, failing if it was sealed with other associated data or tampered with
+    pub fn unseal<T>(&self, trussed: &mut T, associated_data: &[u8]) -> Result<Zeroizing<Vec<u8>>>
+    where
+        T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
+    {
+        let plaintext = syscall!(trussed.decrypt_chacha8poly1305(
+            self.key,
+            &self.ciphertext,
+            associated_data,
+            &self.nonce,
+            &self.tag,
+        )).plaintext
+            .ok_or_else(|| anyhow::anyhow!("The sealed data could not be unsealed"))?;
+        Ok(Zeroizing::new(plaintext.to_vec()))
+    }
+
+    /// The key that sealed the data
+    pub fn key(&self) -> KeyId {
+        self.key
+    }
+
+    /// Loads a sealed record, or `None` if there is none
+    pub fn load<T: trussed::Client>(trussed: &mut T, path: PathBuf) -> Result<Option<Self>> {
+        let serialized_sealed = match try_syscall!(trussed.read_file(Location::Internal, path)) {
+            Ok(reply) => reply.data,
+            Err(_) => return Ok(None),
+        };
+        Self::from_bytes(serialized_sealed.as_ref()).map(Some)
+    }
+
+    /// Deserializes a sealed record as stored, failing (instead of panicking) on malformed input
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        postcard::from_bytes(bytes).map_err(|_| anyhow::anyhow!("The sealed record is corrupted"))
+    }
+
+    /// Persists the sealed record, in the internal store
+    pub fn store<T: trussed::Client>(&self, trussed: &mut T, path: PathBuf) -> Result<()> {
+        let mut buf = [0u8; 1024];
+        let serialized_sealed = postcard::to_slice(self, &mut buf)
+            .map_err(|_| anyhow::anyhow!("postcard serialization error"))?;
+        syscall!(trussed.write_file(
+            Location::Internal,
+            path,
+            // no panic - plaintexts are limited, so sealed records fit
+            Bytes::from_slice(&*serialized_sealed).unwrap(),
+            None
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Wrappers for secret material passing through host memory.
+//!
+//! On a PC, TOTP seeds necessarily pass through the runner before they are injected into
+//! Trussed. These wrappers wipe their memory when dropped, and never show their contents
+//! in `Debug` output (and hence in logs).
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::Zeroizing;
+
+/// The base32 alphabet, cf. RFC 4648, section 6
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+/// Number of base32 characters in each group of a paper backup, followed by a check character
+const BACKUP_GROUP_LENGTH: usize = 4;
+
+#[derive(Clone, Default, PartialEq)]
+/// A `String` which is zeroized on drop
+pub struct SecretString(Zeroizing<String>);
+
+impl SecretString {
+    /// Access to the secret, keep the borrow short
+    pub fn expose(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// A dummy of the same length, consisting of `A`s
+    pub fn dummy(&self) -> SecretString {
+        SecretString::from("A".repeat(self.0.len()))
+    }
+
+    /// A dummy in place of a base32 secret: as many zero bytes, encoded, if this one is
+    /// valid, and else invalid as well, so both are handled alike
+    pub fn base32_dummy(&self) -> SecretString {
+        match SecretBytes::from_base32(self) {
+            Ok(secret) => SecretBytes::from(vec![0; secret.expose().len()]).to_base32(),
+            // `0` is not in the base32 alphabet
+            Err(_) => SecretString::from("0".repeat(self.0.len())),
+        }
+    }
+
+    /// A dummy in place of a hexadecimal secret, cf. `base32_dummy`
+    pub fn hex_dummy(&self) -> SecretString {
+        match SecretBytes::from_hex(self) {
+            Ok(secret) => SecretString::from("00".repeat(secret.expose().len())),
+            Err(_) => SecretString::from("x".repeat(self.0.len())),
+        }
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self(Zeroizing::new(secret))
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        Self::from(String::from(secret))
+    }
+}
+
+/// Serializes the secret itself; commands are redacted before they are written
+/// anywhere else than to Trussed, cf. `transcript::Entry`
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.expose())
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl core::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("SecretString(<redacted>)")
+    }
+}
+
+#[derive(Clone, Default, PartialEq)]
+/// A byte vector which is zeroized on drop
+pub struct SecretBytes(Zeroizing<Vec<u8>>);
+
+impl SecretBytes {
+    /// Access to the secret, keep the borrow short
+    pub fn expose(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+
+    /// Mutable access to the secret, keep the borrow short
+    pub fn expose_mut(&mut self) -> &mut [u8] {
+        self.0.as_mut_slice()
+    }
+
+    /// Decodes a base32 secret, failing (instead of panicking) on malformed input
+    ///
+    /// Secrets are accepted as services present them, e.g. `jbsw y3dp ehpk 3pxp`: whitespace
+    /// and dashes are ignored, lowercase letters are accepted, and padding is optional.
+    pub fn from_base32(secret: &SecretString) -> crate::Result<Self> {
+        let normalized = normalize_base32(secret.expose())?;
+        // lengths that no number of bytes encodes to, cf. RFC 4648, section 6
+        if let 1 | 3 | 6 = normalized.len() % 8 {
+            return Err(anyhow::anyhow!(
+                "The secret is not valid base32: {} characters can't be complete, is one missing?",
+                normalized.len(),
+            ));
+        }
+        let decoded = data_encoding::BASE32_NOPAD.decode(normalized.as_bytes())
+            .map_err(|e| anyhow::anyhow!("The secret is not valid base32: {}", e))?;
+        Ok(Self::from(decoded))
+    }
+
+    /// Decodes a hexadecimal secret, as challenge-response tools (e.g. `ykman`) show them
+    pub fn from_hex(secret: &SecretString) -> crate::Result<Self> {
+        let decoded = data_encoding::HEXLOWER_PERMISSIVE.decode(secret.expose().trim().as_bytes())
+            .map_err(|e| anyhow::anyhow!("The secret is not valid hexadecimal: {}", e))?;
+        Ok(Self::from(decoded))
+    }
+
+    /// Encodes the secret as unpadded base32
+    pub fn to_base32(&self) -> SecretString {
+        SecretString::from(data_encoding::BASE32_NOPAD.encode(self.expose()))
+    }
+
+    /// Encodes the secret for writing it down on paper: groups of base32 characters,
+    /// each followed by a check character, e.g. `JBSWW Y3DPO EHPKH 3PXPM`
+    pub fn to_backup(&self) -> SecretString {
+        let encoded = self.to_base32();
+        let mut backup = Zeroizing::new(String::new());
+        for (index, group) in encoded.expose().as_bytes().chunks(BACKUP_GROUP_LENGTH).enumerate() {
+            if index > 0 {
+                backup.push(' ');
+            }
+            // no panic - base32 is ASCII
+            backup.push_str(core::str::from_utf8(group).unwrap());
+            backup.push(check_character(index, group));
+        }
+        SecretString::from(core::mem::take(&mut *backup))
+    }
+
+    /// Decodes a paper backup, cf. `to_backup`, pointing out the group of a typo
+    pub fn from_backup(backup: &SecretString) -> crate::Result<Self> {
+        let normalized = normalize_base32(backup.expose())?;
+        let mut encoded = Zeroizing::new(String::with_capacity(normalized.len()));
+        for (index, group) in normalized.as_bytes().chunks(BACKUP_GROUP_LENGTH + 1).enumerate() {
+            let (characters, check) = group.split_at(group.len() - 1);
+            if characters.is_empty() || check_character(index, characters) != check[0] as char {
+                return Err(anyhow::anyhow!(
+                    "Group {} of the backup doesn't match its check character, is there a typo?", index + 1));
+            }
+            // no panic - normalized base32 is ASCII
+            encoded.push_str(core::str::from_utf8(characters).unwrap());
+        }
+        Self::from_base32(&SecretString::from(core::mem::take(&mut *encoded)))
+    }
+}
+
+/// The check character of a group of a paper backup, cf. the Luhn mod N algorithm
+///
+/// It catches any single mistyped character and most swapped neighbours. The group's
+/// position is included, so swapped groups are caught as well.
+fn check_character(index: usize, group: &[u8]) -> char {
+    let position = |c: &u8| BASE32_ALPHABET.iter().position(|a| a == c).unwrap_or(0);
+    // from the right, starting with the group's last character
+    let code_points = group.iter().rev().map(position).chain(core::iter::once(index % 32));
+    let mut factor = 2;
+    let mut sum = 0;
+    for code_point in code_points {
+        let addend = factor * code_point;
+        factor = 3 - factor;
+        sum += addend / 32 + addend % 32;
+    }
+    BASE32_ALPHABET[(32 - sum % 32) % 32] as char
+}
+
+/// Strips separators and padding, and uppercases, naming the first character that isn't base32
+fn normalize_base32(secret: &str) -> crate::Result<Zeroizing<String>> {
+    let mut normalized = Zeroizing::new(String::with_capacity(secret.len()));
+    let unpadded = secret.trim_end_matches(|c: char| c == '=' || c.is_whitespace());
+    for (position, c) in unpadded.chars().enumerate() {
+        match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' | c @ '2'..='7' => normalized.push(c),
+            c if c.is_whitespace() || c == '-' => {}
+            // not in the alphabet, so naming it reveals nothing about the secret
+            c => return Err(anyhow::anyhow!(
+                "The secret is not valid base32: {:?} at position {} is not a letter or digit from 2 to 7{}",
+                c, position + 1,
+                match c { '0' => ", is it an O?", '1' => ", is it an I?", '8' => ", is it a B?", _ => "" },
+            )),
+        }
+    }
+    Ok(normalized)
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(secret: Vec<u8>) -> Self {
+        Self(Zeroizing::new(secret))
+    }
+}
+
+impl core::fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "SecretBytes(<{} bytes redacted>)", self.0.len())
+    }
+}
@@ -0,0 +1,258 @@
+//! A second app, keeping static secrets such as API tokens and passwords.
+//!
+//! It runs next to the TOTP authenticator on the same Trussed service, with a client of
+//! its own, so its files end up in a namespace of their own (below `/secrets/`). It reuses
+//! the authenticator's subsystems: values are sealed by Trussed (cf. `sealed`), and each
+//! secret may require user presence (cf. `policy`) before it is revealed or deleted.
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use trussed::{syscall, try_syscall, Bytes};
+use trussed::types::{Location, Mechanism, Message, PathBuf};
+
+use crate::Result;
+use crate::paths;
+use crate::policy::{self, Requirement};
+use crate::sealed::Sealed;
+use crate::secret::SecretString;
+
+/// The Trussed client of this app
+pub const CLIENT_ID: &str = "secrets";
+const MAX_NAME_LENGTH: usize = 128;
+/// Leaves room for the name and metadata in the secret's file
+const MAX_VALUE_LENGTH: usize = 512;
+
+/// The app, keeping secrets using Trussed®
+pub struct Secrets<T> {
+    trussed: T,
+    presence_timeout: core::time::Duration,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this app can process: storing a secret
+pub struct Add {
+    /// Name of the secret, e.g. `github-token`
+    pub name: String,
+    /// The secret itself
+    pub value: SecretString,
+    /// What needs to happen before the secret is revealed or deleted
+    pub requirement: Requirement,
+    /// Timestamp of creation (seconds since UNIX epoch)
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this app can process: revealing a secret
+pub struct Get {
+    /// Name of the secret, e.g. `github-token`
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A command this app can process: deleting a secret
+pub struct Delete {
+    /// Name of the secret, e.g. `github-token`
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// The public API of this app
+#[allow(missing_docs)]
+pub enum Command {
+    Add(Add),
+    Get(Get),
+    List,
+    Delete(Delete),
+}
+
+impl Command {
+    /// The command with the secret replaced by a dummy of the same length, e.g. for transcripts
+    pub fn redacted(&self) -> Command {
+        match self {
+            Command::Add(add) => Command::Add(Add { value: add.value.dummy(), ..add.clone() }),
+            command => command.clone(),
+        }
+    }
+
+    /// Whether the command changes stored state, i.e. is refused in read-only mode
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Command::Add(_) | Command::Delete(_) => true,
+            Command::Get(_) | Command::List => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// Everything about a secret, except its value
+pub struct Item {
+    name: String,
+    requirement: Requirement,
+    created_at: u64,
+}
+
+impl Item {
+    /// Name of the secret, e.g. `github-token`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// What needs to happen before the secret is revealed or deleted
+    pub fn requirement(&self) -> Requirement {
+        self.requirement
+    }
+
+    /// Timestamp of creation (seconds since UNIX epoch)
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+/// A secret, as stored in `paths::ITEMS_DIR`
+struct Record {
+    item: Item,
+    value: Sealed,
+}
+
+impl<T> Secrets<T>
+where
+    T: trussed::Client + trussed::client::mechanisms::Chacha8Poly1305,
+{
+    /// Constructor, consumes a Trussed client
+    pub fn new(trussed: T) -> Self {
+        Self { trussed, presence_timeout: core::time::Duration::from_secs(5) }
+    }
+
+    /// Changes how long to wait for confirmation of user presence, defaults to five seconds
+    pub fn set_presence_timeout(&mut self, timeout: core::time::Duration) {
+        self.presence_timeout = timeout;
+    }
+
+    /// Seals the value in Trussed, and stores it under the name, which must not be taken.
+    pub fn add(&mut self, parameters: &Add) -> Result<()> {
+        let Add { name, value, requirement, timestamp } = parameters;
+        debug!("add {:?}", parameters);
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+            return Err(anyhow::anyhow!("Names must have 1 to {} bytes", MAX_NAME_LENGTH));
+        }
+        if value.expose().is_empty() || value.expose().len() > MAX_VALUE_LENGTH {
+            return Err(anyhow::anyhow!("Secrets must have 1 to {} bytes", MAX_VALUE_LENGTH));
+        }
+
+        let filename = self.filename_for_name(name)?;
+        if self.load(&filename)?.is_some() {
+            return Err(anyhow::anyhow!("There already is a secret named {}, delete it first", name));
+        }
+        let record = Record {
+            item: Item { name: name.clone(), requirement: *requirement, created_at: *timestamp },
+            value: Sealed::seal(&mut self.trussed, None, value.expose().as_bytes(), name.as_bytes())?,
+        };
+
+        let mut buf = [0u8; 1024];
+        let serialized_record = postcard::to_slice(&record, &mut buf)
+            .map_err(|_| anyhow::anyhow!("postcard serialization error"))?;
+        syscall!(self.trussed.write_file(
+            Location::Internal,
+            paths::item(&filename),
+            // no panic - names and values are limited, so records fit
+            Bytes::from_slice(&*serialized_record).unwrap(),
+            None
+        ));
+        Ok(())
+    }
+
+    /// Reveals a secret, after its requirement is met.
+    pub fn get(&mut self, parameters: &Get) -> Result<SecretString> {
+        debug!("get {:?}", parameters);
+        let (_, record) = self.find(&parameters.name)?;
+        self.authorize(record.item.requirement)?;
+
+        let value = record.value.unseal(&mut self.trussed, record.item.name.as_bytes())?;
+        let value = core::str::from_utf8(&value)
+            .map_err(|_| anyhow::anyhow!("The secret named {} is corrupted", parameters.name))?;
+        Ok(value.into())
+    }
+
+    /// Lists the stored secrets, without revealing them.
+    pub fn list(&mut self) -> Result<Vec<Item>> {
+        let mut items = Vec::new();
+        for filename in self.files() {
+            if let Some(record) = self.load(&filename)? {
+                items.push(record.item);
+            }
+        }
+        items.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(items)
+    }
+
+    /// Deletes a secret with its key, after its requirement is met.
+    pub fn delete(&mut self, parameters: &Delete) -> Result<()> {
+        debug!("delete {:?}", parameters);
+        let (filename, record) = self.find(&parameters.name)?;
+        self.authorize(record.item.requirement)?;
+
+        info!("deleting secret {}", record.item.name);
+        syscall!(self.trussed.remove_file(Location::Internal, paths::item(&filename)));
+        syscall!(self.trussed.delete(record.value.key()));
+        Ok(())
+    }
+
+    /// Helper method, enforcing the requirement of a secret
+    fn authorize(&mut self, requirement: Requirement) -> Result<()> {
+        match requirement {
+            Requirement::Nothing => Ok(()),
+            Requirement::Presence => policy::confirm_presence(&mut self.trussed, self.presence_timeout),
+        }
+    }
+
+    /// Helper method, loading the secret with the name, else failing
+    fn find(&mut self, name: &str) -> Result<(PathBuf, Record)> {
+        let filename = self.filename_for_name(name)?;
+        match self.load(&filename)? {
+            Some(record) if record.item.name == name => Ok((filename, record)),
+            _ => Err(anyhow::anyhow!("Could not find a secret named {}", name)),
+        }
+    }
+
+    /// Helper method, loading the secret stored in the file, if any
+    fn load(&mut self, filename: &PathBuf) -> Result<Option<Record>> {
+        let serialized_record = match try_syscall!(self.trussed.read_file(Location::Internal, paths::item(filename))) {
+            Ok(reply) => reply.data,
+            Err(_) => return Ok(None),
+        };
+        postcard::from_bytes(serialized_record.as_ref())
+            .map(Some)
+            .map_err(|_| anyhow::anyhow!("The secret stored in {} is corrupted", filename.as_ref()))
+    }
+
+    /// Helper method, listing the names of the files in which secrets are stored
+    fn files(&mut self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        // the directory doesn't exist until the first secret is stored
+        let mut entry = match try_syscall!(self.trussed.read_dir_first(Location::Internal, PathBuf::from(paths::ITEMS_DIR), None)) {
+            Ok(reply) => reply.entry,
+            Err(_) => None,
+        };
+        while let Some(dir_entry) = entry {
+            if dir_entry.metadata().is_file() {
+                files.push(dir_entry.file_name().clone());
+            }
+            entry = syscall!(self.trussed.read_dir_next()).entry;
+        }
+        files
+    }
+
+    /// Helper method, using Trussed, to determine the name of the file for the secret
+    /// (within `paths::ITEMS_DIR`), like the authenticator does for labels
+    fn filename_for_name(&mut self, name: &str) -> Result<PathBuf> {
+        let message = Message::from_slice(name.as_bytes())
+            .map_err(|_| anyhow::anyhow!("The name is too long"))?;
+        let hash = syscall!(self.trussed.hash(Mechanism::Sha256, message)).hash;
+        let mut hex_filename = [0u8; 16];
+        use std::io::Write as _;
+        // first 8 bytes of SHA256 hash of the name, as hexadecimal digits
+        hex_filename.as_mut().write_fmt(format_args!("{}", delog::hexstr!(&hash[..8]))).unwrap();
+        Ok(PathBuf::from(hex_filename.as_ref()))
+    }
+}
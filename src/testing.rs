@@ -0,0 +1,110 @@
+//! Helpers to run the CLI binary in tests, each against a fresh state file.
+//!
+//! The integration tests in `tests/` drive the actual binary, so they cover the runner's
+//! plumbing (argument parsing, platform setup, writing back state) as well as the app.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::platform::seed_path;
+use crate::platform::store::{corrupted_path, wear_path};
+use crate::rollback;
+
+/// The CLI binary, bound to a temporary state file which is removed on drop
+pub struct Cli {
+    binary: PathBuf,
+    state_file: PathBuf,
+    ui: String,
+}
+
+/// The outcome of running the CLI once
+pub struct Run {
+    /// Whether the CLI exited successfully
+    pub success: bool,
+    /// What the CLI printed to stdout
+    pub stdout: String,
+    /// What the CLI printed to stderr
+    pub stderr: String,
+}
+
+impl Cli {
+    /// Constructor, taking the path of the binary, i.e. `env!("CARGO_BIN_EXE_trussed-totp-pc-tutorial")`
+    ///
+    /// User presence is confirmed without delay, cf. `set_ui`.
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let state_file = std::env::temp_dir().join(format!(
+            "trussed-totp-pc-tutorial-test-{}-{}.littlefs2",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst),
+        ));
+        std::fs::remove_file(&state_file).ok();
+        std::fs::remove_file(wear_path(&state_file)).ok();
+        std::fs::remove_file(seed_path(&state_file)).ok();
+        std::fs::remove_file(rollback::path(&state_file)).ok();
+        std::fs::remove_file(corrupted_path(&state_file)).ok();
+        Self { binary: binary.into(), state_file, ui: "test:delay:0".into() }
+    }
+
+    /// The state file the CLI runs against
+    pub fn state_file(&self) -> &Path {
+        &self.state_file
+    }
+
+    /// Changes how user presence is checked for subsequent runs, e.g. `test:deny`
+    pub fn set_ui(&mut self, ui: impl Into<String>) {
+        self.ui = ui.into();
+    }
+
+    /// Runs the CLI with the given arguments
+    pub fn run(&self, args: &[&str]) -> Run {
+        let output = Command::new(&self.binary)
+            .arg("--state-file")
+            .arg(&self.state_file)
+            .arg("--ui")
+            .arg(&self.ui)
+            // denied presence only fails once the wait times out
+            .arg("--presence-timeout")
+            .arg("1s")
+            .args(args)
+            .env_remove("RUST_LOG")
+            .env_remove("TOTP_STATE_PASSPHRASE")
+            .env_remove("TOTP_CONFIG")
+            // messages are in English unless asked for otherwise
+            .env_remove("LC_ALL")
+            .env_remove("LC_MESSAGES")
+            .env_remove("LANG")
+            .output()
+            .expect("could not run the CLI binary");
+        Run {
+            success: output.status.success(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+
+    /// Runs the CLI, panicking unless it succeeds; returns what it printed to stdout
+    pub fn ok(&self, args: &[&str]) -> String {
+        let run = self.run(args);
+        assert!(run.success, "`{}` failed: {}", args.join(" "), run.stderr);
+        run.stdout
+    }
+
+    /// Runs the CLI, panicking if it succeeds; returns what it printed to stderr
+    pub fn fails(&self, args: &[&str]) -> String {
+        let run = self.run(args);
+        assert!(!run.success, "`{}` succeeded: {}", args.join(" "), run.stdout);
+        run.stderr
+    }
+}
+
+impl Drop for Cli {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.state_file).ok();
+        std::fs::remove_file(wear_path(&self.state_file)).ok();
+        std::fs::remove_file(seed_path(&self.state_file)).ok();
+        std::fs::remove_file(rollback::path(&self.state_file)).ok();
+        std::fs::remove_file(corrupted_path(&self.state_file)).ok();
+    }
+}
@@ -0,0 +1,114 @@
+//! A decorator for Trussed clients, tracing each syscall an app makes.
+//!
+//! Watching which requests an app actually sends to Trussed, and how long the service takes
+//! to reply, is a good way to learn what an app asks of Trussed.
+
+use core::task::Poll;
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc, time::{Duration, Instant}};
+
+use trussed::api::{Reply, Request};
+use trussed::client::{ClientResult, FutureResult, PollClient};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+/// Aggregated statistics of one kind of syscall
+pub struct SyscallStatistics {
+    /// How often the syscall was made
+    pub count: u32,
+    /// How often the service replied with an error
+    pub errors: u32,
+    /// Time taken for all of the syscalls
+    pub total: Duration,
+}
+
+/// Statistics per kind of syscall, shared between the `Traced` client and the runner
+pub type Statistics = Rc<RefCell<BTreeMap<String, SyscallStatistics>>>;
+
+/// Wraps a Trussed client, logging each syscall (with timing and result status) to stderr
+/// and aggregating statistics, if enabled.
+pub struct Traced<C> {
+    inner: C,
+    enabled: bool,
+    pending: Option<(String, Instant)>,
+    statistics: Statistics,
+}
+
+impl<C> Traced<C> {
+    /// Constructor, wraps a Trussed client. If not enabled, syscalls are just passed through.
+    pub fn new(inner: C, enabled: bool) -> Self {
+        Self { inner, enabled, pending: None, statistics: Statistics::default() }
+    }
+
+    /// Access to the statistics, which outlives the client
+    pub fn statistics(&self) -> Statistics {
+        self.statistics.clone()
+    }
+}
+
+/// The name of the request's variant, e.g. `ReadFile`.
+fn request_name(request: &Request) -> String {
+    /// Collects `Debug` output up to the first opening parenthesis
+    struct VariantName(String);
+
+    impl core::fmt::Write for VariantName {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            match s.find('(') {
+                Some(i) => {
+                    self.0.push_str(&s[..i]);
+                    Err(core::fmt::Error)
+                }
+                None => {
+                    self.0.push_str(s);
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    let mut name = VariantName(String::new());
+    // aborts before the request's payload (possibly secret key material) is formatted
+    core::fmt::write(&mut name, format_args!("{:?}", request)).ok();
+    name.0
+}
+
+impl<C: PollClient> PollClient for Traced<C> {
+    fn request<T: From<Reply>>(&mut self, req: impl Into<Request>) -> ClientResult<'_, T, Self> {
+        let request = req.into();
+        if self.enabled {
+            self.pending = Some((request_name(&request), Instant::now()));
+        }
+        self.inner.request::<T>(request)?;
+        Ok(FutureResult::new(self))
+    }
+
+    fn poll(&mut self) -> Poll<core::result::Result<Reply, trussed::error::Error>> {
+        let result = self.inner.poll();
+        if let Poll::Ready(reply) = &result {
+            if let Some((name, start)) = self.pending.take() {
+                let elapsed = start.elapsed();
+                let status = match reply {
+                    Ok(_) => "ok",
+                    Err(_) => "error",
+                };
+                crate::output::diagnostic(&format!("trussed: {} {} ({:?})", name, status, elapsed));
+
+                let mut statistics = self.statistics.borrow_mut();
+                let entry = statistics.entry(name).or_default();
+                entry.count += 1;
+                entry.errors += reply.is_err() as u32;
+                entry.total += elapsed;
+            }
+        }
+        result
+    }
+}
+
+// The client traits only have provided methods, built on `PollClient`.
+impl<C: trussed::client::CertificateClient> trussed::client::CertificateClient for Traced<C> {}
+impl<C: trussed::client::CounterClient> trussed::client::CounterClient for Traced<C> {}
+impl<C: trussed::client::CryptoClient> trussed::client::CryptoClient for Traced<C> {}
+impl<C: trussed::client::FilesystemClient> trussed::client::FilesystemClient for Traced<C> {}
+impl<C: trussed::client::ManagementClient> trussed::client::ManagementClient for Traced<C> {}
+impl<C: trussed::client::UiClient> trussed::client::UiClient for Traced<C> {}
+impl<C: trussed::Client> trussed::Client for Traced<C> {}
+impl<C: trussed::client::mechanisms::Chacha8Poly1305> trussed::client::mechanisms::Chacha8Poly1305 for Traced<C> {}
+impl<C: trussed::client::mechanisms::Totp> trussed::client::mechanisms::Totp for Traced<C> {}
@@ -0,0 +1,92 @@
+//! Transcripts of dispatched commands and their responses, to reproduce bugs.
+//!
+//! A transcript is a file of JSON lines, one `Entry` per dispatched command. Secrets are
+//! never written: commands are recorded with dummies in place of their secrets, which are
+//! valid where the secrets were (e.g. base32 or hexadecimal of the same length), so
+//! replaying a transcript against a fresh state follows the same code paths (yet generates
+//! different OTPs). Recovery codes and revealed secrets are secrets as well, so responses
+//! revealing them are redacted.
+//!
+//! Commands of the secrets app are recorded too, marked with its `app`, but replaying
+//! drives the authenticator only, so it skips them.
+
+use std::io::{BufRead as _, Write as _};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::authenticator::Command;
+use crate::middleware::Dispatch;
+use crate::Result;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// A dispatched command, and the response it got (or the error it failed with)
+pub struct Entry<C = Command> {
+    /// The app the command was dispatched to, cf. `Dispatch::APP`; omitted for the authenticator
+    #[serde(default = "authenticator_app", skip_serializing_if = "is_authenticator_app")]
+    pub app: String,
+    /// The command, with secrets redacted
+    pub command: C,
+    /// The response, as shown by the CLI
+    pub response: core::result::Result<String, String>,
+}
+
+fn authenticator_app() -> String {
+    Command::APP.into()
+}
+
+fn is_authenticator_app(app: &str) -> bool {
+    app == Command::APP
+}
+
+impl<C: Dispatch> Entry<C> {
+    /// Constructor, from the outcome of dispatching the command
+    ///
+    /// The command's secrets are replaced by dummies, and responses revealing secrets are redacted.
+    pub fn new(command: &C, result: &Result<String>) -> Self {
+        let response = match result {
+            Ok(_) if command.reveals_secrets() => Ok("<redacted>\n".into()),
+            Ok(response) => Ok(response.clone()),
+            Err(error) => Err(error.to_string()),
+        };
+        Self { app: C::APP.into(), command: command.redacted(), response }
+    }
+}
+
+/// Appends entries to a transcript file
+pub struct Recorder {
+    file: std::fs::File,
+}
+
+impl Recorder {
+    /// Opens the transcript file for appending, creating it if necessary
+    pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Appends an entry for the command and its outcome
+    pub fn record<C: Dispatch>(&mut self, command: &C, result: &Result<String>) -> Result<()> {
+        let entry = Entry::new(command, result);
+        writeln!(self.file, "{}", serde_json::to_string(&entry)?)?;
+        Ok(())
+    }
+}
+
+/// Reads the entries of the authenticator from a transcript file
+pub fn read(path: impl AsRef<std::path::Path>) -> Result<Vec<Entry>> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut entries = Vec::new();
+    for line in file.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: serde_json::Value = serde_json::from_str(&line)?;
+        match entry.get("app").and_then(|app| app.as_str()) {
+            Some(app) if app != Command::APP => info!("skipping a command of the {} app", app),
+            _ => entries.push(serde_json::from_value(entry)?),
+        }
+    }
+    Ok(entries)
+}
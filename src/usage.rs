@@ -0,0 +1,104 @@
+//! Usage counters of credentials, kept apart from the credentials themselves.
+//!
+//! Each authentication updates a small usage record, instead of rewriting the whole
+//! credential (whose label alone may take up to 256 bytes).
+
+use littlefs2::path::Path;
+use serde::{Deserialize, Serialize};
+use trussed::{syscall, try_syscall, Bytes, types::Location};
+
+use crate::Result;
+use crate::paths;
+
+/// Weeks start on Mondays, the UNIX epoch was a Thursday
+const WEEK_OFFSET_SECONDS: u64 = 3 * 86_400;
+const WEEK_SECONDS: u64 = 7 * 86_400;
+
+/// The number of the week (starting on Monday) the timestamp falls into
+pub fn week_of(timestamp: u64) -> u64 {
+    timestamp.saturating_add(WEEK_OFFSET_SECONDS) / WEEK_SECONDS
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+/// How often a credential was used, and how often using it failed
+pub struct Usage {
+    count: u32,
+    failures: u32,
+    last_used: Option<u64>,
+    week: u64,
+    week_count: u32,
+}
+
+impl Usage {
+    /// Number of OTPs generated
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Number of failed authentications and verifications
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Timestamp of the last authentication (seconds since UNIX epoch), if any
+    pub fn last_used(&self) -> Option<u64> {
+        self.last_used
+    }
+
+    /// Number of OTPs generated in the week of the timestamp
+    pub fn count_in_week_of(&self, timestamp: u64) -> u32 {
+        match week_of(timestamp) == self.week {
+            true => self.week_count,
+            false => 0,
+        }
+    }
+
+    /// Counts an OTP generated for the timestamp
+    pub fn record_use(&mut self, timestamp: u64) {
+        self.count = self.count.saturating_add(1);
+        self.last_used = Some(timestamp);
+        let week = week_of(timestamp);
+        // OTPs for past timestamps don't count towards the current week
+        if week > self.week {
+            self.week = week;
+            self.week_count = 0;
+        }
+        if week == self.week {
+            self.week_count = self.week_count.saturating_add(1);
+        }
+    }
+
+    /// Counts a failed authentication or verification
+    pub fn record_failure(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    /// Loads the usage of the credential stored in the file, starting afresh if there is none
+    ///
+    /// Usage is kept in the same location as the credential.
+    pub fn load<T: trussed::Client>(trussed: &mut T, location: Location, filename: &Path) -> Self {
+        try_syscall!(trussed.read_file(location, paths::usage(filename)))
+            .ok()
+            .and_then(|reply| postcard::from_bytes(reply.data.as_ref()).ok())
+            .unwrap_or_default()
+    }
+
+    /// Persists the usage of the credential stored in the file
+    pub fn store<T: trussed::Client>(&self, trussed: &mut T, location: Location, filename: &Path) -> Result<()> {
+        let mut buf = [0u8; 64];
+        let serialized_usage = postcard::to_slice(self, &mut buf)
+            .map_err(|_| anyhow::anyhow!("postcard serialization error"))?;
+        syscall!(trussed.write_file(
+            location,
+            paths::usage(filename),
+            Bytes::from_slice(&*serialized_usage).unwrap(),
+            None
+        ));
+        Ok(())
+    }
+
+    /// Removes the usage of the credential stored in the file, if any
+    pub fn remove<T: trussed::Client>(trussed: &mut T, location: Location, filename: &Path) {
+        try_syscall!(trussed.remove_file(location, paths::usage(filename))).ok();
+    }
+}
@@ -0,0 +1,79 @@
+//! Files of credentials that can't be read back are quarantined by `repair`, and only those,
+//! and keys aren't collected while quarantined credentials may refer to them. Before that,
+//! the corruption is refused as an edit of the state file, until it is accepted.
+//!
+//! The files are corrupted directly in the state file, through the store. The store is a
+//! process-wide singleton, so this is a test binary of its own, with one test function.
+
+use trussed::store::Store as _;
+use trussed::types::PathBuf;
+
+use tutorial::testing::Cli;
+
+const BINARY: &str = env!("CARGO_BIN_EXE_trussed-totp-pc-tutorial");
+
+// RFC 6238, appendix B: the SHA-1 seed "12345678901234567890", in base32
+const SEED: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+/// The path of the file of the credential with the label in the state file, from its dump
+fn credential_path(cli: &Cli, label: &str) -> String {
+    let dump: serde_json::Value = serde_json::from_str(&cli.ok(&["debug", "dump-state"])).unwrap();
+    let mut directories = vec![&dump];
+    while let Some(directory) = directories.pop() {
+        for entry in directory["entries"].as_array().unwrap() {
+            match entry.get("entries") {
+                Some(_) => directories.push(entry),
+                None if entry["record"] == "credential" && entry["contents"]["label"] == label => {
+                    return entry["path"].as_str().unwrap().into();
+                }
+                None => {}
+            }
+        }
+    }
+    panic!("there is no credential labelled {}", label);
+}
+
+#[test]
+fn repair() {
+    let cli = Cli::new(BINARY);
+    cli.ok(&["register", "--algorithm", "hotp", "alice@trussed.dev", SEED]);
+    cli.ok(&["register", "bob@trussed.dev", SEED]);
+    let alice = credential_path(&cli, "alice@trussed.dev");
+    let bob = credential_path(&cli, "bob@trussed.dev");
+    let name = |path: &str| path.rsplit('/').next().unwrap().to_string();
+
+    // alice's OTP parameters, and bob's credential itself, can no longer be read
+    let (store, sync_handle) = tutorial::platform::store::init_store(cli.state_file(), 0, None, false, false).unwrap();
+    let garbage = [0xff; 4];
+    store.ifs().write(&PathBuf::from(alice.replace("/credentials/", "/parameters/").as_str()), &garbage).unwrap();
+    store.ifs().write(&PathBuf::from(bob.as_str()), &garbage).unwrap();
+    sync_handle.sync().unwrap();
+
+    // edited outside the app, the credentials must be accepted first
+    let stderr = cli.fails(&["list"]);
+    assert!(stderr.contains("--accept-modified-state"), "{}", stderr);
+    let listing = cli.ok(&["--accept-modified-state", "list"]);
+    assert!(listing.contains(&format!("parameters/{}", name(&alice))), "{}", listing);
+    assert!(listing.contains(&format!("credentials/{}", name(&bob))), "{}", listing);
+
+    // each failing file is quarantined, not the credential beside it
+    let repaired = cli.ok(&["repair"]);
+    assert!(repaired.contains(&format!("parameters/{}", name(&alice))), "{}", repaired);
+    assert!(repaired.contains(&format!("credentials/{}", name(&bob))), "{}", repaired);
+    assert!(!repaired.contains(&format!("credentials/{}", name(&alice))), "{}", repaired);
+    let listing = cli.ok(&["list"]);
+    assert!(listing.contains("alice@trussed.dev") && !listing.contains("bob@trussed.dev"), "{}", listing);
+    assert!(!listing.contains("corrupted"), "{}", listing);
+    // without its parameters, alice's credential falls back to TOTP
+    assert_eq!(cli.ok(&["authenticate", "-t", "59", "alice@trussed.dev"]), "287082\n");
+
+    // the key of bob's quarantined credential would seem unused, so it isn't collected
+    let stderr = cli.fails(&["storage", "gc"]);
+    assert!(stderr.contains("repair --delete"), "{}", stderr);
+    let deleted = cli.ok(&["repair", "--delete"]);
+    assert!(deleted.contains(&format!("quarantine/credentials/{}", name(&bob))), "{}", deleted);
+    assert!(deleted.contains(&format!("quarantine/parameters/{}", name(&alice))), "{}", deleted);
+    let collected = cli.ok(&["storage", "gc"]);
+    assert!(collected.contains("removed"), "{}", collected);
+    assert_eq!(cli.ok(&["authenticate", "-t", "59", "alice@trussed.dev"]), "287082\n");
+}
@@ -0,0 +1,138 @@
+//! Whatever is registered survives serialization, storage in the littlefs-backed
+//! state file, and reading back.
+//!
+//! The Trussed store is a process-wide singleton, so all properties share one
+//! authenticator (and hence one test function), wiping the credentials after each case.
+
+use core::cell::RefCell;
+use core::convert::TryInto as _;
+use std::collections::BTreeSet;
+
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestCaseError, TestRunner};
+
+use tutorial::authenticator::{Authenticate, Authenticator, Prune, Register, Trash, PERIODS};
+use tutorial::platform;
+
+/// Labels may be up to 256 bytes, i.e. 64 characters of up to 4 bytes each
+const LABEL: &str = "\\PC{1,64}";
+
+/// RFC 6238, computed independently of Trussed
+fn reference_otp(key: &[u8], period_seconds: u64, timestamp: u64) -> u64 {
+    use hmac::{Mac as _, NewMac as _};
+    let mut mac = hmac::Hmac::<sha1::Sha1>::new_from_slice(key).unwrap();
+    mac.update(&(timestamp / period_seconds).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[19] & 0xf) as usize;
+    let code = u32::from_be_bytes(hash[offset..][..4].try_into().unwrap()) & 0x7fff_ffff;
+    (code % 1_000_000) as u64
+}
+
+fn register(label: &str, key: &[u8], period_seconds: u64, slot: u8, timestamp: u64) -> Register {
+    Register {
+        label: label.into(),
+        base32_secret: data_encoding::BASE32.encode(key).into(),
+        period_seconds,
+        slot,
+        timestamp,
+        location: trussed::types::Location::Internal,
+        algorithm: "totp".into(),
+        counter: 0,
+        issuer: None,
+    }
+}
+
+#[test]
+fn roundtrip() {
+    let state_file = std::env::temp_dir().join(format!("trussed-totp-pc-tutorial-roundtrip-{}.littlefs2", std::process::id()));
+    std::fs::remove_file(&state_file).ok();
+
+    // writing through, so everything actually passes through the state file
+    let presence = platform::Presence::Delay(core::time::Duration::from_millis(0));
+    let (trussed_platform, _sync_handle, _seed_file) = platform::init_platform(&state_file, 0, presence, None, false, false, &platform::rng::RngSource::ChaCha).unwrap();
+    let service = Box::leak(Box::new(trussed::service::Service::new(trussed_platform)));
+    let authenticator = RefCell::new(Authenticator::new(service.try_as_new_client("totp").unwrap()));
+
+    let wipe = || {
+        let prune = Prune { timestamp: u64::MAX, unused_for_seconds: 0, delete: true };
+        authenticator.borrow_mut().prune(&prune).unwrap();
+        assert!(authenticator.borrow_mut().list().unwrap().credentials.is_empty());
+        // purged right away, as a credential in the trash keeps its label and slot from being registered
+        assert!(authenticator.borrow_mut().trash(&Trash { timestamp: u64::MAX }).unwrap().is_empty());
+    };
+
+    let mut runner = TestRunner::new(Config { cases: 64, ..Config::default() });
+
+    // each field of a credential survives, and so does the secret, whatever its length
+    let fields = (LABEL, proptest::collection::vec(any::<u8>(), 10..=64), PERIODS, any::<u8>(), 0..u64::MAX - 1, 0..u64::MAX - 1);
+    runner.run(&fields, |(label, key, period_seconds, slot, created_at, timestamp)| {
+        let result = (|| {
+            let mut authenticator = authenticator.borrow_mut();
+            authenticator.register(&register(&label, &key, period_seconds, slot, created_at))
+                .map_err(|e| TestCaseError::fail(e.to_string()))?;
+
+            let listing = authenticator.list().unwrap();
+            prop_assert!(listing.corrupted.is_empty());
+            prop_assert_eq!(listing.credentials.len(), 1);
+            let credential = &listing.credentials[0];
+            prop_assert_eq!(credential.label(), label.as_str());
+            prop_assert_eq!(credential.slot(), slot);
+            prop_assert_eq!(credential.period_seconds(), period_seconds);
+            prop_assert_eq!(credential.created_at(), created_at);
+            prop_assert_eq!(credential.last_used(), None);
+
+            let authenticated = authenticator.authenticate(&Authenticate { label: label.clone(), slot: Some(slot), timestamp })
+                .map_err(|e| TestCaseError::fail(e.to_string()))?;
+            prop_assert_eq!(authenticated.otp.0, format!("{:06}", reference_otp(&key, period_seconds, timestamp)));
+            prop_assert_eq!(authenticated.period, Some(period_seconds));
+            let remaining_seconds = authenticated.remaining_seconds.unwrap();
+            prop_assert!(remaining_seconds >= 1 && remaining_seconds <= period_seconds);
+            let credential = &authenticator.list().unwrap().credentials[0];
+            prop_assert_eq!(credential.last_used(), Some(timestamp));
+            prop_assert_eq!(credential.usage().count(), 1);
+            Ok(())
+        })();
+        wipe();
+        result
+    }).unwrap();
+
+    // any number of credentials can be listed back
+    let labels = proptest::collection::btree_set(LABEL, 1..=8);
+    runner.run(&labels, |labels| {
+        let result = (|| {
+            let mut authenticator = authenticator.borrow_mut();
+            for label in labels.iter() {
+                authenticator.register(&register(label, &[0x42; 20], 30, 0, 0))
+                    .map_err(|e| TestCaseError::fail(e.to_string()))?;
+            }
+            let listing = authenticator.list().unwrap();
+            let listed: BTreeSet<String> = listing.credentials.iter().map(|credential| credential.label().into()).collect();
+            prop_assert_eq!(listed, labels.clone());
+            Ok(())
+        })();
+        wipe();
+        result
+    }).unwrap();
+
+    // labels of maximum length survive, longer ones are rejected
+    {
+        let mut authenticator = authenticator.borrow_mut();
+        let longest = "ä".repeat(128);
+        authenticator.register(&register(&longest, &[0x42; 20], 30, 0, 0)).unwrap();
+        assert_eq!(authenticator.list().unwrap().credentials[0].label(), longest);
+        assert!(authenticator.register(&register(&format!("{}a", longest), &[0x42; 20], 30, 1, 0)).is_err());
+    }
+    wipe();
+
+    // secrets shorter than 80 bits, or longer than 64 bytes, are rejected
+    {
+        let mut authenticator = authenticator.borrow_mut();
+        assert!(authenticator.register(&register("alice@trussed.dev", &[0x42; 9], 30, 0, 0)).is_err());
+        assert!(authenticator.register(&register("alice@trussed.dev", &[0x42; 65], 30, 0, 0)).is_err());
+        assert!(authenticator.list().unwrap().credentials.is_empty());
+    }
+
+    std::fs::remove_file(&state_file).ok();
+    std::fs::remove_file(platform::store::wear_path(&state_file)).ok();
+    std::fs::remove_file(platform::seed_path(&state_file)).ok();
+}